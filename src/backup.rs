@@ -0,0 +1,144 @@
+use crate::crypto::MasterKey;
+use crate::error::{Error, Result};
+use crate::journal::{self, read_optional};
+use crate::model::Vault;
+use crate::storage::{replace_file, StorageBackend};
+use crate::vault;
+use chrono::{DateTime, Utc};
+use ciborium::{ser, de};
+use serde::{Serialize, Deserialize};
+use std::fs::{create_dir_all, read_dir};
+use std::path::{Path, PathBuf};
+
+// A snapshot is a single CBOR file holding the encrypted vault exactly as it
+// was on disk, plus its journal and the legacy salt file where present. It
+// is never decrypted or re-encrypted on the way in, so taking one needs no
+// password.
+pub const SNAPSHOT_EXTENSION: &str = "rpbak";
+const SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub version: u16,
+    pub vault: String,
+    pub created_at: DateTime<Utc>,
+    pub vault_data: Vec<u8>,
+    pub legacy_salt: Option<Vec<u8>>,
+    #[serde(default)]
+    pub journal: Option<Vec<u8>>,
+    #[serde(default)]
+    pub journal_head: Option<Vec<u8>>,
+}
+
+impl Snapshot {
+    pub fn decrypt(&self, master: &MasterKey) -> Result<Vault> {
+        vault::decrypt_vault_with_journal(
+            &self.vault_data,
+            self.legacy_salt.as_deref(),
+            self.journal.as_deref(),
+            self.journal_head.as_deref(),
+            master,
+        )
+    }
+}
+
+pub fn backups_dir(root: &Path) -> Result<PathBuf> {
+    let dir = root.join("backups");
+    create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Writes a timestamped snapshot of the named vault into `out_dir` and
+/// returns its path.
+pub fn create_snapshot(storage: &dyn StorageBackend, name: &str, out_dir: &Path) -> Result<PathBuf> {
+    let key = vault::vault_key(name);
+    let _lock = storage.lock(&key)?;
+    let vault_data = storage.read(&key).map_err(|e| match e {
+        Error::NotFound(_) => Error::NotFound(format!("vault '{}'", name)),
+        e => e,
+    })?;
+    let snapshot = Snapshot {
+        version: SNAPSHOT_VERSION,
+        vault: name.to_string(),
+        created_at: Utc::now(),
+        vault_data,
+        legacy_salt: read_optional(storage, &vault::legacy_salt_key(name))?,
+        journal: read_optional(storage, &journal::journal_key(&key))?,
+        journal_head: read_optional(storage, &journal::head_key(&key))?,
+    };
+    create_dir_all(out_dir)?;
+    let path = out_dir.join(format!(
+        "{}-{}.{}",
+        name,
+        snapshot.created_at.format("%Y%m%dT%H%M%SZ"),
+        SNAPSHOT_EXTENSION
+    ));
+    if path.exists() {
+        return Err(Error::InvalidInput(format!("Snapshot {} already exists.", path.display())));
+    }
+    let mut buf = Vec::new();
+    ser::into_writer(&snapshot, &mut buf)?;
+    replace_file(&path, &buf, 0)?;
+    Ok(path)
+}
+
+pub fn read_snapshot(path: &Path) -> Result<Snapshot> {
+    let data = std::fs::read(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => Error::NotFound(format!("snapshot {}", path.display())),
+        _ => e.into(),
+    })?;
+    let snapshot: Snapshot = de::from_reader(data.as_slice())
+        .map_err(|e| Error::Corrupt(format!("snapshot {}: {}", path.display(), e)))?;
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(Error::UnsupportedVersion(snapshot.version));
+    }
+    Ok(snapshot)
+}
+
+/// Snapshot files in `dir`, oldest first.
+pub fn list_snapshots(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == SNAPSHOT_EXTENSION) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Replaces the live files of vault `name` with a snapshot, after checking
+/// that the snapshot decrypts with `master`. The vault being replaced, if
+/// any, must open with `current`; it is compacted and kept as its newest
+/// `.bak.N` generation.
+pub fn restore_snapshot(
+    storage: &dyn StorageBackend,
+    snapshot: &Snapshot,
+    name: &str,
+    master: &MasterKey,
+    current: &MasterKey,
+) -> Result<Vault> {
+    let vault = snapshot.decrypt(master)?;
+    let at = vault::Location::named(storage, name);
+    let _lock = at.lock()?;
+    // Fold the replaced vault's journal into it first, which also moves a
+    // legacy vault off its salt file, so the generation kept as a backup
+    // opens on its own. If it does not open, nothing is replaced.
+    if storage.exists(&at.key)? {
+        vault::compact_at(&at, current)?;
+    }
+    storage.write(&at.key, &snapshot.vault_data, storage.config()?.backups)?;
+    journal::remove(storage, &at.key)?;
+    if let Some(data) = &snapshot.journal {
+        storage.write(&journal::journal_key(&at.key), data, 0)?;
+    }
+    if let Some(head) = &snapshot.journal_head {
+        storage.write(&journal::head_key(&at.key), head, 0)?;
+    }
+    match &snapshot.legacy_salt {
+        Some(salt) => storage.write(&at.salt_key, salt, 0)?,
+        None => storage.delete(&at.salt_key)?,
+    }
+    Ok(vault)
+}
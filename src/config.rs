@@ -0,0 +1,53 @@
+use crate::error::{Error, Result};
+use directories::ProjectDirs;
+use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+
+const CONFIG_FILE: &str = "config.toml";
+pub const DEFAULT_BACKUPS: usize = 3;
+pub const DEFAULT_LOCK_TIMEOUT_MS: u64 = 5000;
+
+/// User settings, read from `config.toml` in the data directory. Missing
+/// keys (or a missing file) fall back to the defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Config {
+    /// How many previous encrypted generations of a vault are kept as
+    /// `vault_<name>.cbor.bak.N`. Zero disables backups.
+    pub backups: usize,
+    /// How long to wait for another rustpass process to release a vault
+    /// before giving up.
+    pub lock_timeout_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            backups: DEFAULT_BACKUPS,
+            lock_timeout_ms: DEFAULT_LOCK_TIMEOUT_MS,
+        }
+    }
+}
+
+/// The per-user data directory used when no other one is given.
+pub fn default_data_dir() -> Result<PathBuf> {
+    let proj_dirs = ProjectDirs::from("com", "rustpass", "RustPass")
+        .ok_or_else(|| Error::NotFound("project data directory".to_string()))?;
+    Ok(proj_dirs.data_dir().to_path_buf())
+}
+
+pub fn config_path(root: &Path) -> PathBuf {
+    root.join(CONFIG_FILE)
+}
+
+impl Config {
+    pub fn load(root: &Path) -> Result<Config> {
+        let path = config_path(root);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text)
+                .map_err(|e| Error::InvalidInput(format!("Invalid config file {}: {}", path.display(), e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -1,28 +1,208 @@
-use argon2::{Argon2};
+use argon2::{Algorithm, Argon2, Params, Version};
+use crate::error::{Error, Result};
+use crate::secret::{SecretBytes, SecretKey, SecretString};
 use rand::RngCore;
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
 use chacha20poly1305::{
-    aead::Aead, Key, KeyInit, XChaCha20Poly1305, XNonce
+    aead::{Aead, Payload}, Key, KeyInit, XChaCha20Poly1305, XNonce
 };
 
-pub fn derive_key(master: &str, salt: &[u8]) -> [u8; 32] {
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfAlgorithm {
+    Argon2id,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            algorithm: KdfAlgorithm::Argon2id,
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl std::fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} memory={} KiB iterations={} parallelism={}",
+            self.algorithm, self.m_cost, self.t_cost, self.p_cost
+        )
+    }
+}
+
+// Memory is kept between the argon2 default (19 MiB) and 1 GiB while tuning.
+const TUNE_MAX_M_COST: u32 = 1024 * 1024;
+
+pub fn time_kdf(kdf: &KdfParams) -> Result<Duration> {
+    let salt = random_salt();
+    let start = Instant::now();
+    derive_key_with("rustpass-benchmark", None, &salt, kdf)?;
+    Ok(start.elapsed())
+}
+
+/// Finds Argon2id parameters that take roughly `target` to derive a key on
+/// this machine. Memory is raised first, then the iteration count.
+pub fn tune_kdf(target: Duration) -> Result<KdfParams> {
+    let threads = std::thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(1);
+    let mut kdf = KdfParams {
+        p_cost: threads.min(4),
+        t_cost: 1,
+        ..KdfParams::default()
+    };
+    let mut elapsed = time_kdf(&kdf)?;
+    while elapsed * 2 <= target && kdf.m_cost * 2 <= TUNE_MAX_M_COST {
+        kdf.m_cost *= 2;
+        elapsed = time_kdf(&kdf)?;
+    }
+    if elapsed < target {
+        let per_pass = elapsed.as_secs_f64().max(f64::EPSILON);
+        kdf.t_cost = (target.as_secs_f64() / per_pass).round().max(1.0) as u32;
+    }
+    Ok(kdf)
+}
+
+/// The secrets a key slot is unlocked with: a password and, optionally, the
+/// contents of a keyfile.
+#[derive(Clone)]
+pub struct MasterKey {
+    pub password: SecretString,
+    pub keyfile: Option<SecretBytes>,
+}
+
+impl MasterKey {
+    pub fn new(password: &str) -> Self {
+        MasterKey {
+            password: password.into(),
+            keyfile: None,
+        }
+    }
+
+    pub fn with_keyfile(mut self, contents: Vec<u8>) -> Self {
+        self.keyfile = Some(SecretBytes::new(contents));
+        self
+    }
+
+    pub fn keyfile_bytes(&self) -> Option<&[u8]> {
+        self.keyfile.as_ref().map(|k| k.as_slice())
+    }
+}
+
+impl From<&str> for MasterKey {
+    fn from(password: &str) -> Self {
+        MasterKey::new(password)
+    }
+}
+
+pub const KEYFILE_LEN: usize = 64;
+
+pub fn generate_keyfile() -> Vec<u8> {
+    let mut contents = vec![0u8; KEYFILE_LEN];
+    rand::thread_rng().fill_bytes(&mut contents);
+    contents
+}
+
+pub fn derive_key(master: &str, salt: &[u8]) -> Result<SecretKey> {
     let argon2 = Argon2::default();
-    let mut key = [0u8; 32];
-    argon2.hash_password_into(master.as_bytes(), salt, &mut key).expect("Key derivation failed");
+    let mut key = SecretKey::new([0u8; 32]);
+    argon2
+        .hash_password_into(master.as_bytes(), salt, key.as_mut())
+        .map_err(|e| Error::Crypto(format!("Key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+// The keyfile, if any, is fed to Argon2 as its secret input, so the derived
+// key depends on both the password and the keyfile contents.
+pub fn derive_key_with(master: &str, keyfile: Option<&[u8]>, salt: &[u8], kdf: &KdfParams) -> Result<SecretKey> {
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32))
+        .map_err(|e| Error::InvalidInput(format!("Invalid KDF parameters: {}", e)))?;
+    let argon2 = match (kdf.algorithm, keyfile) {
+        (KdfAlgorithm::Argon2id, None) => Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+        (KdfAlgorithm::Argon2id, Some(secret)) => Argon2::new_with_secret(secret, Algorithm::Argon2id, Version::V0x13, params)
+            .map_err(|e| Error::InvalidInput(format!("Invalid keyfile: {}", e)))?,
+    };
+    let mut key = SecretKey::new([0u8; 32]);
+    argon2
+        .hash_password_into(master.as_bytes(), salt, key.as_mut())
+        .map_err(|e| Error::Crypto(format!("Key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+pub fn random_salt() -> [u8; 16] {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    salt
+}
+
+pub fn random_key() -> SecretKey {
+    let mut key = SecretKey::new([0u8; 32]);
+    rand::thread_rng().fill_bytes(key.as_mut());
     key
 }
 
-pub fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> (Vec<u8>, [u8; 24]) {
+pub fn random_nonce() -> [u8; 24] {
+    let mut nonce = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+pub fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<(Vec<u8>, [u8; 24])> {
     let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
     let mut nonce = [0u8; 24];
     rand::thread_rng().fill_bytes(&mut nonce);
-    let ciphertext = cipher.encrypt(XNonce::from_slice(&nonce), plaintext).expect("Encryption failed");
-    (ciphertext, nonce)
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), plaintext)
+        .map_err(|_| Error::Crypto("Encryption failed".to_string()))?;
+    Ok((ciphertext, nonce))
 }
 
-pub fn decrypt(key: &[u8; 32], ciphertext: &[u8], nonce: &[u8; 24]) -> anyhow::Result<Vec<u8>> {
+pub fn decrypt(key: &[u8; 32], ciphertext: &[u8], nonce: &[u8; 24]) -> Result<SecretBytes> {
     let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
     let plaintext = cipher
         .decrypt(XNonce::from_slice(nonce), ciphertext)
-        .map_err(|_| anyhow::anyhow!("Failed to decrypt vault: Incorrect master password or corrupted vault."))?;
-    Ok(plaintext)
+        .map_err(|_| Error::WrongPassword)?;
+    Ok(SecretBytes::new(plaintext))
+}
+
+pub fn encrypt_with_aad(key: &[u8; 32], nonce: &[u8; 24], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    cipher
+        .encrypt(XNonce::from_slice(nonce), Payload { msg: plaintext, aad })
+        .map_err(|_| Error::Crypto("Encryption failed".to_string()))
+}
+
+pub fn decrypt_with_aad(key: &[u8; 32], nonce: &[u8; 24], ciphertext: &[u8], aad: &[u8]) -> Result<SecretBytes> {
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    cipher
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map(SecretBytes::new)
+        .map_err(|_| Error::WrongPassword)
+}
+
+const KEY_WRAP_AAD: &[u8] = b"rustpass key slot";
+
+pub fn wrap_key(kek: &[u8; 32], data_key: &[u8; 32]) -> Result<(Vec<u8>, [u8; 24])> {
+    let nonce = random_nonce();
+    let wrapped = encrypt_with_aad(kek, &nonce, data_key, KEY_WRAP_AAD)?;
+    Ok((wrapped, nonce))
+}
+
+pub fn unwrap_key(kek: &[u8; 32], nonce: &[u8; 24], wrapped: &[u8]) -> Result<SecretKey> {
+    let key = decrypt_with_aad(kek, nonce, wrapped, KEY_WRAP_AAD)?;
+    let key: [u8; 32] = key
+        .as_slice()
+        .try_into()
+        .map_err(|_| Error::Corrupt("wrapped vault key has an invalid length".to_string()))?;
+    Ok(SecretKey::new(key))
 }
@@ -0,0 +1,55 @@
+use std::io;
+
+/// Errors returned by the rustpass library.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Incorrect master password or corrupted vault.")]
+    WrongPassword,
+    #[error("This vault requires a keyfile (--keyfile <PATH>) together with the master password.")]
+    KeyfileRequired,
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Vault file is truncated.")]
+    Truncated,
+    #[error("Vault is corrupted: {0}")]
+    Corrupt(String),
+    #[error("Unsupported vault format version {0}.")]
+    UnsupportedVersion(u16),
+    #[error("This vault was written by a newer rustpass (schema version {0}). Upgrade rustpass to open it.")]
+    UnsupportedSchema(u32),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(String),
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error("Cryptographic error: {0}")]
+    Crypto(String),
+    #[error("Vault {0} is locked by another process. Try again once it has finished.")]
+    Locked(String),
+    #[error("The vault was modified by another process since it was loaded. Nothing was saved; run the command again.")]
+    Conflict,
+    #[error("Database error: {0}")]
+    Database(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<ciborium::ser::Error<io::Error>> for Error {
+    fn from(e: ciborium::ser::Error<io::Error>) -> Self {
+        Error::Serialization(e.to_string())
+    }
+}
+
+impl From<ciborium::de::Error<io::Error>> for Error {
+    fn from(e: ciborium::de::Error<io::Error>) -> Self {
+        Error::Serialization(e.to_string())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Database(e.to_string())
+    }
+}
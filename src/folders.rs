@@ -0,0 +1,41 @@
+use crate::error::{Error, Result};
+use crate::model::Entry;
+use std::collections::BTreeMap;
+
+// Folders are not stored on their own: an entry's folder is a path of
+// `/`-separated segments, and a folder exists while some entry is in it or
+// in one of its subfolders.
+
+/// Normalizes a folder path: segments are trimmed, and empty ones (from
+/// leading, trailing or doubled slashes) dropped. A path with no segment
+/// left is refused.
+pub fn normalize(path: &str) -> Result<String> {
+    let segments: Vec<&str> = path.split('/').map(str::trim).filter(|s| !s.is_empty()).collect();
+    if segments.is_empty() {
+        return Err(Error::InvalidInput(format!("'{}' is not a folder path such as work/aws/prod.", path)));
+    }
+    Ok(segments.join("/"))
+}
+
+/// Whether `folder` is `path` or one of its subfolders.
+pub fn contains(path: &str, folder: &str) -> bool {
+    folder.strip_prefix(path).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// `folder` with its `from` part replaced by `to`, if it is in `from`.
+pub fn reparent(folder: &str, from: &str, to: &str) -> Option<String> {
+    contains(from, folder).then(|| format!("{}{}", to, &folder[from.len()..]))
+}
+
+/// The number of entries in each folder, counting those in its subfolders,
+/// keyed by path segments so a folder sorts right before its subfolders.
+pub fn counts<'a>(entries: impl IntoIterator<Item = &'a Entry>) -> BTreeMap<Vec<String>, usize> {
+    let mut counts = BTreeMap::new();
+    for folder in entries.into_iter().filter_map(|e| e.folder.as_deref()) {
+        let segments: Vec<String> = folder.split('/').map(str::to_string).collect();
+        for depth in 1..=segments.len() {
+            *counts.entry(segments[..depth].to_vec()).or_insert(0) += 1;
+        }
+    }
+    counts
+}
@@ -0,0 +1,117 @@
+use crate::crypto::KdfParams;
+use crate::error::{Error, Result};
+use serde::{Serialize, Deserialize};
+
+// On-disk layout of a vault file:
+//
+//   magic (8) | format version (u16 LE) | header length (u32 LE) | header (CBOR) | ciphertext
+//
+// Everything before the ciphertext is passed to the AEAD as associated data,
+// so tampering with the header is detected when the vault is decrypted.
+pub const MAGIC: &[u8; 8] = b"RUSTPASS";
+pub const FORMAT_VERSION: u16 = 2;
+const PREFIX_LEN: usize = MAGIC.len() + 2 + 4;
+
+/// Version 1 header: the payload was encrypted directly with the key derived
+/// from the master password.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaultHeaderV1 {
+    pub kdf: KdfParams,
+    pub salt: Vec<u8>,
+    pub nonce: [u8; 24],
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlotKind {
+    #[default]
+    Password,
+    /// Unlocked by the vault's recovery phrase rather than a passphrase.
+    Recovery,
+}
+
+/// A copy of the vault's data key, wrapped by a key derived from one secret.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeySlot {
+    pub label: String,
+    #[serde(default)]
+    pub kind: SlotKind,
+    /// Whether a keyfile must be supplied alongside the password.
+    #[serde(default)]
+    pub keyfile: bool,
+    pub kdf: KdfParams,
+    pub salt: Vec<u8>,
+    pub nonce: [u8; 24],
+    pub wrapped_key: Vec<u8>,
+}
+
+/// Version 2 header: the payload is encrypted with a random data key which
+/// is stored wrapped in one or more key slots.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaultHeader {
+    pub nonce: [u8; 24],
+    pub key_slots: Vec<KeySlot>,
+}
+
+pub struct VaultFile {
+    pub version: u16,
+    pub header_bytes: Vec<u8>,
+    pub aad: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl VaultFile {
+    pub fn header(&self) -> Result<VaultHeader> {
+        if self.version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(self.version));
+        }
+        ciborium::de::from_reader(self.header_bytes.as_slice())
+            .map_err(|e| Error::Corrupt(format!("vault header: {}", e)))
+    }
+
+    pub fn header_v1(&self) -> Result<VaultHeaderV1> {
+        if self.version != 1 {
+            return Err(Error::UnsupportedVersion(self.version));
+        }
+        ciborium::de::from_reader(self.header_bytes.as_slice())
+            .map_err(|e| Error::Corrupt(format!("vault header: {}", e)))
+    }
+}
+
+pub fn has_magic(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn encode_header(header: &VaultHeader) -> Result<Vec<u8>> {
+    let mut header_buf = Vec::new();
+    ciborium::ser::into_writer(header, &mut header_buf)?;
+    let mut out = Vec::with_capacity(PREFIX_LEN + header_buf.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    out.extend_from_slice(&(header_buf.len() as u32).to_le_bytes());
+    out.extend_from_slice(&header_buf);
+    Ok(out)
+}
+
+pub fn decode(data: &[u8]) -> Result<VaultFile> {
+    if !has_magic(data) {
+        return Err(Error::Corrupt("not a rustpass vault file".to_string()));
+    }
+    if data.len() < PREFIX_LEN {
+        return Err(Error::Truncated);
+    }
+    let version = u16::from_le_bytes([data[8], data[9]]);
+    if version == 0 || version > FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let header_len = u32::from_le_bytes([data[10], data[11], data[12], data[13]]) as usize;
+    let header_end = PREFIX_LEN
+        .checked_add(header_len)
+        .filter(|end| *end <= data.len())
+        .ok_or(Error::Truncated)?;
+    Ok(VaultFile {
+        version,
+        header_bytes: data[PREFIX_LEN..header_end].to_vec(),
+        aad: data[..header_end].to_vec(),
+        ciphertext: data[header_end..].to_vec(),
+    })
+}
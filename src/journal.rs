@@ -0,0 +1,466 @@
+use crate::crypto::{decrypt_with_aad, encrypt_with_aad, random_nonce};
+use crate::error::{Error, Result};
+use crate::model::{decode_payload, encode_payload, payload_schema, CustomField, Entry, EntryKind, EntryUri, EntryV1, EntryV3, EntryV4, EntryV5, EntryV6, Vault, VersionedEntry};
+use crate::secret::SecretString;
+use crate::storage::StorageBackend;
+use chrono::{DateTime, Utc};
+use ciborium::{ser, de};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+
+// Changes made since a vault blob was written are appended to
+// `<vault key>.journal` instead of rewriting the blob. Each record is
+//
+//   record length (u32 LE) | CBOR { seq, prev, nonce, ciphertext }
+//
+// where `prev` is the SHA-256 of the previous record, or of the vault blob
+// for the first one, and the ciphertext is encrypted under the vault's data
+// key with `seq` and `prev` as associated data. Records therefore cannot be
+// reordered, dropped from the middle or replayed against another blob. The
+// encrypted `<vault key>.journal.head` holds the record count and last hash,
+// so records cut off the end are noticed too. Compacting writes a new blob
+// with the records folded in and removes both files.
+const RECORD_AAD: &[u8] = b"rustpass-journal-record";
+const HEAD_AAD: &[u8] = b"rustpass-journal-head";
+
+/// One change to an entry, as recorded in the journal. `E` is the entry
+/// type of the schema the record was written in.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Change<E = Entry> {
+    AddEntry(E),
+    EditField { id: u32, field: EntryField },
+    RenameEntry { id: u32, name: String },
+    DeleteEntry { id: u32 },
+    /// The entry was used (shown or copied); it sets `last_used_at`.
+    UseEntry { id: u32 },
+}
+
+impl Change {
+    /// Id of the entry the change is to.
+    pub fn entry_id(&self) -> u32 {
+        match self {
+            Change::AddEntry(entry) => entry.id,
+            Change::EditField { id, .. } | Change::RenameEntry { id, .. } | Change::DeleteEntry { id } | Change::UseEntry { id } => *id,
+        }
+    }
+}
+
+/// A new value for one field of an entry. `Username` and `Password` apply
+/// to logins; any other change to the kind-specific fields replaces them
+/// all with `Kind`; changes to the custom fields, URIs or tags replace the
+/// whole list with `Fields`, `Uris` or `Tags`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum EntryField {
+    Username(String),
+    Password(SecretString),
+    Notes(Option<String>),
+    Kind(EntryKind),
+    Fields(Vec<CustomField>),
+    Uris(Vec<EntryUri>),
+    Tags(BTreeSet<String>),
+    Folder(Option<String>),
+}
+
+/// The changes of one commit, which are applied together.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record<E = Entry> {
+    pub at: DateTime<Utc>,
+    pub changes: Vec<Change<E>>,
+}
+
+impl<E> Record<E> {
+    fn map_entries<F>(self, f: impl Fn(E) -> F) -> Record<F> {
+        let changes = self
+            .changes
+            .into_iter()
+            .map(|change| match change {
+                Change::AddEntry(entry) => Change::AddEntry(f(entry)),
+                Change::EditField { id, field } => Change::EditField { id, field },
+                Change::RenameEntry { id, name } => Change::RenameEntry { id, name },
+                Change::DeleteEntry { id } => Change::DeleteEntry { id },
+                Change::UseEntry { id } => Change::UseEntry { id },
+            })
+            .collect();
+        Record { at: self.at, changes }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawRecord {
+    seq: u64,
+    prev: [u8; 32],
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Head {
+    records: u64,
+    last: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawHead {
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+pub fn journal_key(key: &str) -> String {
+    format!("{}.journal", key)
+}
+
+pub fn head_key(key: &str) -> String {
+    format!("{}.journal.head", key)
+}
+
+/// The changes that turn `old` into `new`, matching entries by id.
+pub fn diff(old: &Vault, new: &Vault) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for entry in &new.entries {
+        let id = entry.id;
+        let Some(before) = old.entries.iter().find(|e| e.id == id) else {
+            changes.push(Change::AddEntry(entry.clone()));
+            continue;
+        };
+        if before.name != entry.name {
+            changes.push(Change::RenameEntry { id, name: entry.name.clone() });
+        }
+        match (before.as_login(), entry.as_login()) {
+            (Some(old), Some(new)) => {
+                if old.username != new.username {
+                    changes.push(Change::EditField { id, field: EntryField::Username(new.username.clone()) });
+                }
+                if old.password != new.password {
+                    changes.push(Change::EditField { id, field: EntryField::Password(new.password.clone()) });
+                }
+            }
+            _ if before.kind != entry.kind => {
+                changes.push(Change::EditField { id, field: EntryField::Kind(entry.kind.clone()) });
+            }
+            _ => {}
+        }
+        if before.notes != entry.notes {
+            changes.push(Change::EditField { id, field: EntryField::Notes(entry.notes.clone()) });
+        }
+        if before.fields != entry.fields {
+            changes.push(Change::EditField { id, field: EntryField::Fields(entry.fields.clone()) });
+        }
+        if before.uris != entry.uris {
+            changes.push(Change::EditField { id, field: EntryField::Uris(entry.uris.clone()) });
+        }
+        if before.tags != entry.tags {
+            changes.push(Change::EditField { id, field: EntryField::Tags(entry.tags.clone()) });
+        }
+        if before.folder != entry.folder {
+            changes.push(Change::EditField { id, field: EntryField::Folder(entry.folder.clone()) });
+        }
+        if before.last_used_at != entry.last_used_at {
+            changes.push(Change::UseEntry { id });
+        }
+    }
+    for entry in &old.entries {
+        if !new.entries.iter().any(|e| e.id == entry.id) {
+            changes.push(Change::DeleteEntry { id: entry.id });
+        }
+    }
+    changes
+}
+
+/// Applies `record` to `vault`, stamping the entries it adds, changes or
+/// uses with the record's time. Ids of added entries are never handed out
+/// again. A change that does not fit the vault, such
+/// as editing a missing entry, means the journal is corrupt.
+pub fn apply(vault: &mut Vault, record: &Record) -> Result<()> {
+    let at = Some(record.at);
+    for change in &record.changes {
+        match change {
+            Change::AddEntry(entry) => {
+                if vault.entries.iter().any(|e| e.id == entry.id) {
+                    return Err(Error::Corrupt(format!("journal adds entry {} twice", entry.id)));
+                }
+                let mut entry = entry.clone();
+                entry.created_at = entry.created_at.or(at);
+                entry.modified_at = entry.modified_at.or(at);
+                if entry.password().is_some() {
+                    entry.password_changed_at = entry.password_changed_at.or(at);
+                }
+                vault.next_id = vault.next_id.max(entry.id + 1);
+                vault.entries.push(entry);
+            }
+            Change::EditField { id, field } => {
+                let entry = entry_mut(vault, *id)?;
+                let password = entry.password().cloned();
+                match field {
+                    EntryField::Username(username) => login_mut(entry)?.username = username.clone(),
+                    EntryField::Password(password) => login_mut(entry)?.password = password.clone(),
+                    EntryField::Notes(notes) => entry.notes = notes.clone(),
+                    EntryField::Kind(kind) => entry.kind = kind.clone(),
+                    EntryField::Fields(fields) => entry.fields = fields.clone(),
+                    EntryField::Uris(uris) => entry.uris = uris.clone(),
+                    EntryField::Tags(tags) => entry.tags = tags.clone(),
+                    EntryField::Folder(folder) => entry.folder = folder.clone(),
+                }
+                entry.modified_at = at;
+                if entry.password() != password.as_ref() {
+                    entry.password_changed_at = at;
+                }
+            }
+            Change::RenameEntry { id, name } => {
+                let entry = entry_mut(vault, *id)?;
+                entry.name = name.clone();
+                entry.modified_at = at;
+            }
+            Change::UseEntry { id } => entry_mut(vault, *id)?.last_used_at = at,
+            Change::DeleteEntry { id } => {
+                entry_mut(vault, *id)?;
+                vault.entries.retain(|e| e.id != *id);
+            }
+        }
+    }
+    vault.last_modified = record.at;
+    Ok(())
+}
+
+fn entry_mut(vault: &mut Vault, id: u32) -> Result<&mut Entry> {
+    vault
+        .entries
+        .iter_mut()
+        .find(|e| e.id == id)
+        .ok_or_else(|| Error::Corrupt(format!("journal changes missing entry {}", id)))
+}
+
+fn login_mut(entry: &mut Entry) -> Result<&mut crate::model::Login> {
+    let id = entry.id;
+    entry
+        .as_login_mut()
+        .ok_or_else(|| Error::Corrupt(format!("journal sets login fields on entry {}, which is not a login", id)))
+}
+
+/// The verified journal of one vault blob.
+pub(crate) struct Journal {
+    pub(crate) records: Vec<Record>,
+    // Hash of the vault blob the journal continues.
+    genesis: [u8; 32],
+    // Hash of the last record, or of the blob while there are none.
+    last: [u8; 32],
+    // Length of the stored journal that holds `records`, and whether that is
+    // all of it. Anything after is a torn append, or a journal left over from
+    // before the blob was last written; it is dropped on the next append.
+    len: usize,
+    clean: bool,
+}
+
+impl Journal {
+    /// An empty journal for the vault blob `base`.
+    pub(crate) fn new(base: &[u8]) -> Journal {
+        let genesis = Sha256::digest(base).into();
+        Journal {
+            records: Vec::new(),
+            genesis,
+            last: genesis,
+            len: 0,
+            clean: false,
+        }
+    }
+
+    /// Reads and verifies the journal stored for the blob `key`, whose
+    /// contents are `base`.
+    pub(crate) fn read(storage: &dyn StorageBackend, key: &str, base: &[u8], data_key: &[u8; 32]) -> Result<Journal> {
+        let data = read_optional(storage, &journal_key(key))?;
+        let head = read_optional(storage, &head_key(key))?;
+        Journal::parse(base, data.as_deref(), head.as_deref(), data_key)
+    }
+
+    /// Verifies a journal and its head given as bytes, e.g. from a snapshot.
+    pub(crate) fn parse(base: &[u8], data: Option<&[u8]>, head: Option<&[u8]>, data_key: &[u8; 32]) -> Result<Journal> {
+        let data = data.unwrap_or_default();
+        let mut journal = Journal::new(base);
+        let first = split_frame(data).map(|(frame, _)| raw_record(frame, 0)).transpose()?;
+        match first {
+            Some(first) if first.prev != journal.genesis => {
+                // Written against an earlier blob. A head for this blob means
+                // its own records were cut off, unless it counts none yet: it
+                // is written before the first record replaces the old journal.
+                match head.and_then(|head| open_head(head, &journal.genesis, data_key)) {
+                    Some(head) if head.records > 0 => return Err(Error::Truncated),
+                    Some(_) => return Ok(journal),
+                    None => {}
+                }
+                // Otherwise compaction was interrupted before it removed the
+                // journal, which must still check out against its own head.
+                let mut stale = Journal { genesis: first.prev, last: first.prev, ..Journal::new(base) };
+                let hashes = stale.read_records(data, data_key)?;
+                stale.check_head(head, &hashes, data_key)?;
+            }
+            _ => {
+                let hashes = journal.read_records(data, data_key)?;
+                journal.check_head(head, &hashes, data_key)?;
+            }
+        }
+        Ok(journal)
+    }
+
+    // Reads the records chained from `last` out of `data` and returns their
+    // hashes.
+    fn read_records(&mut self, data: &[u8], data_key: &[u8; 32]) -> Result<Vec<[u8; 32]>> {
+        let mut hashes = Vec::new();
+        let mut rest = data;
+        while let Some((frame, tail)) = split_frame(rest) {
+            let seq = self.records.len() as u64;
+            let raw = raw_record(frame, seq)?;
+            if raw.seq != seq || raw.prev != self.last {
+                return Err(Error::Corrupt(format!("journal record {} is out of order", seq)));
+            }
+            let plaintext = decrypt_with_aad(data_key, &raw.nonce, &raw.ciphertext, &record_aad(seq, &raw.prev))
+                .map_err(|_| Error::Corrupt(format!("journal record {} failed authentication", seq)))?;
+            self.records.push(decode_record(&plaintext)?);
+            self.last = Sha256::digest(frame).into();
+            hashes.push(self.last);
+            rest = tail;
+            self.len = data.len() - rest.len();
+        }
+        self.clean = self.len == data.len();
+        Ok(hashes)
+    }
+
+    // Checks the records read, with hashes `hashes`, against the head. Every
+    // append updates the head right after its record, so the head may lag
+    // by one record after a crash but is never missing while there are any.
+    fn check_head(&self, head: Option<&[u8]>, hashes: &[[u8; 32]], data_key: &[u8; 32]) -> Result<()> {
+        let Some(sealed) = head else {
+            return if hashes.is_empty() { Ok(()) } else { Err(Error::Truncated) };
+        };
+        let Some(head) = open_head(sealed, &self.genesis, data_key) else {
+            // With no records, the head is one an earlier blob left behind.
+            return match hashes.is_empty() {
+                true => Ok(()),
+                false => Err(Error::Corrupt("journal head failed authentication".to_string())),
+            };
+        };
+        let count = head.records as usize;
+        if count > hashes.len() {
+            return Err(Error::Truncated);
+        }
+        if count + 1 < hashes.len() {
+            return Err(Error::Corrupt("journal has records its head does not count".to_string()));
+        }
+        if count > 0 && hashes[count - 1] != head.last {
+            return Err(Error::Corrupt("journal does not match its head".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Appends `record` to the journal stored for the blob `key`, then
+    /// updates the head. A crash in between leaves a valid record the head
+    /// does not count yet, which is kept.
+    pub(crate) fn append(&mut self, storage: &dyn StorageBackend, key: &str, data_key: &[u8; 32], record: Record) -> Result<()> {
+        let seq = self.records.len() as u64;
+        let frame = encode_record(seq, &self.last, data_key, &record)?;
+        if seq == 0 {
+            // A head counting no records yet, so the first one is never
+            // stored without a head.
+            let head = Head { records: 0, last: self.genesis };
+            storage.write(&head_key(key), &seal_head(&head, &self.genesis, data_key)?, 0)?;
+        }
+        let key_journal = journal_key(key);
+        if self.clean {
+            storage.append(&key_journal, &frame)?;
+        } else {
+            let mut data = read_optional(storage, &key_journal)?.unwrap_or_default();
+            data.truncate(self.len);
+            data.extend_from_slice(&frame);
+            storage.write(&key_journal, &data, 0)?;
+        }
+        self.last = Sha256::digest(&frame).into();
+        self.len += frame.len();
+        self.clean = true;
+        self.records.push(record);
+
+        let head = Head { records: seq + 1, last: self.last };
+        storage.write(&head_key(key), &seal_head(&head, &self.genesis, data_key)?, 0)
+    }
+}
+
+/// Removes the journal of the blob `key`, once the blob holds its records.
+/// The head goes last, so a journal is never left without one.
+/// Number of complete records in the journal of blob `key`, counted without
+/// decrypting them.
+pub fn record_count(storage: &dyn StorageBackend, key: &str) -> Result<usize> {
+    let Some(data) = read_optional(storage, &journal_key(key))? else {
+        return Ok(0);
+    };
+    let (mut count, mut rest) = (0, data.as_slice());
+    while let Some((_, next)) = split_frame(rest) {
+        count += 1;
+        rest = next;
+    }
+    Ok(count)
+}
+
+pub(crate) fn remove(storage: &dyn StorageBackend, key: &str) -> Result<()> {
+    storage.delete(&journal_key(key))?;
+    storage.delete(&head_key(key))
+}
+
+pub(crate) fn read_optional(storage: &dyn StorageBackend, key: &str) -> Result<Option<Vec<u8>>> {
+    match storage.read(key) {
+        Ok(data) => Ok(Some(data)),
+        Err(Error::NotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// The next complete `length | record` frame and what follows it.
+fn split_frame(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    data.split_at_checked(len.checked_add(4)?)
+}
+
+fn raw_record(frame: &[u8], seq: u64) -> Result<RawRecord> {
+    de::from_reader(&frame[4..]).map_err(|e| Error::Corrupt(format!("journal record {}: {}", seq, e)))
+}
+
+fn encode_record(seq: u64, prev: &[u8; 32], data_key: &[u8; 32], record: &Record) -> Result<Vec<u8>> {
+    let buf = encode_payload(record)?;
+    let nonce = random_nonce();
+    let ciphertext = encrypt_with_aad(data_key, &nonce, &buf, &record_aad(seq, prev))?;
+    let mut body = Vec::new();
+    ser::into_writer(&RawRecord { seq, prev: *prev, nonce, ciphertext }, &mut body)?;
+    let mut frame = (body.len() as u32).to_le_bytes().to_vec();
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+// Records are tagged with the schema they were written in; entries they add
+// in an older schema are decoded as such and migrated.
+fn decode_record(plaintext: &[u8]) -> Result<Record> {
+    Ok(match payload_schema(plaintext)? {
+        schema @ (1 | 2) => decode_payload::<Record<EntryV1>>(plaintext, schema)?.map_entries(|e| VersionedEntry::V1(e).migrate()),
+        3 => decode_payload::<Record<EntryV3>>(plaintext, 3)?.map_entries(|e| VersionedEntry::V3(e).migrate()),
+        4 => decode_payload::<Record<EntryV4>>(plaintext, 4)?.map_entries(|e| VersionedEntry::V4(e).migrate()),
+        5 => decode_payload::<Record<EntryV5>>(plaintext, 5)?.map_entries(|e| VersionedEntry::V5(e).migrate()),
+        6 => decode_payload::<Record<EntryV6>>(plaintext, 6)?.map_entries(|e| VersionedEntry::V6(e).migrate()),
+        schema => decode_payload(plaintext, schema)?,
+    })
+}
+
+fn record_aad(seq: u64, prev: &[u8; 32]) -> Vec<u8> {
+    [RECORD_AAD, &seq.to_le_bytes(), prev].concat()
+}
+
+fn seal_head(head: &Head, genesis: &[u8; 32], data_key: &[u8; 32]) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    ser::into_writer(head, &mut buf)?;
+    let nonce = random_nonce();
+    let ciphertext = encrypt_with_aad(data_key, &nonce, &buf, &[HEAD_AAD, genesis].concat())?;
+    let mut sealed = Vec::new();
+    ser::into_writer(&RawHead { nonce, ciphertext }, &mut sealed)?;
+    Ok(sealed)
+}
+
+fn open_head(sealed: &[u8], genesis: &[u8; 32], data_key: &[u8; 32]) -> Option<Head> {
+    let raw: RawHead = de::from_reader(sealed).ok()?;
+    let plaintext = decrypt_with_aad(data_key, &raw.nonce, &raw.ciphertext, &[HEAD_AAD, genesis].concat()).ok()?;
+    de::from_reader(plaintext.as_slice()).ok()
+}
@@ -1,3 +1,21 @@
 pub mod model;
+pub mod backup;
+pub mod config;
 pub mod crypto;
+pub mod error;
+pub mod folders;
+pub mod format;
+pub mod journal;
+pub mod recovery;
+pub mod secret;
+pub mod shamir;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod storage;
+pub mod store;
+pub mod totp;
+pub mod urls;
 pub mod vault;
+pub mod verify;
+
+pub use error::{Error, Result};
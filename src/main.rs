@@ -1,21 +1,29 @@
-mod model;
-mod crypto;
-mod vault;
-use rand::RngCore;
-
-use clap::{Parser, Subcommand};
-use anyhow::Result;
-use model::Entry;
-use chrono::Utc;
+use rustpass::{backup, crypto, folders, model, shamir};
+use rustpass::crypto::MasterKey;
+use rustpass::journal::{Change, EntryField};
+use rustpass::secret::SecretString;
+use rustpass::store::{Session, VaultStore};
+use rustpass::Error;
+
+use clap::{Args, Parser, Subcommand};
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use model::{ApiCredential, Card, CustomField, Entry, EntryKind, EntryTime, EntryUri, FieldType, Identity, Login, SecureNote, SshKey, UriMatch};
 use colored::*;
+use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::ExitCode;
+use std::str::FromStr;
 
 #[derive(Parser)]
 #[command(name = "rustpass")]
 #[command(about = "Minimalist Password Manager (Bitwarden/ProtonPass style)", long_about = None)]
 struct Cli {
+    /// Directory holding the vaults and config.toml (defaults to the per-user data directory)
+    #[arg(long, global = true, env = "RUSTPASS_HOME")]
+    data_dir: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -26,11 +34,20 @@ enum Commands {
     CreateVault {
         #[arg(long)]
         name: String,
+        /// Also print a recovery phrase that can unlock the vault
+        #[arg(long)]
+        recovery: bool,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
     },
     /// Delete a vault
     DeleteVault {
         #[arg(long)]
         name: String,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
     },
     /// Edit vault name
     EditVault {
@@ -47,17 +64,36 @@ enum Commands {
         name: String,
     },
     /// Initialize (login) to a vault
-    Init,
-    /// Add a new entry to the selected vault
-    Add {
+    Init {
+        /// Keyfile required to unlock the vault, if it was created with one
         #[arg(long)]
-        name: String,
+        keyfile: Option<PathBuf>,
+    },
+    /// Add a new entry to the selected vault: a login, or the kind given as a subcommand
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Add {
+        #[command(subcommand)]
+        kind: Option<Box<AddKind>>,
+        #[arg(long, required = true)]
+        name: Option<String>,
+        #[arg(long, required = true)]
+        username: Option<String>,
+        #[arg(long, required = true)]
+        password: Option<String>,
         #[arg(long)]
-        username: String,
+        notes: Option<String>,
+        /// A site the entry is for, matched by base domain; repeat for more
+        #[arg(long = "uri", value_name = "URI")]
+        uris: Vec<String>,
+        /// A tag for the entry; repeat for more
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Folder path such as work/aws/prod
         #[arg(long)]
-        password: String,
+        folder: Option<String>,
+        /// Keyfile required to unlock the vault, if it was created with one
         #[arg(long)]
-        notes: Option<String>,
+        keyfile: Option<PathBuf>,
     },
     /// Edit an entry in the selected vault
     EditEntry {
@@ -65,6 +101,9 @@ enum Commands {
         id: Option<u32>,
         #[arg(long)]
         name: Option<String>,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
     },
     /// Delete an entry from the selected vault
     DeleteEntry {
@@ -72,474 +111,1756 @@ enum Commands {
         id: Option<u32>,
         #[arg(long)]
         name: Option<String>,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
     },
     /// List all entries in the selected vault
-    List,
+    List {
+        #[command(flatten)]
+        filter: EntryFilter,
+        /// Show entries grouped by folder, with the number of entries in each
+        #[arg(long)]
+        tree: bool,
+        /// Order by name, created, modified, changed (password) or used; entries with no recorded time come first
+        #[arg(long, value_name = "KEY")]
+        sort: Option<SortKey>,
+        /// Reverse the order
+        #[arg(long)]
+        reverse: bool,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
     /// Get entry details from the selected vault
     Get {
         #[arg(long)]
         id: Option<u32>,
         #[arg(long)]
         name: Option<String>,
+        /// Show the values of hidden and TOTP custom fields
+        #[arg(long)]
+        reveal: bool,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Add, change or remove the custom fields of an entry in the selected vault
+    Field {
+        #[command(subcommand)]
+        action: FieldCommand,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Add or remove the sites (URIs) of an entry in the selected vault
+    Uri {
+        #[command(subcommand)]
+        action: UriCommand,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Organize the entries of the selected vault into folders
+    Folder {
+        #[command(subcommand)]
+        action: FolderCommand,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Tag or untag entries of the selected vault in bulk
+    Tag {
+        #[command(subcommand)]
+        action: TagCommand,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// List the entries whose URIs match a page URL, best match first
+    FindUrl {
+        url: String,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
     },
     /// Save the selected vault
-    Save,
+    Save {
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Fold the change journal of a vault back into its vault file
+    Compact {
+        /// Vault to compact (defaults to the selected vault)
+        #[arg(long)]
+        vault: Option<String>,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Show the changes recorded since the selected vault was last compacted
+    History {
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Change the master password of the selected vault
+    ChangePassword {
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Manage the passphrases (key slots) that can unlock the selected vault
+    Keyslot {
+        #[command(subcommand)]
+        action: KeyslotCommand,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Write a timestamped snapshot of the encrypted vault, or list snapshots
+    Backup {
+        #[command(subcommand)]
+        action: Option<BackupCommand>,
+        /// Vault to back up (defaults to the selected vault)
+        #[arg(long)]
+        vault: Option<String>,
+        /// Directory to write the snapshot to (defaults to the backups folder in the data directory)
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Check vaults for damage and offer to restore the newest good backup
+    Verify {
+        /// Vault to check (defaults to the selected vault)
+        #[arg(long, conflicts_with = "all")]
+        vault: Option<String>,
+        /// Check every vault in the data directory
+        #[arg(long)]
+        all: bool,
+        /// Keyfile required to unlock the vaults, if they were created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Replace a vault with a snapshot, after checking the snapshot unlocks
+    Restore {
+        /// Snapshot file written by `backup`
+        #[arg(long)]
+        from: PathBuf,
+        /// Vault to restore into (defaults to the vault the snapshot was taken of)
+        #[arg(long)]
+        vault: Option<String>,
+        /// Keyfile required to unlock the snapshot, if the vault used one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Unlock a vault with its recovery phrase and set a new master password
+    Recover {
+        /// Vault to recover (defaults to the selected vault)
+        #[arg(long)]
+        vault: Option<String>,
+        /// Keyfile to require together with the new master password
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Manage the recovery phrase of the selected vault
+    Recovery {
+        #[command(subcommand)]
+        action: RecoveryCommand,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Manage keyfiles
+    Keyfile {
+        #[command(subcommand)]
+        action: KeyfileCommand,
+    },
+    /// Create per-entry SQLite vaults and convert vault files to and from them
+    #[cfg(feature = "sqlite")]
+    Sqlite {
+        #[command(subcommand)]
+        action: SqliteCommand,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Benchmark this machine and suggest Argon2id parameters
+    TuneKdf {
+        #[arg(long, default_value_t = 1000)]
+        target_ms: u64,
+        /// Re-encrypt the selected vault with the suggested parameters
+        #[arg(long)]
+        apply: bool,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+    /// Re-encrypt the selected vault with new Argon2id parameters
+    SetKdf {
+        /// Memory cost in KiB
+        #[arg(long)]
+        memory: Option<u32>,
+        #[arg(long)]
+        iterations: Option<u32>,
+        #[arg(long)]
+        parallelism: Option<u32>,
+        /// Keyfile required to unlock the vault, if it was created with one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
 }
 
-fn vaults_dir() -> PathBuf {
-    let proj_dirs = directories::ProjectDirs::from("com", "rustpass", "RustPass").unwrap();
-    let dir = proj_dirs.data_dir();
-    fs::create_dir_all(dir).unwrap();
-    dir.to_path_buf()
+#[derive(Subcommand)]
+enum AddKind {
+    /// A username and password (what `add` adds without a kind)
+    Login {
+        #[command(flatten)]
+        entry: NewEntryArgs,
+        #[arg(long)]
+        username: String,
+        #[arg(long)]
+        password: String,
+    },
+    /// A secure note, whose text only `get` shows
+    Note {
+        #[command(flatten)]
+        entry: NewEntryArgs,
+        #[arg(long)]
+        text: String,
+    },
+    /// A payment card
+    Card {
+        #[command(flatten)]
+        entry: NewEntryArgs,
+        #[arg(long)]
+        cardholder: String,
+        /// Card number; spaces and dashes are ignored
+        #[arg(long)]
+        number: String,
+        /// Expiry date as MM/YY or MM/YYYY
+        #[arg(long)]
+        expiry: String,
+        #[arg(long)]
+        cvv: String,
+    },
+    /// A person's details and document numbers
+    Identity {
+        #[command(flatten)]
+        entry: NewEntryArgs,
+        #[arg(long)]
+        full_name: String,
+        #[arg(long)]
+        email: Option<String>,
+        #[arg(long)]
+        phone: Option<String>,
+        #[arg(long)]
+        address: Option<String>,
+        #[arg(long)]
+        passport: Option<String>,
+        /// Driver's license number
+        #[arg(long)]
+        license: Option<String>,
+        #[arg(long)]
+        national_id: Option<String>,
+    },
+    /// An SSH key pair, read from its key files
+    SshKey {
+        #[command(flatten)]
+        entry: NewEntryArgs,
+        #[arg(long)]
+        private_key: PathBuf,
+        #[arg(long)]
+        public_key: Option<PathBuf>,
+        /// Prompt for the private key's passphrase and store it too
+        #[arg(long)]
+        passphrase: bool,
+    },
+    /// An API key or token
+    Api {
+        #[command(flatten)]
+        entry: NewEntryArgs,
+        /// The public half, such as an access key id or client id
+        #[arg(long)]
+        key_id: Option<String>,
+        #[arg(long)]
+        secret: String,
+        #[arg(long)]
+        endpoint: Option<String>,
+    },
 }
 
-fn vault_file(name: &str) -> PathBuf {
-    vaults_dir().join(format!("vault_{}.cbor", name))
+#[derive(Args)]
+struct NewEntryArgs {
+    #[arg(long)]
+    name: String,
+    #[arg(long)]
+    notes: Option<String>,
+    /// A site the entry is for, matched by base domain; repeat for more
+    #[arg(long = "uri", value_name = "URI")]
+    uris: Vec<String>,
+    /// A tag for the entry; repeat for more
+    #[arg(long = "tag", value_name = "TAG")]
+    tags: Vec<String>,
+    /// Folder path such as work/aws/prod
+    #[arg(long)]
+    folder: Option<String>,
+    /// Keyfile required to unlock the vault, if it was created with one
+    #[arg(long)]
+    keyfile: Option<PathBuf>,
 }
 
-fn salt_file(name: &str) -> PathBuf {
-    vaults_dir().join(format!("salt_{}.bin", name))
+#[derive(Subcommand)]
+enum FieldCommand {
+    /// Add a custom field after the entry's other custom fields
+    Add(FieldArgs),
+    /// Change the value or type of a custom field
+    Set(FieldArgs),
+    /// Remove a custom field
+    Remove {
+        /// ID of the entry
+        #[arg(long)]
+        entry: u32,
+        #[arg(long)]
+        label: String,
+    },
 }
 
-fn current_vault_file() -> PathBuf {
-    vaults_dir().join(".current_vault")
+#[derive(Args)]
+struct FieldArgs {
+    /// ID of the entry
+    #[arg(long)]
+    entry: u32,
+    #[arg(long)]
+    label: String,
+    /// The value; prompted for when omitted, without echo for hidden and TOTP fields
+    #[arg(long)]
+    value: Option<String>,
+    /// text, hidden, url, email, date (YYYY-MM-DD) or totp (base32 secret or otpauth:// URI)
+    #[arg(long = "type", value_name = "TYPE")]
+    kind: Option<FieldType>,
+    /// Shorthand for --type hidden
+    #[arg(long, conflicts_with = "kind")]
+    hidden: bool,
 }
 
-fn set_current_vault(name: &str) {
-    fs::write(current_vault_file(), name).unwrap();
+#[derive(Subcommand)]
+enum UriCommand {
+    /// Add a URI to an entry
+    Add {
+        /// ID of the entry
+        #[arg(long)]
+        entry: u32,
+        #[arg(long)]
+        uri: String,
+        /// How page URLs are matched: domain (same registrable domain), host, prefix, regex or never
+        #[arg(long = "match", value_name = "MODE", default_value = "domain")]
+        mode: UriMatch,
+    },
+    /// Remove a URI from an entry
+    Remove {
+        /// ID of the entry
+        #[arg(long)]
+        entry: u32,
+        #[arg(long)]
+        uri: String,
+    },
+}
+
+#[derive(Args)]
+struct EntryFilter {
+    /// Only entries with this tag
+    #[arg(long)]
+    tag: Option<String>,
+    /// Only entries in this folder or its subfolders
+    #[arg(long)]
+    folder: Option<String>,
+    /// Only entries created before this date (YYYY-MM-DD)
+    #[arg(long, value_name = "DATE")]
+    created_before: Option<NaiveDate>,
+    /// Only entries created on or after this date
+    #[arg(long, value_name = "DATE")]
+    created_after: Option<NaiveDate>,
+    /// Only entries last modified before this date
+    #[arg(long, value_name = "DATE")]
+    modified_before: Option<NaiveDate>,
+    /// Only entries last modified on or after this date
+    #[arg(long, value_name = "DATE")]
+    modified_after: Option<NaiveDate>,
+    /// Only entries whose password last changed before this date
+    #[arg(long, value_name = "DATE")]
+    changed_before: Option<NaiveDate>,
+    /// Only entries whose password last changed on or after this date
+    #[arg(long, value_name = "DATE")]
+    changed_after: Option<NaiveDate>,
+    /// Only entries last used before this date
+    #[arg(long, value_name = "DATE")]
+    used_before: Option<NaiveDate>,
+    /// Only entries last used on or after this date
+    #[arg(long, value_name = "DATE")]
+    used_after: Option<NaiveDate>,
 }
 
-fn get_current_vault() -> Option<String> {
-    fs::read_to_string(current_vault_file()).ok().map(|s| s.trim().to_string())
+impl EntryFilter {
+    // Each entry time with the dates it must be before and on or after.
+    fn date_bounds(&self) -> [(EntryTime, Option<NaiveDate>, Option<NaiveDate>); 4] {
+        [
+            (EntryTime::Created, self.created_before, self.created_after),
+            (EntryTime::Modified, self.modified_before, self.modified_after),
+            (EntryTime::PasswordChanged, self.changed_before, self.changed_after),
+            (EntryTime::LastUsed, self.used_before, self.used_after),
+        ]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tag.is_none() && self.folder.is_none() && self.date_bounds().iter().all(|(_, before, after)| before.is_none() && after.is_none())
+    }
+
+    fn select<'a>(&self, entries: &'a [Entry]) -> Result<Vec<&'a Entry>> {
+        let folder = self.folder.as_deref().map(folders::normalize).transpose()?;
+        let bounds = self.date_bounds();
+        Ok(entries
+            .iter()
+            .filter(|e| self.tag.as_ref().is_none_or(|tag| e.tags.contains(tag)))
+            .filter(|e| folder.as_deref().is_none_or(|folder| e.in_folder(folder)))
+            .filter(|e| bounds.iter().all(|(which, before, after)| within(e.time(*which), *before, *after)))
+            .collect())
+    }
 }
 
-fn vault_exists(name: &str) -> bool {
-    vault_file(name).exists() && salt_file(name).exists()
+// Whether `time` is before `before` and on or after `after`. An unknown time
+// passes only when there are no bounds.
+fn within(time: Option<DateTime<Utc>>, before: Option<NaiveDate>, after: Option<NaiveDate>) -> bool {
+    if before.is_none() && after.is_none() {
+        return true;
+    }
+    let Some(day) = time.map(|t| t.date_naive()) else {
+        return false;
+    };
+    before.is_none_or(|before| day < before) && after.is_none_or(|after| day >= after)
 }
 
-fn prompt_password(msg: &str) -> String {
-    rpassword::prompt_password(msg).unwrap()
+// What `list --sort` orders entries by.
+#[derive(Clone, Copy)]
+enum SortKey {
+    Name,
+    Time(EntryTime),
 }
 
-fn prompt(msg: &str) -> String {
+impl FromStr for SortKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> rustpass::Result<SortKey> {
+        if s.eq_ignore_ascii_case("name") {
+            return Ok(SortKey::Name);
+        }
+        s.parse()
+            .map(SortKey::Time)
+            .map_err(|_| Error::InvalidInput(format!("'{}' is not a sort key; use name, created, modified, changed or used.", s)))
+    }
+}
+
+#[derive(Subcommand)]
+enum FolderCommand {
+    /// Rename or move a folder along with its subfolders and their entries
+    #[command(visible_alias = "rename")]
+    Move {
+        #[arg(long)]
+        from: String,
+        #[arg(long)]
+        to: String,
+    },
+    /// Put entries in a folder
+    Set {
+        /// ID of an entry; repeat for more
+        #[arg(long = "entry", value_name = "ID", required = true)]
+        entries: Vec<u32>,
+        /// Folder path such as work/aws/prod; omit to move the entries out of any folder
+        #[arg(long)]
+        path: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum TagCommand {
+    /// Add a tag to the chosen entries
+    Add {
+        #[arg(value_name = "TAG")]
+        name: String,
+        #[command(flatten)]
+        selection: Selection,
+    },
+    /// Remove a tag from the chosen entries
+    Remove {
+        #[arg(value_name = "TAG")]
+        name: String,
+        #[command(flatten)]
+        selection: Selection,
+    },
+}
+
+// The entries a bulk command applies to: those with the given ids, if any,
+// that also pass the filter.
+#[derive(Args)]
+struct Selection {
+    /// ID of an entry; repeat for more
+    #[arg(long = "entry", value_name = "ID")]
+    entries: Vec<u32>,
+    #[command(flatten)]
+    filter: EntryFilter,
+}
+
+impl Selection {
+    fn ids(&self, session: &Session) -> Result<Vec<u32>> {
+        if self.entries.is_empty() && self.filter.is_empty() {
+            return Err(Error::InvalidInput("Choose the entries with --entry, --tag or --folder.".to_string()).into());
+        }
+        let selected = self.filter.select(session.entries())?;
+        Ok(selected
+            .into_iter()
+            .map(|e| e.id)
+            .filter(|id| self.entries.is_empty() || self.entries.contains(id))
+            .collect())
+    }
+}
+
+#[derive(Subcommand)]
+enum KeyslotCommand {
+    /// Add a passphrase that can unlock the vault
+    Add {
+        #[arg(long)]
+        label: String,
+        /// Require this keyfile together with the new passphrase
+        #[arg(long)]
+        new_keyfile: Option<PathBuf>,
+    },
+    /// Remove a passphrase from the vault
+    Remove {
+        #[arg(long)]
+        label: String,
+    },
+    /// List the key slots of the vault
+    List,
+}
+
+#[derive(Subcommand)]
+enum BackupCommand {
+    /// List snapshots with their last modification time and entry count
+    List {
+        /// Directory to look in (defaults to the backups folder in the data directory)
+        #[arg(long)]
+        dir: Option<PathBuf>,
+        /// Only show snapshots of this vault
+        #[arg(long)]
+        vault: Option<String>,
+        /// Keyfile required to unlock the snapshots, if the vault used one
+        #[arg(long)]
+        keyfile: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RecoveryCommand {
+    /// Create a new recovery phrase, replacing any previous one
+    Generate,
+    /// Remove the recovery phrase so it can no longer unlock the vault
+    Revoke,
+    /// Replace the recovery secret with one split into shares (any THRESHOLD of SHARES recover the vault)
+    Split {
+        #[arg(long)]
+        threshold: u8,
+        #[arg(long)]
+        shares: u8,
+    },
+    /// Recover the vault from enough shares and set a new master password
+    Combine,
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(Subcommand)]
+enum SqliteCommand {
+    /// Create a new vault that keeps each entry in its own SQLite row
+    Create {
+        #[arg(long)]
+        name: String,
+    },
+    /// Turn a vault into a SQLite vault, so saves write only changed entries
+    Convert {
+        /// Vault to convert (defaults to the selected vault)
+        #[arg(long)]
+        vault: Option<String>,
+    },
+    /// Write a vault to a new SQLite file
+    Export {
+        /// Vault to export (defaults to the selected vault)
+        #[arg(long)]
+        vault: Option<String>,
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Create a vault from a SQLite file
+    Import {
+        #[arg(long)]
+        from: PathBuf,
+        #[arg(long)]
+        vault: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyfileCommand {
+    /// Write a new random keyfile
+    Generate {
+        #[arg(long)]
+        out: PathBuf,
+    },
+}
+
+// --data-dir, then RUSTPASS_HOME (both handled by clap), then the per-user
+// data directory.
+fn open_store(data_dir: Option<PathBuf>) -> Result<VaultStore> {
+    Ok(match data_dir {
+        Some(dir) => VaultStore::open(dir)?,
+        None => VaultStore::open_default()?,
+    })
+}
+
+fn prompt_password(msg: &str) -> Result<SecretString> {
+    Ok(SecretString::new(rpassword::prompt_password(msg)?))
+}
+
+fn print_recovery_phrase(phrase: &str) {
+    println!("{}", "Recovery phrase (write it down and store it offline):".yellow().bold());
+    for (i, word) in phrase.split(' ').enumerate() {
+        print!("{:>2}. {:<10}", i + 1, word);
+        if (i + 1) % 6 == 0 {
+            println!();
+        }
+    }
+    println!("{}", "Anyone with this phrase can unlock the vault. It will not be shown again.".yellow());
+}
+
+fn master_key(password: SecretString, keyfile: Option<&PathBuf>) -> Result<MasterKey> {
+    let key = MasterKey { password, keyfile: None };
+    match keyfile {
+        Some(path) => {
+            let contents = fs::read(path).with_context(|| format!("Could not read keyfile {}", path.display()))?;
+            Ok(key.with_keyfile(contents))
+        }
+        None => Ok(key),
+    }
+}
+
+fn prompt(msg: &str) -> Result<String> {
     print!("{}", msg);
-    std::io::stdout().flush().unwrap();
+    std::io::stdout().flush()?;
     let mut buf = String::new();
-    std::io::stdin().read_line(&mut buf).unwrap();
-    buf.trim().to_string()
+    std::io::stdin().read_line(&mut buf)?;
+    Ok(buf.trim().to_string())
 }
 
 fn print_usage_examples() {
     println!("{}", "Commands:".blue().bold());
-    println!("  rustpass create-vault --name <NAME>");
+    println!("  rustpass create-vault --name <NAME> [--keyfile <PATH>] [--recovery]");
     println!("  rustpass delete-vault --name <NAME>");
     println!("  rustpass edit-vault --old-name <OLD> --new-name <NEW>");
     println!("  rustpass list-vaults");
     println!("  rustpass select-vault --name <NAME>");
     println!("  rustpass init");
-    println!("  rustpass add --name <NAME> --username <USERNAME> --password <PASSWORD> [--notes <NOTES>]");
+    println!("  rustpass add --name <NAME> --username <USERNAME> --password <PASSWORD> [--notes <NOTES>] [--uri <URI>...]");
+    println!("  rustpass add note --name <NAME> --text <TEXT>");
+    println!("  rustpass add card --name <NAME> --cardholder <NAME> --number <NUMBER> --expiry <MM/YY> --cvv <CVV>");
+    println!("  rustpass add identity --name <NAME> --full-name <NAME> [--email <EMAIL>] [--address <ADDRESS>] [--passport <NUMBER>]");
+    println!("  rustpass add ssh-key --name <NAME> --private-key <FILE> [--public-key <FILE>] [--passphrase]");
+    println!("  rustpass add api --name <NAME> --secret <SECRET> [--key-id <ID>] [--endpoint <URL>]");
     println!("  rustpass edit-entry --name <NAME>");
     println!("  rustpass delete-entry --name <NAME>");
-    println!("  rustpass list");
-    println!("  rustpass get --name <NAME>");
+    println!("  rustpass list [--tag <TAG>] [--folder <PATH>] [--tree] [--sort <KEY>] [--reverse] [--changed-before <DATE>] ...");
+    println!("  rustpass get --name <NAME> [--reveal]");
+    println!("  rustpass field add --entry <ID> --label <LABEL> [--value <VALUE>] [--type <TYPE> | --hidden]");
+    println!("  rustpass field set --entry <ID> --label <LABEL> [--value <VALUE>] [--type <TYPE> | --hidden]");
+    println!("  rustpass field remove --entry <ID> --label <LABEL>");
+    println!("  rustpass uri add --entry <ID> --uri <URI> [--match domain|host|prefix|regex|never]");
+    println!("  rustpass uri remove --entry <ID> --uri <URI>");
+    println!("  rustpass find-url <URL>");
+    println!("  rustpass folder set --entry <ID>... [--path <PATH>]");
+    println!("  rustpass folder move --from <PATH> --to <PATH>");
+    println!("  rustpass tag add <TAG> [--entry <ID>...] [--tag <TAG>] [--folder <PATH>]");
+    println!("  rustpass tag remove <TAG> [--entry <ID>...] [--tag <TAG>] [--folder <PATH>]");
     println!("  rustpass save");
+    println!("  rustpass history");
+    println!("  rustpass compact [--vault <NAME>]");
+    println!("  rustpass change-password");
+    println!("  rustpass keyslot add --label <LABEL>");
+    println!("  rustpass keyslot remove --label <LABEL>");
+    println!("  rustpass keyslot list");
+    println!("  rustpass backup [--vault <NAME>] [--out <DIR>]");
+    println!("  rustpass backup list [--dir <DIR>] [--vault <NAME>]");
+    println!("  rustpass restore --from <FILE> [--vault <NAME>]");
+    println!("  rustpass verify [--vault <NAME> | --all]");
+    println!("  rustpass recover [--vault <NAME>]");
+    println!("  rustpass recovery generate");
+    println!("  rustpass recovery revoke");
+    println!("  rustpass recovery split --threshold <K> --shares <N>");
+    println!("  rustpass recovery combine");
+    println!("  rustpass keyfile generate --out <PATH>");
+    #[cfg(feature = "sqlite")]
+    {
+        println!("  rustpass sqlite create --name <NAME>");
+        println!("  rustpass sqlite convert [--vault <NAME>]");
+        println!("  rustpass sqlite export [--vault <NAME>] --out <FILE>");
+        println!("  rustpass sqlite import --from <FILE> --vault <NAME>");
+    }
+    println!("  rustpass tune-kdf [--target-ms <MS>] [--apply]");
+    println!("  rustpass set-kdf [--memory <KIB>] [--iterations <N>] [--parallelism <N>]");
+    println!();
+    println!("Commands that unlock a vault accept --keyfile <PATH> for vaults created with a keyfile.");
     println!();
     println!("{}", "Examples:".blue().bold());
     println!("  rustpass create-vault --name Personal");
     println!("  rustpass select-vault --name Personal");
     println!("  rustpass init");
-    println!("  rustpass add --name Github --username user --password 1234");
+    println!("  rustpass add --name Github --username user --password 1234 --uri github.com");
+    println!("  rustpass add card --name Visa --cardholder \"Jane Doe\" --number 4111111111111111 --expiry 08/29 --cvv 123");
     println!("  rustpass edit-entry --name Github");
     println!("  rustpass delete-entry --name Github");
     println!("  rustpass list");
     println!("  rustpass get --name Github");
+    println!("  rustpass field add --entry 1 --label \"Recovery codes\" --hidden");
+    println!("  rustpass find-url https://login.example.co.uk/x");
+    println!("  rustpass folder move --from work/aws --to archive/aws");
+    println!("  rustpass list --tree");
+    println!("  rustpass list --changed-before 2025-01-01 --sort changed");
     println!("  rustpass save");
     println!();
 }
 
-fn find_entry_by_id(vault: &model::VaultV1, id: u32) -> Option<&model::Entry> {
-    vault.entries.iter().find(|e| e.id == id)
+fn selected_vault(store: &VaultStore) -> rustpass::Result<String> {
+    store
+        .selected()
+        .ok_or_else(|| Error::NotFound("selected vault. Use 'select-vault --name <NAME>' first.".to_string()))
+}
+
+fn unlock_selected(store: &VaultStore, msg: &str, keyfile: Option<&PathBuf>) -> Result<Session> {
+    let name = selected_vault(store)?;
+    let master = master_key(prompt_password(msg)?, keyfile)?;
+    Ok(store.unlock(&name, &master)?)
 }
-fn find_entry_mut_by_id(vault: &mut model::VaultV1, id: u32) -> Option<&mut model::Entry> {
-    vault.entries.iter_mut().find(|e| e.id == id)
+
+// One line per journal change; secrets are never shown.
+fn describe_change(change: &Change) -> String {
+    match change {
+        Change::AddEntry(entry) => format!("added entry {} '{}'", entry.id, entry.name),
+        Change::RenameEntry { id, name } => format!("renamed entry {} to '{}'", id, name),
+        Change::EditField { id, field } => {
+            let field = match field {
+                EntryField::Username(_) => "username",
+                EntryField::Password(_) => "password",
+                EntryField::Notes(_) => "notes",
+                EntryField::Kind(kind) => return format!("changed the {} details of entry {}", kind.label(), id),
+                EntryField::Fields(_) => "custom fields",
+                EntryField::Uris(_) => "URIs",
+                EntryField::Tags(_) => "tags",
+                EntryField::Folder(_) => "folder",
+            };
+            format!("changed the {} of entry {}", field, id)
+        }
+        Change::DeleteEntry { id } => format!("deleted entry {}", id),
+        Change::UseEntry { id } => format!("used entry {}", id),
+    }
+}
+
+fn entry_not_found() -> anyhow::Error {
+    Error::NotFound("entry".to_string()).into()
+}
+
+fn today() -> chrono::NaiveDate {
+    chrono::Utc::now().date_naive()
+}
+
+fn card_digits(number: &str) -> SecretString {
+    number.chars().filter(|c| !matches!(c, ' ' | '-')).collect::<String>().into()
+}
+
+fn read_key_file(path: &PathBuf) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("Could not read key file {}", path.display()))
+}
+
+// Builds and validates the entry `add <kind>` describes, before anything is
+// unlocked, and returns it with the keyfile given.
+fn new_entry(kind: AddKind) -> Result<(Entry, Option<PathBuf>)> {
+    let (args, kind) = match kind {
+        AddKind::Login { entry, username, password } => (entry, EntryKind::Login(Login { username, password: password.into() })),
+        AddKind::Note { entry, text } => (entry, EntryKind::SecureNote(SecureNote { text: text.into() })),
+        AddKind::Card { entry, cardholder, number, expiry, cvv } => {
+            let card = Card {
+                cardholder,
+                number: card_digits(&number),
+                expiry: expiry.parse()?,
+                cvv: cvv.into(),
+            };
+            (entry, EntryKind::Card(card))
+        }
+        AddKind::Identity { entry, full_name, email, phone, address, passport, license, national_id } => {
+            let identity = Identity {
+                full_name,
+                email,
+                phone,
+                address,
+                passport_number: passport.map(SecretString::from),
+                license_number: license.map(SecretString::from),
+                national_id: national_id.map(SecretString::from),
+            };
+            (entry, EntryKind::Identity(identity))
+        }
+        AddKind::SshKey { entry, private_key, public_key, passphrase } => {
+            let key = SshKey {
+                private_key: read_key_file(&private_key)?.into(),
+                public_key: public_key.map(|path| read_key_file(&path).map(|k| k.trim().to_string())).transpose()?,
+                passphrase: if passphrase { Some(prompt_password("Passphrase of the private key: ")?) } else { None },
+            };
+            (entry, EntryKind::SshKey(key))
+        }
+        AddKind::Api { entry, key_id, secret, endpoint } => {
+            (entry, EntryKind::ApiCredential(ApiCredential { key_id, secret: secret.into(), endpoint }))
+        }
+    };
+    let mut entry = Entry::new(args.name, kind);
+    entry.notes = args.notes;
+    entry.uris = args.uris.into_iter().map(|uri| EntryUri::new(uri, UriMatch::BaseDomain)).collect();
+    entry.tags = args.tags.into_iter().collect();
+    entry.folder = args.folder.as_deref().map(folders::normalize).transpose()?;
+    entry.validate()?;
+    Ok((entry, args.keyfile))
+}
+
+// What `list` shows of an entry; secrets are masked.
+fn entry_summary(entry: &Entry) -> String {
+    match &entry.kind {
+        EntryKind::Login(login) => format!("{} / ********", login.username),
+        EntryKind::SecureNote(_) => "secure note".to_string(),
+        EntryKind::Card(card) => {
+            let expired = if card.is_expired(today()) { " (expired)" } else { "" };
+            format!("{} card ending {}, expires {}{}", card.brand(), card.last_four(), card.expiry, expired)
+        }
+        EntryKind::Identity(identity) => match &identity.email {
+            Some(email) => format!("identity of {} <{}>", identity.full_name, email),
+            None => format!("identity of {}", identity.full_name),
+        },
+        EntryKind::SshKey(key) => format!("{} SSH key", key.algorithm().unwrap_or("private")),
+        EntryKind::ApiCredential(api) => {
+            let mut summary = "API credential".to_string();
+            if let Some(key_id) = &api.key_id {
+                summary.push_str(&format!(" {}", key_id));
+            }
+            if let Some(endpoint) = &api.endpoint {
+                summary.push_str(&format!(" for {}", endpoint));
+            }
+            summary
+        }
+    }
+}
+
+fn time_label(which: EntryTime) -> &'static str {
+    match which {
+        EntryTime::Created => "Created",
+        EntryTime::Modified => "Modified",
+        EntryTime::PasswordChanged => "Password changed",
+        EntryTime::LastUsed => "Last used",
+    }
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S UTC").to_string()
 }
 
-fn main() -> Result<()> {
+// One entry as `list` shows it, indented by `indent`, with the time it is
+// sorted by if any; secrets are masked.
+fn print_entry(entry: &Entry, indent: &str, with_folder: bool, time: Option<EntryTime>) {
+    println!("{}[{}] {} / {}", indent, entry.id.to_string().cyan(), entry.name.cyan(), entry_summary(entry).cyan());
+    if let Some(which) = time {
+        let shown = entry.time(which).map(format_time).unwrap_or_else(|| "unknown".to_string());
+        println!("{}   {}", indent, format!("{}: {}", time_label(which), shown).cyan());
+    }
+    if let (Some(folder), true) = (&entry.folder, with_folder) {
+        println!("{}   {}", indent, format!("Folder: {}", folder).cyan());
+    }
+    if !entry.tags.is_empty() {
+        println!("{}   {}", indent, format!("Tags: {}", entry.tags.iter().cloned().collect::<Vec<_>>().join(", ")).cyan());
+    }
+    if let Some(notes) = &entry.notes {
+        println!("{}   {}", indent, format!("Notes: {}", notes).cyan());
+    }
+}
+
+// `list --tree`: the entries outside any folder, then each folder with the
+// number of entries in it and its subfolders, and its own entries beneath.
+fn print_tree(entries: &[&Entry], time: Option<EntryTime>) {
+    for entry in entries.iter().filter(|e| e.folder.is_none()) {
+        print_entry(entry, "", false, time);
+    }
+    for (segments, count) in folders::counts(entries.iter().copied()) {
+        let indent = "  ".repeat(segments.len() - 1);
+        println!("{}{} ({})", indent, format!("{}/", segments[segments.len() - 1]).blue().bold(), count);
+        let path = segments.join("/");
+        for entry in entries.iter().filter(|e| e.folder.as_deref() == Some(path.as_str())) {
+            print_entry(entry, &format!("{}  ", indent), false, time);
+        }
+    }
+}
+
+// The custom fields of an entry as `get` shows them. Hidden values are
+// masked unless `reveal`; TOTP fields show their current code.
+fn custom_fields(entry: &Entry, reveal: bool) -> Vec<(String, String)> {
+    let now = chrono::Utc::now();
+    entry
+        .fields
+        .iter()
+        .map(|field| {
+            let value = field.value.expose();
+            let shown = match field.kind {
+                FieldType::Totp => match rustpass::totp::Totp::parse(value) {
+                    Ok(totp) if reveal => format!("{} ({}s left, secret {})", totp.code_at(now), totp.remaining_at(now), value),
+                    Ok(totp) => format!("{} ({}s left)", totp.code_at(now), totp.remaining_at(now)),
+                    Err(_) => "invalid TOTP secret".to_string(),
+                },
+                FieldType::Hidden if !reveal => "********".to_string(),
+                _ => value.to_string(),
+            };
+            (field.label.clone(), shown)
+        })
+        .collect()
+}
+
+// The value of a field given on the command line, or prompted for.
+fn field_value(label: &str, value: Option<String>, kind: FieldType) -> Result<SecretString> {
+    match value {
+        Some(value) => Ok(value.into()),
+        None if kind.is_secret() => prompt_password(&format!("Value of '{}': ", label)),
+        None => Ok(prompt(&format!("Value of '{}': ", label))?.into()),
+    }
+}
+
+// Prompts for a new value of each field of an entry's kind, showing the
+// current one; an empty answer keeps it.
+fn edit_kind(kind: &mut EntryKind) -> Result<()> {
+    match kind {
+        EntryKind::Login(login) => {
+            edit_text("username", &mut login.username)?;
+            edit_secret("password", &mut login.password, "keep")?;
+        }
+        EntryKind::SecureNote(note) => edit_secret("text", &mut note.text, "keep")?,
+        EntryKind::Card(card) => {
+            edit_text("cardholder", &mut card.cardholder)?;
+            let ending = format!("•••• {}", card.last_four());
+            edit_secret("number", &mut card.number, &ending)?;
+            card.number = card_digits(card.number.expose());
+            let expiry = prompt(&format!("New expiry [{}]: ", card.expiry))?;
+            if !expiry.is_empty() {
+                card.expiry = expiry.parse()?;
+            }
+            edit_secret("CVV", &mut card.cvv, "keep")?;
+        }
+        EntryKind::Identity(identity) => {
+            edit_text("full name", &mut identity.full_name)?;
+            edit_optional("email", &mut identity.email)?;
+            edit_optional("phone", &mut identity.phone)?;
+            edit_optional("address", &mut identity.address)?;
+            edit_optional_secret("passport number", &mut identity.passport_number)?;
+            edit_optional_secret("driver's license number", &mut identity.license_number)?;
+            edit_optional_secret("national ID", &mut identity.national_id)?;
+        }
+        EntryKind::SshKey(key) => {
+            let path = prompt("New private key file [keep]: ")?;
+            if !path.is_empty() {
+                key.private_key = read_key_file(&PathBuf::from(path))?.into();
+            }
+            let path = prompt("New public key file [keep]: ")?;
+            if !path.is_empty() {
+                key.public_key = Some(read_key_file(&PathBuf::from(path))?.trim().to_string());
+            }
+            edit_optional_secret("passphrase", &mut key.passphrase)?;
+        }
+        EntryKind::ApiCredential(api) => {
+            edit_optional("key ID", &mut api.key_id)?;
+            edit_secret("secret", &mut api.secret, "keep")?;
+            edit_optional("endpoint", &mut api.endpoint)?;
+        }
+    }
+    Ok(())
+}
+
+fn edit_text(label: &str, value: &mut String) -> Result<()> {
+    let new = prompt(&format!("New {} [{}]: ", label, value))?;
+    if !new.is_empty() {
+        *value = new;
+    }
+    Ok(())
+}
+
+// Secrets are never shown while editing; `current` stands in for the value,
+// such as `keep` or the last digits of a card number.
+fn edit_secret(label: &str, value: &mut SecretString, current: &str) -> Result<()> {
+    let new = prompt_password(&format!("New {} [{}]: ", label, current))?;
+    if !new.is_empty() {
+        *value = new;
+    }
+    Ok(())
+}
+
+fn edit_optional(label: &str, value: &mut Option<String>) -> Result<()> {
+    let new = prompt(&format!("New {} [{}]: ", label, value.as_deref().unwrap_or("")))?;
+    if !new.is_empty() {
+        *value = Some(new);
+    }
+    Ok(())
+}
+
+fn edit_optional_secret(label: &str, value: &mut Option<SecretString>) -> Result<()> {
+    let new = prompt_password(&format!("New {} [{}]: ", label, if value.is_some() { "keep" } else { "" }))?;
+    if !new.is_empty() {
+        *value = Some(new);
+    }
+    Ok(())
+}
+
+/// Process exit code for a failed command, so scripts can tell failures apart.
+/// Documented in the README; keep the two in sync.
+fn exit_code(err: &anyhow::Error) -> u8 {
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<Error>() {
+            return match e {
+                Error::WrongPassword => 3,
+                Error::KeyfileRequired => 4,
+                Error::NotFound(_) => 5,
+                Error::Truncated => 6,
+                Error::Corrupt(_) => 7,
+                Error::UnsupportedVersion(_) => 8,
+                Error::Io(_) => 9,
+                Error::Serialization(_) => 10,
+                Error::InvalidInput(_) => 11,
+                Error::Crypto(_) => 12,
+                Error::Locked(_) => 13,
+                Error::Conflict => 14,
+                Error::Database(_) => 15,
+                Error::UnsupportedSchema(_) => 16,
+            };
+        }
+        if cause.is::<std::io::Error>() {
+            return 9;
+        }
+    }
+    1
+}
+
+fn main() -> ExitCode {
     let cli = Cli::parse();
 
     if std::env::args().len() == 1 {
         print_usage_examples();
-        return Ok(());
+        return ExitCode::SUCCESS;
+    }
+
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{} {:#}", "Error:".red(), e);
+            ExitCode::from(exit_code(&e))
+        }
     }
+}
 
+fn run(cli: Cli) -> Result<()> {
+    let store = open_store(cli.data_dir)?;
     match cli.command {
-        Commands::CreateVault { name } => {
-            if vault_exists(&name) {
-                println!("{}", "Vault already exists.".yellow());
-                return Ok(());
+        Commands::CreateVault { name, recovery, keyfile } => {
+            if store.exists(&name)? {
+                return Err(Error::InvalidInput(format!("Vault '{}' already exists.", name)).into());
             }
-            let master = prompt_password("Set master password for this vault: ");
-            let salt = {
-                let mut salt = [0u8; 16];
-                rand::thread_rng().fill_bytes(&mut salt);
-                salt
-            };
-            let key = crypto::derive_key(&master, &salt);
-            let vault = model::VaultV1 {
-                created_at: Utc::now(),
-                last_modified: Utc::now(),
-                entries: Vec::new(),
-            };
-            let mut buf = Vec::new();
-            ciborium::ser::into_writer(&vault, &mut buf)?;
-            let (ciphertext, nonce) = crypto::encrypt(&key, &buf);
-
-            let mut vault_data = Vec::new();
-            vault_data.extend_from_slice(&nonce);
-            vault_data.extend_from_slice(&ciphertext);
-
-            fs::write(vault_file(&name), &vault_data)?;
-            fs::write(salt_file(&name), &salt)?;
+            let master = master_key(prompt_password("Set master password for this vault: ")?, keyfile.as_ref())?;
+            store.create(&name, &master)?;
             println!("{}", "Vault created.".green());
+            if recovery {
+                let phrase = store.unlock(&name, &master)?.set_recovery()?;
+                print_recovery_phrase(phrase.expose());
+            }
         }
-        Commands::DeleteVault { name } => {
-            if !vault_exists(&name) {
-                println!("{}", "Vault not found.".yellow());
-                return Ok(());
+        Commands::DeleteVault { name, keyfile } => {
+            if !store.exists(&name)? {
+                return Err(Error::NotFound(format!("vault '{}'", name)).into());
             }
             println!("{}", format!("You are about to delete vault '{}'. This action is irreversible and all data will be lost.", name).red().bold());
-            let confirm = prompt("Are you sure you want to delete this vault? (y/n): ");
+            let confirm = prompt("Are you sure you want to delete this vault? (y/n): ")?;
             if !confirm.trim().eq_ignore_ascii_case("y") {
                 println!("{}", "Vault deletion cancelled.".yellow());
                 return Ok(());
             }
-            let master = prompt_password("Enter master password for this vault: ");
-            // Try to decrypt to verify password
-            let salt = match fs::read(salt_file(&name)) {
-                Ok(s) => s,
-                Err(_) => {
-                    println!("{}", "Vault not found or corrupted.".red());
-                    return Ok(());
-                }
-            };
-            let key = crypto::derive_key(&master, &salt);
-            let vault_data = match fs::read(vault_file(&name)) {
-                Ok(v) => v,
-                Err(_) => {
-                    println!("{}", "Vault not found or corrupted.".red());
-                    return Ok(());
-                }
-            };
-            let nonce = &vault_data[..24];
-            let ciphertext = &vault_data[24..];
-            if crypto::decrypt(&key, ciphertext, nonce.try_into().unwrap()).is_err() {
-                println!("{}", "Incorrect master password. Vault not deleted.".red());
-                return Ok(());
-            }
-            let vf = vault_file(&name);
-            let sf = salt_file(&name);
-            let mut deleted = false;
-            if vf.exists() {
-                fs::remove_file(vf)?;
-                deleted = true;
-            }
-            if sf.exists() {
-                fs::remove_file(sf)?;
-                deleted = true;
-            }
-            if deleted {
-                println!("{}", "Vault deleted.".green());
-                if get_current_vault().as_deref() == Some(&name) {
-                    let _ = fs::remove_file(current_vault_file());
-                }
-            } else {
-                println!("{}", "Vault not found.".yellow());
-            }
+            let master = master_key(prompt_password("Enter master password for this vault: ")?, keyfile.as_ref())?;
+            store.delete(&name, &master)?;
+            println!("{}", "Vault deleted.".green());
         }
         Commands::EditVault { old_name, new_name } => {
-            if !vault_exists(&old_name) {
-                println!("{}", "Vault not found.".yellow());
-                return Ok(());
-            }
-            if vault_exists(&new_name) {
-                println!("{}", "A vault with the new name already exists.".yellow());
-                return Ok(());
-            }
-            fs::rename(vault_file(&old_name), vault_file(&new_name))?;
-            fs::rename(salt_file(&old_name), salt_file(&new_name))?;
-            if get_current_vault().as_deref() == Some(&old_name) {
-                set_current_vault(&new_name);
-            }
+            store.rename(&old_name, &new_name)?;
             println!("{}", "Vault renamed.".green());
         }
         Commands::ListVaults => {
-            let dir = vaults_dir();
-            let mut found = false;
+            let names = store.list()?;
             println!("{}", "Available vaults:".blue().bold());
-            for entry in fs::read_dir(dir)? {
-                let entry = entry?;
-                let fname = entry.file_name().to_string_lossy().to_string();
-                if fname.starts_with("vault_") && fname.ends_with(".cbor") {
-                    let name = fname.trim_start_matches("vault_").trim_end_matches(".cbor");
-                    println!("{}", name.cyan());
-                    found = true;
-                }
+            for name in &names {
+                println!("{}", name.cyan());
             }
-            if !found {
+            if names.is_empty() {
                 println!("{}", "No vaults found.".yellow());
             }
         }
         Commands::SelectVault { name } => {
-            if !vault_exists(&name) {
-                println!("{}", "Vault not found.".yellow());
-                return Ok(());
-            }
-            set_current_vault(&name);
+            store.select(&name)?;
             println!("{}", format!("Vault '{}' selected.", name).green());
         }
-        Commands::Init => {
-            let vault_name = match get_current_vault() {
-                Some(name) => name,
-                None => {
-                    println!("{}", "No vault selected. Use 'select-vault --name <NAME>' first.".red());
-                    return Ok(());
-                }
-            };
-            let master = prompt_password(&format!("Master password for vault '{}': ", vault_name));
-            let salt = match fs::read(salt_file(&vault_name)) {
-                Ok(s) => s,
-                Err(_) => {
-                    println!("{}", "Vault not found or corrupted.".red());
-                    return Ok(());
-                }
-            };
-            let key = crypto::derive_key(&master, &salt);
-            let vault_data = match fs::read(vault_file(&vault_name)) {
-                Ok(v) => v,
-                Err(_) => {
-                    println!("{}", "Vault not found or corrupted.".red());
-                    return Ok(());
-                }
-            };
-            let nonce = &vault_data[..24];
-            let ciphertext = &vault_data[24..];
-            let plaintext = match crypto::decrypt(&key, ciphertext, nonce.try_into().unwrap()) {
-                Ok(p) => p,
-                Err(_) => {
-                    println!("{}", "Incorrect master password or corrupted vault.".red());
-                    return Ok(());
-                }
-            };
-            let _vault: model::VaultV1 = ciborium::de::from_reader(plaintext.as_slice())?;
+        Commands::Init { keyfile } => {
+            let vault_name = selected_vault(&store)?;
+            let master = master_key(prompt_password(&format!("Master password for vault '{}': ", vault_name))?, keyfile.as_ref())?;
+            store.unlock(&vault_name, &master)?;
             println!("{}", format!("Vault '{}' unlocked.", vault_name).green());
         }
-        Commands::Add { name, username, password, notes } => {
-            let vault_name = match get_current_vault() {
-                Some(name) => name,
-                None => {
-                    println!("{}", "No vault selected. Use 'select-vault --name <NAME>' first.".red());
-                    return Ok(());
-                }
-            };
-            println!("{}", format!(
-                "Adding entry '{}' for user '{}' to vault '{}'.",
-                name, username, vault_name
-            ).blue().bold());
-            let master = prompt_password("Master password: ");
-            let mut v = vault::load_named(&vault_name, &master)
-                .map_err(|e| {
-                    println!("{} {}", "Error:".red(), e);
-                    e
-                })?;
-            let next_id = v.entries.iter().map(|e| e.id).max().unwrap_or(0) + 1;
-            let entry = Entry {
-                id: next_id,
-                name,
-                username,
-                password,
-                notes,
+        Commands::Add { kind, name, username, password, notes, uris, tags, folder, keyfile } => {
+            // Without a kind, clap has required all three login flags.
+            let kind = kind.map(|kind| *kind).unwrap_or_else(|| AddKind::Login {
+                entry: NewEntryArgs { name: name.unwrap_or_default(), notes, uris, tags, folder, keyfile },
+                username: username.unwrap_or_default(),
+                password: password.unwrap_or_default(),
+            });
+            let (entry, keyfile) = new_entry(kind)?;
+            let vault_name = selected_vault(&store)?;
+            let adding = match entry.as_login() {
+                Some(login) => format!("Adding entry '{}' for user '{}' to vault '{}'.", entry.name, login.username, vault_name),
+                None => format!("Adding {} entry '{}' to vault '{}'.", entry.kind.label(), entry.name, vault_name),
             };
-            v.entries.push(entry);
-            v.last_modified = Utc::now();
-            vault::save_named(&vault_name, &master, &v)?;
+            println!("{}", adding.blue().bold());
+            if let EntryKind::Card(card) = &entry.kind {
+                if card.is_expired(today()) {
+                    println!("{}", format!("Note: this card expired in {}.", card.expiry).yellow());
+                }
+            }
+            let mut session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
+            session.add_entry(entry);
+            session.commit()?;
             println!("{}", "Entry added.".green());
         }
-        Commands::List => {
-            let vault_name = match get_current_vault() {
-                Some(name) => name,
-                None => {
-                    println!("{}", "No vault selected. Use 'select-vault --name <NAME>' first.".red());
-                    return Ok(());
+        Commands::List { filter, tree, sort, reverse, keyfile } => {
+            let session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
+            let mut entries = filter.select(session.entries())?;
+            let time = match sort {
+                Some(SortKey::Name) => {
+                    entries.sort_by_key(|e| e.name.to_lowercase());
+                    None
+                }
+                Some(SortKey::Time(which)) => {
+                    entries.sort_by_key(|e| e.time(which));
+                    Some(which)
                 }
+                None => None,
             };
-            let master = prompt_password("Master password: ");
-            let v = vault::load_named(&vault_name, &master)
-                .map_err(|e| {
-                    println!("{} {}", "Error:".red(), e);
-                    e
-                })?;
+            if reverse {
+                entries.reverse();
+            }
             println!("{}", "Entries:".blue().bold());
-            for entry in &v.entries {
-                println!(
-                    "[{}] {} / {} / {}",
-                    entry.id.to_string().cyan(),
-                    entry.name.cyan(),
-                    entry.username.cyan(),
-                    "********".cyan()
-                );
-                if let Some(notes) = &entry.notes {
-                    println!("   {}", format!("Notes: {}", notes).cyan());
+            if tree {
+                print_tree(&entries, time);
+            } else {
+                for entry in entries {
+                    print_entry(entry, "", true, time);
                 }
             }
         }
-        Commands::Get { id, name } => {
-            let vault_name = match get_current_vault() {
-                Some(name) => name,
-                None => {
-                    println!("{}", "No vault selected. Use 'select-vault --name <NAME>' first.".red());
-                    return Ok(());
-                }
-            };
-            let master = prompt_password("Master password: ");
-            let v = vault::load_named(&vault_name, &master)
-                .map_err(|e| {
-                    println!("{} {}", "Error:".red(), e);
-                    e
-                })?;
+        Commands::Get { id, name, reveal, keyfile } => {
+            let mut session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
             let entry = if let Some(id) = id {
-                find_entry_by_id(&v, id)
+                session.entry(id)
             } else if let Some(name) = name {
-                vault::find_entry(&v, &name)
+                session.entries_named(&name).into_iter().next()
             } else {
                 None
             };
-            if let Some(entry) = entry {
-                println!(
-                    "[{}] {} / {} / {}",
-                    entry.id.to_string().green().bold(),
-                    entry.name.green().bold(),
-                    entry.username.green(),
-                    entry.password.green()
-                );
-                if let Some(notes) = &entry.notes {
-                    println!("   {}", format!("Notes: {}", notes).green());
+            let entry = entry.ok_or_else(entry_not_found)?;
+            println!("[{}] {} ({})", entry.id.to_string().green().bold(), entry.name.green().bold(), entry.kind.label());
+            for (label, value) in entry.kind.fields(true, today()) {
+                println!("   {}", format!("{}: {}", label, value).green());
+            }
+            for (label, value) in custom_fields(entry, reveal) {
+                println!("   {}", format!("{}: {}", label, value).green());
+            }
+            for uri in &entry.uris {
+                println!("   {}", format!("URI: {} ({})", uri.uri, uri.mode).green());
+            }
+            if let Some(folder) = &entry.folder {
+                println!("   {}", format!("Folder: {}", folder).green());
+            }
+            if !entry.tags.is_empty() {
+                println!("   {}", format!("Tags: {}", entry.tags.iter().cloned().collect::<Vec<_>>().join(", ")).green());
+            }
+            if let Some(notes) = &entry.notes {
+                println!("   {}", format!("Notes: {}", notes).green());
+            }
+            for which in [EntryTime::Created, EntryTime::Modified, EntryTime::PasswordChanged, EntryTime::LastUsed] {
+                if let Some(time) = entry.time(which) {
+                    println!("   {}", format!("{}: {}", time_label(which), format_time(time)).green());
                 }
-            } else {
-                println!("{}", "Entry not found.".yellow());
             }
+            let id = entry.id;
+            session.mark_used(id);
+            session.commit()?;
         }
-        Commands::EditEntry { id, name } => {
-            let vault_name = match get_current_vault() {
-                Some(name) => name,
-                None => {
-                    println!("{}", "No vault selected. Use 'select-vault --name <NAME>' first.".red());
-                    return Ok(());
+        Commands::Field { action, keyfile } => {
+            let mut session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
+            let message = match action {
+                FieldCommand::Add(args) => {
+                    let entry = session.entry_mut(args.entry).ok_or_else(entry_not_found)?;
+                    if entry.field(&args.label).is_some() {
+                        return Err(Error::InvalidInput(format!("Entry {} already has a field '{}'; use 'field set' to change it.", entry.id, args.label)).into());
+                    }
+                    let kind = if args.hidden { FieldType::Hidden } else { args.kind.unwrap_or(FieldType::Text) };
+                    let value = field_value(&args.label, args.value, kind)?;
+                    entry.fields.push(CustomField { label: args.label.clone(), value, kind });
+                    entry.validate()?;
+                    format!("Field '{}' added.", args.label)
+                }
+                FieldCommand::Set(args) => {
+                    let entry = session.entry_mut(args.entry).ok_or_else(entry_not_found)?;
+                    let field = entry
+                        .field_mut(&args.label)
+                        .ok_or_else(|| Error::NotFound(format!("field '{}'", args.label)))?;
+                    let retyped = args.kind.is_some() || args.hidden;
+                    if args.hidden {
+                        field.kind = FieldType::Hidden;
+                    } else if let Some(kind) = args.kind {
+                        field.kind = kind;
+                    }
+                    // Changing only the type keeps the value.
+                    if args.value.is_some() || !retyped {
+                        field.value = field_value(&args.label, args.value, field.kind)?;
+                    }
+                    entry.validate()?;
+                    format!("Field '{}' updated.", args.label)
+                }
+                FieldCommand::Remove { entry, label } => {
+                    let entry = session.entry_mut(entry).ok_or_else(entry_not_found)?;
+                    let before = entry.fields.len();
+                    entry.fields.retain(|f| f.label != label);
+                    if entry.fields.len() == before {
+                        return Err(Error::NotFound(format!("field '{}'", label)).into());
+                    }
+                    format!("Field '{}' removed.", label)
                 }
             };
-            println!("{}", format!("Editing entry in vault '{}'.", vault_name).blue().bold());
-            let master = prompt_password("Master password: ");
-            let mut v = vault::load_named(&vault_name, &master)
-                .map_err(|e| {
-                    println!("{} {}", "Error:".red(), e);
-                    e
-                })?;
-            let entry = if let Some(id) = id {
-                find_entry_mut_by_id(&mut v, id)
-            } else if let Some(name) = name {
-                vault::find_entry_mut(&mut v, &name)
-            } else {
-                None
+            session.commit()?;
+            println!("{}", message.green());
+        }
+        Commands::Uri { action, keyfile } => {
+            let mut session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
+            let message = match action {
+                UriCommand::Add { entry, uri, mode } => {
+                    let entry = session.entry_mut(entry).ok_or_else(entry_not_found)?;
+                    if entry.uris.iter().any(|u| u.uri == uri) {
+                        return Err(Error::InvalidInput(format!("Entry {} already has URI '{}'.", entry.id, uri)).into());
+                    }
+                    entry.uris.push(EntryUri::new(uri.clone(), mode));
+                    entry.validate()?;
+                    format!("URI '{}' added ({} match).", uri, mode)
+                }
+                UriCommand::Remove { entry, uri } => {
+                    let entry = session.entry_mut(entry).ok_or_else(entry_not_found)?;
+                    let before = entry.uris.len();
+                    entry.uris.retain(|u| u.uri != uri);
+                    if entry.uris.len() == before {
+                        return Err(Error::NotFound(format!("URI '{}'", uri)).into());
+                    }
+                    format!("URI '{}' removed.", uri)
+                }
             };
-            if let Some(entry) = entry {
-                println!("Current values:");
-                println!("ID: {}", entry.id);
-                println!("Name: {}", entry.name);
-                println!("Username: {}", entry.username);
-                println!("Password: {}", entry.password);
-                println!("Notes: {}", entry.notes.as_deref().unwrap_or(""));
-                let new_name = prompt(&format!("New name [{}]: ", entry.name));
-                let new_username = prompt(&format!("New username [{}]: ", entry.username));
-                let new_password = prompt_password(&format!("New password [{}]: ", entry.password));
-                let new_notes = prompt(&format!("New notes [{}]: ", entry.notes.as_deref().unwrap_or("")));
-                entry.name = if new_name.is_empty() { entry.name.clone() } else { new_name };
-                entry.username = if new_username.is_empty() { entry.username.clone() } else { new_username };
-                entry.password = if new_password.is_empty() { entry.password.clone() } else { new_password };
-                entry.notes = if new_notes.is_empty() { entry.notes.clone() } else { Some(new_notes) };
-                v.last_modified = Utc::now();
-                vault::save_named(&vault_name, &master, &v)?;
-                println!("{}", "Entry updated.".green());
-            } else {
-                println!("{}", "Entry not found.".yellow());
-            }
+            session.commit()?;
+            println!("{}", message.green());
         }
-        Commands::DeleteEntry { id, name } => {
-            let vault_name = match get_current_vault() {
-                Some(name) => name,
-                None => {
-                    println!("{}", "No vault selected. Use 'select-vault --name <NAME>' first.".red());
-                    return Ok(());
+        Commands::Folder { action, keyfile } => {
+            let mut session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
+            let message = match action {
+                FolderCommand::Move { from, to } => {
+                    let moved = session.move_folder(&from, &to)?;
+                    format!("Moved {} entries from '{}' to '{}'.", moved, from, to)
+                }
+                FolderCommand::Set { entries, path } => {
+                    for &id in &entries {
+                        session.entry(id).ok_or_else(entry_not_found)?;
+                    }
+                    let moved = session.set_folder(&entries, path.as_deref())?;
+                    match path.as_deref().map(folders::normalize).transpose()? {
+                        Some(folder) => format!("Moved {} entries to '{}'.", moved, folder),
+                        None => format!("Moved {} entries out of their folders.", moved),
+                    }
+                }
+            };
+            session.commit()?;
+            println!("{}", message.green());
+        }
+        Commands::Tag { action, keyfile } => {
+            let mut session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
+            let message = match action {
+                TagCommand::Add { name, selection } => {
+                    let ids = selection.ids(&session)?;
+                    let tagged = session.tag_entries(&ids, &name)?;
+                    format!("Tagged {} entries with '{}'.", tagged, name)
                 }
+                TagCommand::Remove { name, selection } => {
+                    let ids = selection.ids(&session)?;
+                    let untagged = session.untag_entries(&ids, &name);
+                    format!("Removed tag '{}' from {} entries.", name, untagged)
+                }
+            };
+            session.commit()?;
+            println!("{}", message.green());
+        }
+        Commands::FindUrl { url, keyfile } => {
+            let session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
+            let found = session.find_url(&url)?;
+            if found.is_empty() {
+                println!("{}", format!("No entries match {}.", url).yellow());
+            }
+            for (entry, quality) in found {
+                println!("[{}] {} / {} ({})", entry.id.to_string().cyan(), entry.name.cyan(), entry_summary(entry).cyan(), quality.label());
+            }
+        }
+        Commands::EditEntry { id, name, keyfile } => {
+            let vault_name = selected_vault(&store)?;
+            println!("{}", format!("Editing entry in vault '{}'.", vault_name).blue().bold());
+            // The session does not hold the vault lock while prompting for the
+            // new values; commit refuses to overwrite changes made meanwhile.
+            let mut session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
+            let id = match (id, name) {
+                (Some(id), _) => Some(id),
+                (None, Some(name)) => session.entries_named(&name).first().map(|e| e.id),
+                (None, None) => None,
             };
+            let entry = id.and_then(|id| session.entry_mut(id)).ok_or_else(entry_not_found)?;
+            println!("Current values:");
+            println!("ID: {}", entry.id);
+            println!("Kind: {}", entry.kind.label());
+            println!("Name: {}", entry.name);
+            for (label, value) in entry.kind.fields(false, today()) {
+                println!("{}: {}", label, value);
+            }
+            for (label, value) in custom_fields(entry, false) {
+                println!("{}: {}", label, value);
+            }
+            for uri in &entry.uris {
+                println!("URI: {} ({})", uri.uri, uri.mode);
+            }
+            println!("Folder: {}", entry.folder.as_deref().unwrap_or(""));
+            println!("Notes: {}", entry.notes.as_deref().unwrap_or(""));
+            edit_text("name", &mut entry.name)?;
+            edit_kind(&mut entry.kind)?;
+            edit_optional("folder", &mut entry.folder)?;
+            entry.folder = entry.folder.as_deref().map(folders::normalize).transpose()?;
+            edit_optional("notes", &mut entry.notes)?;
+            entry.validate()?;
+            session.commit()?;
+            println!("{}", "Entry updated.".green());
+        }
+        Commands::DeleteEntry { id, name, keyfile } => {
+            let vault_name = selected_vault(&store)?;
             println!("{}", format!("Deleting entry from vault '{}'.", vault_name).blue().bold());
-            let master = prompt_password("Master password: ");
-            let mut v = vault::load_named(&vault_name, &master)
-                .map_err(|e| {
-                    println!("{} {}", "Error:".red(), e);
-                    e
-                })?;
-            if let Some(id_val) = id {
-                let before = v.entries.len();
-                v.entries.retain(|e| e.id != id_val);
-                if v.entries.len() < before {
-                    v.last_modified = Utc::now();
-                    vault::save_named(&vault_name, &master, &v)?;
-                    println!("{}", "Entry deleted.".green());
-                } else {
-                    println!("{}", "Entry not found.".yellow());
-                }
-            } else if let Some(ref name_val) = name {
-                let matches: Vec<&Entry> = v.entries.iter().filter(|e| e.name.eq_ignore_ascii_case(name_val)).collect();
-                if matches.is_empty() {
-                    println!("{}", "Entry not found.".yellow());
-                } else if matches.len() > 1 {
-                    println!("{}", format!("Warning: There are {} entries with the name '{}'.", matches.len(), name_val).yellow());
+            if id.is_none() && name.is_none() {
+                println!("{}", "Please provide either --id or --name to delete an entry.".yellow());
+                return Ok(());
+            }
+            let mut session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
+            if let Some(id) = id {
+                session.remove_entry(id).ok_or_else(entry_not_found)?;
+                session.commit()?;
+                println!("{}", "Entry deleted.".green());
+            } else if let Some(name) = name {
+                let matches = session.entries_named(&name).len();
+                if matches == 0 {
+                    return Err(entry_not_found());
+                }
+                if matches > 1 {
+                    println!("{}", format!("Warning: There are {} entries with the name '{}'.", matches, name).yellow());
                     println!("Do you want to delete ALL of them? (y/n)");
                     println!("Tip: To delete a specific entry, use --id <ID> instead of --name.");
-                    let confirm = prompt("");
-                    if confirm.trim().eq_ignore_ascii_case("y") {
-                        v.entries.retain(|e| !e.name.eq_ignore_ascii_case(name_val));
-                        v.last_modified = Utc::now();
-                        vault::save_named(&vault_name, &master, &v)?;
-                        println!("{}", "All matching entries deleted.".green());
-                    } else {
+                    let confirm = prompt("")?;
+                    if !confirm.trim().eq_ignore_ascii_case("y") {
                         println!("{}", "No entries deleted.".yellow());
+                        return Ok(());
                     }
+                }
+                session.remove_entries_named(&name);
+                session.commit()?;
+                if matches > 1 {
+                    println!("{}", "All matching entries deleted.".green());
                 } else {
-                    v.entries.retain(|e| !e.name.eq_ignore_ascii_case(name_val));
-                    v.last_modified = Utc::now();
-                    vault::save_named(&vault_name, &master, &v)?;
                     println!("{}", "Entry deleted.".green());
                 }
+            }
+        }
+        Commands::Save { keyfile } => {
+            unlock_selected(&store, "Master password: ", keyfile.as_ref())?.commit()?;
+            println!("{}", "Vault saved.".green());
+        }
+        Commands::Compact { vault: name, keyfile } => {
+            let vault_name = match name {
+                Some(name) => name,
+                None => selected_vault(&store)?,
+            };
+            let master = master_key(prompt_password(&format!("Master password for vault '{}': ", vault_name))?, keyfile.as_ref())?;
+            let records = store.compact(&vault_name, &master)?;
+            if records == 0 {
+                println!("{}", format!("Vault '{}' has no journal records to fold in.", vault_name).yellow());
             } else {
-                println!("{}", "Please provide either --id or --name to delete an entry.".yellow());
+                println!("{}", format!("Folded {} journal records into vault '{}'.", records, vault_name).green());
+            }
+        }
+        Commands::History { keyfile } => {
+            let session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
+            println!("{}", format!("Changes to vault '{}' since it was last compacted:", session.name()).blue().bold());
+            for record in session.history() {
+                for change in &record.changes {
+                    println!("{} {}", record.at.format("%Y-%m-%d %H:%M:%S UTC").to_string().cyan(), describe_change(change));
+                }
+            }
+            if session.history().is_empty() {
+                println!("{}", "No changes recorded.".yellow());
+            }
+        }
+        Commands::ChangePassword { keyfile } => {
+            let vault_name = selected_vault(&store)?;
+            let mut session = unlock_selected(&store, &format!("Current master password for vault '{}': ", vault_name), keyfile.as_ref())?;
+            let new_master = prompt_password("New master password: ")?;
+            if new_master.is_empty() {
+                return Err(Error::InvalidInput("The new master password cannot be empty.".to_string()).into());
+            }
+            let confirm = prompt_password("Confirm new master password: ")?;
+            if new_master != confirm {
+                return Err(Error::InvalidInput("Passwords do not match. Master password not changed.".to_string()).into());
+            }
+            session.change_password(&master_key(new_master, keyfile.as_ref())?)?;
+            println!("{}", "Master password changed.".green());
+        }
+        Commands::Keyslot { action, keyfile } => {
+            let vault_name = selected_vault(&store)?;
+            match action {
+                KeyslotCommand::Add { label, new_keyfile } => {
+                    let mut session = unlock_selected(&store, "Existing passphrase for this vault: ", keyfile.as_ref())?;
+                    let new_secret = prompt_password(&format!("New passphrase for key slot '{}': ", label))?;
+                    if new_secret.is_empty() {
+                        return Err(Error::InvalidInput("The passphrase cannot be empty.".to_string()).into());
+                    }
+                    let confirm = prompt_password("Confirm new passphrase: ")?;
+                    if new_secret != confirm {
+                        return Err(Error::InvalidInput("Passphrases do not match. Key slot not added.".to_string()).into());
+                    }
+                    session.add_key_slot(&label, &master_key(new_secret, new_keyfile.as_ref())?)?;
+                    println!("{}", format!("Key slot '{}' added.", label).green());
+                }
+                KeyslotCommand::Remove { label } => {
+                    let mut session = unlock_selected(&store, "Passphrase for this vault: ", keyfile.as_ref())?;
+                    session.remove_key_slot(&label)?;
+                    println!("{}", format!("Key slot '{}' removed.", label).green());
+                }
+                KeyslotCommand::List => {
+                    let slots = store.key_slots(&vault_name)?;
+                    println!("{}", format!("Key slots of vault '{}':", vault_name).blue().bold());
+                    for (i, slot) in slots.iter().enumerate() {
+                        let kind = match (slot.kind, slot.keyfile) {
+                            (rustpass::format::SlotKind::Recovery, _) => "recovery phrase",
+                            (_, true) => "passphrase + keyfile",
+                            (_, false) => "passphrase",
+                        };
+                        println!("[{}] {} - {} ({})", i.to_string().cyan(), slot.label.cyan(), kind, slot.kdf);
+                    }
+                }
             }
         }
-        Commands::Save => {
-            let vault_name = match get_current_vault() {
+        Commands::Backup { action: None, vault: name, out } => {
+            let vault_name = match name {
                 Some(name) => name,
-                None => {
-                    println!("{}", "No vault selected. Use 'select-vault --name <NAME>' first.".red());
-                    return Ok(());
+                None => selected_vault(&store)?,
+            };
+            let out = match out {
+                Some(dir) => dir,
+                None => store.backups_dir()?,
+            };
+            let path = store.snapshot(&vault_name, &out)?;
+            println!("{}", format!("Snapshot of vault '{}' written to {}.", vault_name, path.display()).green());
+        }
+        Commands::Backup { action: Some(BackupCommand::List { dir, vault: only, keyfile }), .. } => {
+            let dir = match dir {
+                Some(dir) => dir,
+                None => store.backups_dir()?,
+            };
+            let mut masters: HashMap<String, MasterKey> = HashMap::new();
+            let mut found = false;
+            println!("{}", format!("Snapshots in {}:", dir.display()).blue().bold());
+            for path in backup::list_snapshots(&dir)? {
+                let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                let snapshot = match backup::read_snapshot(&path) {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => {
+                        println!("{} {}", file_name.cyan(), format!("(unreadable: {})", e).red());
+                        continue;
+                    }
+                };
+                if only.as_ref().is_some_and(|name| *name != snapshot.vault) {
+                    continue;
+                }
+                found = true;
+                if !masters.contains_key(&snapshot.vault) {
+                    let password = prompt_password(&format!("Master password for vault '{}' snapshots: ", snapshot.vault))?;
+                    masters.insert(snapshot.vault.clone(), master_key(password, keyfile.as_ref())?);
                 }
+                let status = match snapshot.decrypt(&masters[&snapshot.vault]) {
+                    Ok(v) => format!(
+                        "last modified {}, {} entries",
+                        v.last_modified.format("%Y-%m-%d %H:%M:%S UTC"),
+                        v.entries.len()
+                    ),
+                    Err(e) => format!("could not unlock: {}", e).red().to_string(),
+                };
+                println!(
+                    "{} - vault '{}', taken {} - {}",
+                    file_name.cyan(),
+                    snapshot.vault,
+                    snapshot.created_at.format("%Y-%m-%d %H:%M:%S UTC"),
+                    status
+                );
+            }
+            if !found {
+                println!("{}", "No snapshots found.".yellow());
+            }
+        }
+        Commands::Verify { vault: name, all, keyfile } => {
+            let names = match (all, name) {
+                (true, _) => store.list()?,
+                (false, Some(name)) => vec![name],
+                (false, None) => vec![selected_vault(&store)?],
             };
-            let master = prompt_password("Master password: ");
-            let v = vault::load_named(&vault_name, &master)
-                .map_err(|e| {
-                    println!("{} {}", "Error:".red(), e);
-                    e
-                })?;
-            vault::save_named(&vault_name, &master, &v)?;
-            println!("{}", "Vault saved.".green());
+            if names.is_empty() {
+                println!("{}", "No vaults found.".yellow());
+            }
+            let mut damaged = 0;
+            for name in &names {
+                let master = master_key(prompt_password(&format!("Master password for vault '{}': ", name))?, keyfile.as_ref())?;
+                let report = store.verify(name, Some(&master))?;
+                if report.is_ok() {
+                    println!("{}", format!("Vault '{}': OK", name).green());
+                    continue;
+                }
+                println!("{}", format!("Vault '{}': {} problem(s)", name, report.problems.len()).red().bold());
+                for problem in &report.problems {
+                    println!("  {}", problem);
+                }
+                match &report.good_backup {
+                    Some(backup) => {
+                        let records = store.journal_records(name)?;
+                        if records > 0 {
+                            println!(
+                                "{}",
+                                format!("Restoring drops the {} journal record(s) saved since the vault was last compacted.", records).yellow()
+                            );
+                        }
+                        let confirm = prompt(&format!("Restore vault '{}' from {}? The damaged file is kept as a backup. (y/n): ", name, backup))?;
+                        if confirm.eq_ignore_ascii_case("y") {
+                            store.restore_backup(name, backup, true)?;
+                            println!("{}", format!("Vault '{}' restored from {}.", name, backup).green());
+                            continue;
+                        }
+                    }
+                    None => println!("{}", "No backup of this vault passes the checks either.".yellow()),
+                }
+                damaged += 1;
+            }
+            if damaged > 0 {
+                return Err(Error::Corrupt(format!("{} of {} vaults checked have problems", damaged, names.len())).into());
+            }
+        }
+        Commands::Restore { from, vault: name, keyfile } => {
+            let snapshot = backup::read_snapshot(&from)?;
+            let vault_name = name.unwrap_or_else(|| snapshot.vault.clone());
+            let master = master_key(prompt_password(&format!("Master password of the snapshot of vault '{}': ", snapshot.vault))?, keyfile.as_ref())?;
+            let replaced = store.exists(&vault_name)?;
+            let current = match replaced {
+                true => {
+                    let password = prompt_password(&format!("Current master password of vault '{}' (Enter if the same): ", vault_name))?;
+                    match password.expose().is_empty() {
+                        true => master.clone(),
+                        false => master_key(password, keyfile.as_ref())?,
+                    }
+                }
+                false => master.clone(),
+            };
+            let v = store.restore(&snapshot, &vault_name, &master, &current)?;
+            println!(
+                "{}",
+                format!(
+                    "Vault '{}' restored from {} ({} entries, last modified {}).",
+                    vault_name,
+                    from.display(),
+                    v.entries.len(),
+                    v.last_modified.format("%Y-%m-%d %H:%M:%S UTC")
+                )
+                .green()
+            );
+            if replaced && !store.backups(&vault_name)?.is_empty() {
+                println!("The replaced vault was kept as its newest .bak.1 generation.");
+            }
+        }
+        Commands::Recover { vault: name, keyfile } => {
+            let vault_name = match name {
+                Some(name) => name,
+                None => selected_vault(&store)?,
+            };
+            if !store.exists(&vault_name)? {
+                return Err(Error::NotFound(format!("vault '{}'", vault_name)).into());
+            }
+            let phrase = prompt_password(&format!("Recovery phrase for vault '{}' (input is hidden): ", vault_name))?;
+            rustpass::recovery::decode_phrase(phrase.expose())?;
+            let new_master = prompt_password("New master password: ")?;
+            if new_master.is_empty() {
+                return Err(Error::InvalidInput("The new master password cannot be empty.".to_string()).into());
+            }
+            let confirm = prompt_password("Confirm new master password: ")?;
+            if new_master != confirm {
+                return Err(Error::InvalidInput("Passwords do not match. Vault not recovered.".to_string()).into());
+            }
+            let new_master = master_key(new_master, keyfile.as_ref())?;
+            store.recover(&vault_name, phrase.expose(), &new_master)?;
+            // Wiped as soon as the vault is recovered.
+            drop(phrase);
+            println!("{}", format!("Vault '{}' recovered. A new master password has been set.", vault_name).green());
+        }
+        Commands::Recovery { action, keyfile } => {
+            let vault_name = selected_vault(&store)?;
+            match action {
+                RecoveryCommand::Generate => {
+                    let phrase = unlock_selected(&store, "Master password: ", keyfile.as_ref())?.set_recovery()?;
+                    println!("{}", "New recovery phrase created. Any previous phrase no longer works.".green());
+                    print_recovery_phrase(phrase.expose());
+                }
+                RecoveryCommand::Revoke => {
+                    unlock_selected(&store, "Master password: ", keyfile.as_ref())?.revoke_recovery()?;
+                    println!("{}", "Recovery phrase revoked.".green());
+                }
+                RecoveryCommand::Split { threshold, shares } => {
+                    let mut session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
+                    let secret = rustpass::recovery::generate_secret();
+                    let split = shamir::split(secret.as_slice(), threshold, shares)?;
+                    session.set_recovery_phrase(&rustpass::recovery::encode_phrase(&secret))?;
+                    println!("{}", format!("Recovery secret split into {} shares; any {} of them can recover vault '{}'.", shares, threshold, vault_name).green());
+                    println!("{}", "Any previous recovery phrase or shares no longer work. Give each share to a different person:".yellow());
+                    for share in &split {
+                        println!("  {}", share.encode().cyan());
+                    }
+                }
+                RecoveryCommand::Combine => {
+                    println!("Enter one share per line (input is hidden), then an empty line when done.");
+                    let mut given = Vec::new();
+                    loop {
+                        let line = prompt_password(&format!("Share {}: ", given.len() + 1))?;
+                        if line.is_empty() {
+                            break;
+                        }
+                        match shamir::Share::decode(line.expose()) {
+                            Ok(share) => given.push(share),
+                            Err(e) => println!("{} {}", "Share ignored:".red(), e),
+                        }
+                    }
+                    let secret = shamir::combine(&given)?;
+                    let phrase = match secret.as_slice().try_into() {
+                        Ok(secret) => SecretString::new(rustpass::recovery::encode_phrase(secret)),
+                        Err(_) => {
+                            return Err(Error::InvalidInput("The shares do not hold a recovery secret.".to_string()).into());
+                        }
+                    };
+                    let new_master = prompt_password("New master password: ")?;
+                    if new_master.is_empty() {
+                        return Err(Error::InvalidInput("The new master password cannot be empty.".to_string()).into());
+                    }
+                    let confirm = prompt_password("Confirm new master password: ")?;
+                    if new_master != confirm {
+                        return Err(Error::InvalidInput("Passwords do not match. Vault not recovered.".to_string()).into());
+                    }
+                    let new_master = master_key(new_master, keyfile.as_ref())?;
+                    store.recover(&vault_name, phrase.expose(), &new_master)?;
+                    println!("{}", format!("Vault '{}' recovered. A new master password has been set.", vault_name).green());
+                }
+            }
+        }
+        Commands::Keyfile { action } => match action {
+            KeyfileCommand::Generate { out } => {
+                if out.exists() {
+                    return Err(Error::InvalidInput(format!("{} already exists. Refusing to overwrite it.", out.display())).into());
+                }
+                fs::write(&out, crypto::generate_keyfile())?;
+                println!("{}", format!("Keyfile written to {}.", out.display()).green());
+                println!("Keep it safe: without it, vaults created with this keyfile cannot be unlocked.");
+            }
+        },
+        #[cfg(feature = "sqlite")]
+        Commands::Sqlite { action, keyfile } => match action {
+            SqliteCommand::Create { name } => {
+                if store.exists(&name)? {
+                    return Err(Error::InvalidInput(format!("Vault '{}' already exists.", name)).into());
+                }
+                let master = master_key(prompt_password("Set master password for this vault: ")?, keyfile.as_ref())?;
+                store.create_sqlite(&name, &master)?;
+                println!("{}", "SQLite vault created.".green());
+            }
+            SqliteCommand::Convert { vault: name } => {
+                let vault_name = match name {
+                    Some(name) => name,
+                    None => selected_vault(&store)?,
+                };
+                let master = master_key(prompt_password("Master password: ")?, keyfile.as_ref())?;
+                store.convert_to_sqlite(&vault_name, &master)?;
+                println!("{}", format!("Vault '{}' is now a SQLite vault.", vault_name).green());
+            }
+            SqliteCommand::Export { vault: name, out } => {
+                let vault_name = match name {
+                    Some(name) => name,
+                    None => selected_vault(&store)?,
+                };
+                let master = master_key(prompt_password("Master password: ")?, keyfile.as_ref())?;
+                let exported = store.export_sqlite(&vault_name, &master, &out)?;
+                println!("{}", format!("Vault '{}' written to {} ({} entries).", vault_name, out.display(), exported.entries()?.len()).green());
+            }
+            SqliteCommand::Import { from, vault: name } => {
+                let master = master_key(prompt_password(&format!("Master password of {}: ", from.display()))?, keyfile.as_ref())?;
+                store.import_sqlite(&from, &name, &master)?;
+                println!("{}", format!("Vault '{}' created from {}.", name, from.display()).green());
+            }
+        },
+        Commands::TuneKdf { target_ms, apply, keyfile } => {
+            println!("{}", format!("Benchmarking Argon2id for ~{} ms per unlock...", target_ms).blue().bold());
+            let kdf = rustpass::crypto::tune_kdf(std::time::Duration::from_millis(target_ms))?;
+            let elapsed = rustpass::crypto::time_kdf(&kdf)?;
+            println!("Suggested parameters: {}", kdf.to_string().cyan());
+            println!("Measured unlock time: {} ms", elapsed.as_millis().to_string().cyan());
+            if !apply {
+                println!("Run with --apply to re-encrypt the selected vault with these parameters.");
+                return Ok(());
+            }
+            let mut session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
+            session.set_kdf(kdf)?;
+            println!("{}", format!("Vault '{}' re-encrypted with the new parameters.", session.name()).green());
+        }
+        Commands::SetKdf { memory, iterations, parallelism, keyfile } => {
+            let mut session = unlock_selected(&store, "Master password: ", keyfile.as_ref())?;
+            let mut kdf = session.kdf_params()?;
+            println!("Current parameters: {}", kdf.to_string().cyan());
+            kdf.m_cost = memory.unwrap_or(kdf.m_cost);
+            kdf.t_cost = iterations.unwrap_or(kdf.t_cost);
+            kdf.p_cost = parallelism.unwrap_or(kdf.p_cost);
+            println!("New parameters:     {}", kdf.to_string().cyan());
+            session.set_kdf(kdf)?;
+            println!("{}", format!("Vault '{}' re-encrypted with the new parameters.", session.name()).green());
         }
     }
     Ok(())
@@ -1,21 +1,1042 @@
 use serde::{Serialize, Deserialize};
-use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
 use chrono::serde::ts_seconds;
+use ciborium::{ser, de};
+use crate::error::{Error, Result};
+use crate::secret::{SecretBytes, SecretString};
+use crate::folders;
+use crate::totp::Totp;
+use crate::urls;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// Schema version of the payloads this version of rustpass writes.
+pub const SCHEMA_VERSION: u32 = 8;
+
+/// The current vault schema, which the rest of the library works with.
+pub type Vault = VaultV8;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Entry {
+    pub id: u32,
+    pub name: String,
+    pub notes: Option<String>,
+    pub kind: EntryKind,
+    /// Custom fields, in the order they were added.
+    pub fields: Vec<CustomField>,
+    /// The sites the entry is for.
+    pub uris: Vec<EntryUri>,
+    pub tags: BTreeSet<String>,
+    /// Folder path such as `work/aws/prod`; `None` at the top level.
+    pub folder: Option<String>,
+    /// Time of the commit that added the entry. This and the times below
+    /// are set by `journal::apply`, and are `None` when unknown, as for
+    /// entries from before schema 7 that have not changed since.
+    pub created_at: Option<DateTime<Utc>>,
+    pub modified_at: Option<DateTime<Utc>>,
+    /// When `password()` last changed.
+    pub password_changed_at: Option<DateTime<Utc>>,
+    /// When the entry was last used, as recorded by `Session::mark_used`.
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl Entry {
+    /// A new entry; `id` is assigned when it is added to a vault.
+    pub fn new(name: impl Into<String>, kind: EntryKind) -> Entry {
+        Entry {
+            id: 0,
+            name: name.into(),
+            notes: None,
+            kind,
+            fields: Vec::new(),
+            uris: Vec::new(),
+            tags: BTreeSet::new(),
+            folder: None,
+            created_at: None,
+            modified_at: None,
+            password_changed_at: None,
+            last_used_at: None,
+        }
+    }
+
+    /// The secret whose changes `password_changed_at` tracks: a login's
+    /// password or an API credential's secret.
+    pub fn password(&self) -> Option<&SecretString> {
+        match &self.kind {
+            EntryKind::Login(login) => Some(&login.password),
+            EntryKind::ApiCredential(api) => Some(&api.secret),
+            _ => None,
+        }
+    }
+
+    pub fn time(&self, which: EntryTime) -> Option<DateTime<Utc>> {
+        match which {
+            EntryTime::Created => self.created_at,
+            EntryTime::Modified => self.modified_at,
+            EntryTime::PasswordChanged => self.password_changed_at,
+            EntryTime::LastUsed => self.last_used_at,
+        }
+    }
+
+    /// Whether the entry is in folder `path` or one of its subfolders.
+    pub fn in_folder(&self, path: &str) -> bool {
+        self.folder.as_deref().is_some_and(|folder| folders::contains(path, folder))
+    }
+
+    pub fn as_login(&self) -> Option<&Login> {
+        match &self.kind {
+            EntryKind::Login(login) => Some(login),
+            _ => None,
+        }
+    }
+
+    pub fn as_login_mut(&mut self) -> Option<&mut Login> {
+        match &mut self.kind {
+            EntryKind::Login(login) => Some(login),
+            _ => None,
+        }
+    }
+
+    pub fn field(&self, label: &str) -> Option<&CustomField> {
+        self.fields.iter().find(|f| f.label == label)
+    }
+
+    pub fn field_mut(&mut self, label: &str) -> Option<&mut CustomField> {
+        self.fields.iter_mut().find(|f| f.label == label)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            return Err(Error::InvalidInput("An entry needs a name.".to_string()));
+        }
+        self.kind.validate()?;
+        for (i, field) in self.fields.iter().enumerate() {
+            field.validate()?;
+            if self.fields[..i].iter().any(|f| f.label == field.label) {
+                return Err(Error::InvalidInput(format!("The entry has two fields labelled '{}'.", field.label)));
+            }
+        }
+        for uri in &self.uris {
+            uri.validate()?;
+        }
+        for tag in &self.tags {
+            validate_tag(tag)?;
+        }
+        if let Some(folder) = &self.folder {
+            if folders::normalize(folder)? != *folder {
+                return Err(Error::InvalidInput(format!("'{}' is not a normalized folder path.", folder)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One of the times kept for each entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryTime {
+    Created,
+    Modified,
+    PasswordChanged,
+    LastUsed,
+}
+
+impl EntryTime {
+    /// Short name of the time, as used on the command line.
+    pub fn label(&self) -> &'static str {
+        match self {
+            EntryTime::Created => "created",
+            EntryTime::Modified => "modified",
+            EntryTime::PasswordChanged => "changed",
+            EntryTime::LastUsed => "used",
+        }
+    }
+}
+
+impl FromStr for EntryTime {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<EntryTime> {
+        [EntryTime::Created, EntryTime::Modified, EntryTime::PasswordChanged, EntryTime::LastUsed]
+            .into_iter()
+            .find(|which| which.label().eq_ignore_ascii_case(s))
+            .ok_or_else(|| Error::InvalidInput(format!("'{}' is not an entry time; use created, modified, changed or used.", s)))
+    }
+}
+
+/// What an entry holds, with the fields of that kind.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum EntryKind {
+    Login(Login),
+    SecureNote(SecureNote),
+    Card(Card),
+    Identity(Identity),
+    SshKey(SshKey),
+    ApiCredential(ApiCredential),
+}
+
+impl EntryKind {
+    /// Short name of the kind, as used on the command line.
+    pub fn label(&self) -> &'static str {
+        match self {
+            EntryKind::Login(_) => "login",
+            EntryKind::SecureNote(_) => "note",
+            EntryKind::Card(_) => "card",
+            EntryKind::Identity(_) => "identity",
+            EntryKind::SshKey(_) => "ssh-key",
+            EntryKind::ApiCredential(_) => "api",
+        }
+    }
+
+    /// Checks the fields that have a known shape, such as card numbers.
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            EntryKind::Login(_) => Ok(()),
+            EntryKind::SecureNote(note) => {
+                if note.text.is_empty() {
+                    return Err(Error::InvalidInput("A secure note cannot be empty.".to_string()));
+                }
+                Ok(())
+            }
+            EntryKind::Card(card) => card.validate(),
+            EntryKind::Identity(identity) => identity.validate(),
+            EntryKind::SshKey(key) => key.validate(),
+            EntryKind::ApiCredential(api) => api.validate(),
+        }
+    }
+
+    /// Every field of the kind as a label and the text to show for it.
+    /// Secrets are masked unless `reveal`; card numbers then show only
+    /// their last four digits. Cards past their expiry on `today` say so.
+    pub fn fields(&self, reveal: bool, today: NaiveDate) -> Vec<(&'static str, String)> {
+        let mut fields = Vec::new();
+        let mut optional = |label, value: Option<&str>| {
+            if let Some(value) = value {
+                fields.push((label, value.to_string()));
+            }
+        };
+        let secret = |value: &SecretString| if reveal { value.expose().to_string() } else { "********".to_string() };
+        match self {
+            EntryKind::Login(login) => {
+                optional("Username", Some(&login.username));
+                optional("Password", Some(&secret(&login.password)));
+            }
+            EntryKind::SecureNote(note) => optional("Text", Some(&secret(&note.text))),
+            EntryKind::Card(card) => {
+                let expired = if card.is_expired(today) { " (expired)" } else { "" };
+                let number = if reveal { card.number.expose().to_string() } else { format!("•••• {}", card.last_four()) };
+                optional("Cardholder", Some(&card.cardholder));
+                optional("Brand", Some(card.brand()));
+                optional("Number", Some(&number));
+                optional("Expiry", Some(&format!("{}{}", card.expiry, expired)));
+                optional("CVV", Some(&secret(&card.cvv)));
+            }
+            EntryKind::Identity(identity) => {
+                optional("Full name", Some(&identity.full_name));
+                optional("Email", identity.email.as_deref());
+                optional("Phone", identity.phone.as_deref());
+                optional("Address", identity.address.as_deref());
+                optional("Passport", identity.passport_number.as_ref().map(secret).as_deref());
+                optional("Driver's license", identity.license_number.as_ref().map(secret).as_deref());
+                optional("National ID", identity.national_id.as_ref().map(secret).as_deref());
+            }
+            EntryKind::SshKey(key) => {
+                let private_key = if reveal { key.private_key.expose().trim_end().to_string() } else { secret(&key.private_key) };
+                optional("Public key", key.public_key.as_deref());
+                optional("Private key", Some(&private_key));
+                optional("Passphrase", key.passphrase.as_ref().map(secret).as_deref());
+            }
+            EntryKind::ApiCredential(api) => {
+                optional("Key ID", api.key_id.as_deref());
+                optional("Secret", Some(&secret(&api.secret)));
+                optional("Endpoint", api.endpoint.as_deref());
+            }
+        }
+        fields
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Login {
+    pub username: String,
+    pub password: SecretString,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SecureNote {
+    pub text: SecretString,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Card {
+    pub cardholder: String,
+    /// Digits only.
+    pub number: SecretString,
+    pub expiry: Expiry,
+    pub cvv: SecretString,
+}
+
+impl Card {
+    /// Checks that the number has 12 to 19 digits and passes the Luhn
+    /// check, and that the CVV has 3 or 4 digits.
+    pub fn validate(&self) -> Result<()> {
+        let number = self.number.expose();
+        if !(12..=19).contains(&number.len()) || !number.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(Error::InvalidInput("A card number has 12 to 19 digits.".to_string()));
+        }
+        if !luhn_valid(number) {
+            return Err(Error::InvalidInput("The card number fails the Luhn check; it has a typo.".to_string()));
+        }
+        let cvv = self.cvv.expose();
+        if !(3..=4).contains(&cvv.len()) || !cvv.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(Error::InvalidInput("A CVV has 3 or 4 digits.".to_string()));
+        }
+        self.expiry.validate()
+    }
+
+    /// The card network, guessed from the number's prefix.
+    pub fn brand(&self) -> &'static str {
+        let number = self.number.expose();
+        let prefix = |len: usize| number.get(..len).and_then(|p| p.parse::<u32>().ok()).unwrap_or(0);
+        match () {
+            _ if number.starts_with('4') => "Visa",
+            _ if (51..=55).contains(&prefix(2)) || (2221..=2720).contains(&prefix(4)) => "Mastercard",
+            _ if prefix(2) == 34 || prefix(2) == 37 => "American Express",
+            _ if prefix(4) == 6011 || prefix(2) == 65 || (644..=649).contains(&prefix(3)) => "Discover",
+            _ => "Card",
+        }
+    }
+
+    pub fn last_four(&self) -> &str {
+        let number = self.number.expose();
+        &number[number.len().saturating_sub(4)..]
+    }
+
+    /// Whether the card is past its expiry month on `today`.
+    pub fn is_expired(&self, today: NaiveDate) -> bool {
+        (self.expiry.year as i32, self.expiry.month as u32) < (today.year(), today.month())
+    }
+}
+
+/// Whether `digits` passes the Luhn checksum used by card numbers.
+pub fn luhn_valid(digits: &str) -> bool {
+    let mut sum = 0;
+    for (i, b) in digits.bytes().rev().enumerate() {
+        if !b.is_ascii_digit() {
+            return false;
+        }
+        let mut d = (b - b'0') as u32;
+        if i % 2 == 1 {
+            d *= 2;
+            if d > 9 {
+                d -= 9;
+            }
+        }
+        sum += d;
+    }
+    !digits.is_empty() && sum % 10 == 0
+}
+
+/// The month a card expires in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expiry {
+    pub month: u8,
+    pub year: u16,
+}
+
+impl Expiry {
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=12).contains(&self.month) || !(2000..=2099).contains(&self.year) {
+            return Err(Error::InvalidInput(format!("{} is not a valid expiry date.", self)));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Expiry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}/{}", self.month, self.year)
+    }
+}
+
+/// Parses `MM/YY` or `MM/YYYY`.
+impl FromStr for Expiry {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Expiry> {
+        let invalid = || Error::InvalidInput(format!("'{}' is not an expiry date; use MM/YY or MM/YYYY.", s));
+        let (month, year) = s.trim().split_once('/').ok_or_else(invalid)?;
+        let month: u8 = month.parse().map_err(|_| invalid())?;
+        let year: u16 = match year.len() {
+            2 => 2000 + year.parse::<u16>().map_err(|_| invalid())?,
+            4 => year.parse().map_err(|_| invalid())?,
+            _ => return Err(invalid()),
+        };
+        let expiry = Expiry { month, year };
+        expiry.validate()?;
+        Ok(expiry)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub full_name: String,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub address: Option<String>,
+    pub passport_number: Option<SecretString>,
+    pub license_number: Option<SecretString>,
+    pub national_id: Option<SecretString>,
+}
+
+impl Identity {
+    pub fn validate(&self) -> Result<()> {
+        if self.full_name.trim().is_empty() {
+            return Err(Error::InvalidInput("An identity needs a full name.".to_string()));
+        }
+        if let Some(email) = &self.email {
+            if !is_email(email) {
+                return Err(Error::InvalidInput(format!("'{}' is not an email address.", email)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks that `tag` is non-empty and has no whitespace or commas.
+pub fn validate_tag(tag: &str) -> Result<()> {
+    if tag.is_empty() || tag.chars().any(|c| c.is_whitespace() || c == ',') {
+        return Err(Error::InvalidInput(format!("'{}' is not a valid tag; tags are single words.", tag)));
+    }
+    Ok(())
+}
+
+fn is_email(s: &str) -> bool {
+    s.split_once('@').is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.'))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SshKey {
+    /// The private key in PEM or OpenSSH format.
+    pub private_key: SecretString,
+    /// The `authorized_keys` line of the public key.
+    pub public_key: Option<String>,
+    pub passphrase: Option<SecretString>,
+}
+
+impl SshKey {
+    pub fn validate(&self) -> Result<()> {
+        let private_key = self.private_key.expose().trim();
+        if !private_key.starts_with("-----BEGIN ") || !private_key.contains("PRIVATE KEY-----") {
+            return Err(Error::InvalidInput("The private key is not in PEM or OpenSSH format.".to_string()));
+        }
+        if let Some(public_key) = &self.public_key {
+            let mut parts = public_key.split_whitespace();
+            let algorithm = parts.next().unwrap_or_default();
+            let known = ["ssh-", "ecdsa-", "sk-"].iter().any(|p| algorithm.starts_with(p));
+            if !known || parts.next().is_none() {
+                return Err(Error::InvalidInput("The public key is not an OpenSSH public key line.".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The key algorithm, from the public key if there is one.
+    pub fn algorithm(&self) -> Option<&str> {
+        self.public_key.as_deref()?.split_whitespace().next()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiCredential {
+    /// The public half, such as an access key id or client id.
+    pub key_id: Option<String>,
+    pub secret: SecretString,
+    pub endpoint: Option<String>,
+}
+
+impl ApiCredential {
+    pub fn validate(&self) -> Result<()> {
+        if self.secret.is_empty() {
+            return Err(Error::InvalidInput("An API credential needs a secret.".to_string()));
+        }
+        if let Some(endpoint) = &self.endpoint {
+            if !endpoint.starts_with("https://") && !endpoint.starts_with("http://") {
+                return Err(Error::InvalidInput(format!("'{}' is not an http(s) URL.", endpoint)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A labelled value the user added to an entry, beyond the fields of its
+/// kind.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CustomField {
+    pub label: String,
+    pub value: SecretString,
+    pub kind: FieldType,
+}
+
+impl CustomField {
+    /// Checks that the value has the shape its type asks for.
+    pub fn validate(&self) -> Result<()> {
+        if self.label.trim().is_empty() {
+            return Err(Error::InvalidInput("A custom field needs a label.".to_string()));
+        }
+        let value = self.value.expose();
+        let valid = match self.kind {
+            FieldType::Text | FieldType::Hidden => true,
+            FieldType::Url => value.split_once("://").is_some_and(|(scheme, rest)| !scheme.is_empty() && !rest.is_empty()),
+            FieldType::Email => is_email(value),
+            FieldType::Date => NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok(),
+            FieldType::Totp => Totp::parse(value).is_ok(),
+        };
+        if !valid {
+            let expected = match self.kind {
+                FieldType::Url => "a URL such as https://example.com",
+                FieldType::Email => "an email address",
+                FieldType::Date => "a date as YYYY-MM-DD",
+                _ => "a base32 TOTP secret or an otpauth://totp/ URI",
+            };
+            return Err(Error::InvalidInput(format!("Field '{}' should hold {}.", self.label, expected)));
+        }
+        Ok(())
+    }
+}
+
+/// How a custom field's value is shown and checked.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    Text,
+    /// Masked unless asked to reveal it.
+    Hidden,
+    Url,
+    Email,
+    /// A calendar date, as `YYYY-MM-DD`.
+    Date,
+    /// A TOTP secret, shown as its current code.
+    Totp,
+}
+
+impl FieldType {
+    /// Short name of the type, as used on the command line.
+    pub fn label(&self) -> &'static str {
+        match self {
+            FieldType::Text => "text",
+            FieldType::Hidden => "hidden",
+            FieldType::Url => "url",
+            FieldType::Email => "email",
+            FieldType::Date => "date",
+            FieldType::Totp => "totp",
+        }
+    }
+
+    /// Whether the value is a secret that is masked unless revealed.
+    pub fn is_secret(&self) -> bool {
+        matches!(self, FieldType::Hidden | FieldType::Totp)
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+impl FromStr for FieldType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<FieldType> {
+        [FieldType::Text, FieldType::Hidden, FieldType::Url, FieldType::Email, FieldType::Date, FieldType::Totp]
+            .into_iter()
+            .find(|kind| kind.label().eq_ignore_ascii_case(s))
+            .ok_or_else(|| Error::InvalidInput(format!("'{}' is not a field type; use text, hidden, url, email, date or totp.", s)))
+    }
+}
+
+/// A site an entry is for, and how page URLs are matched against it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EntryUri {
+    pub uri: String,
+    pub mode: UriMatch,
+}
+
+impl EntryUri {
+    pub fn new(uri: impl Into<String>, mode: UriMatch) -> EntryUri {
+        EntryUri { uri: uri.into(), mode }
+    }
+
+    /// Checks that the URI parses (or, for `Regex`, compiles).
+    pub fn validate(&self) -> Result<()> {
+        match self.mode {
+            UriMatch::Regex => {
+                regex::Regex::new(&self.uri).map_err(|e| Error::InvalidInput(format!("'{}' is not a valid regex: {}", self.uri, e)))?;
+            }
+            UriMatch::Never => {}
+            _ => {
+                urls::parse(&self.uri)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How `EntryUri::uri` is matched against a page URL.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UriMatch {
+    /// The same registrable domain, by the public suffix list, so
+    /// `login.example.co.uk` matches `www.example.co.uk`.
+    BaseDomain,
+    /// The same host and port.
+    Host,
+    /// The page URL starts with the URI.
+    Prefix,
+    /// The URI is a regular expression searched for in the page URL.
+    Regex,
+    /// Never matched; the URI is kept for reference.
+    Never,
+}
+
+impl UriMatch {
+    /// Short name of the mode, as used on the command line.
+    pub fn label(&self) -> &'static str {
+        match self {
+            UriMatch::BaseDomain => "domain",
+            UriMatch::Host => "host",
+            UriMatch::Prefix => "prefix",
+            UriMatch::Regex => "regex",
+            UriMatch::Never => "never",
+        }
+    }
+}
+
+impl fmt::Display for UriMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+impl FromStr for UriMatch {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<UriMatch> {
+        [UriMatch::BaseDomain, UriMatch::Host, UriMatch::Prefix, UriMatch::Regex, UriMatch::Never]
+            .into_iter()
+            .find(|mode| mode.label().eq_ignore_ascii_case(s))
+            .ok_or_else(|| Error::InvalidInput(format!("'{}' is not a match mode; use domain, host, prefix, regex or never.", s)))
+    }
+}
+
+/// An entry as stored in schemas 1 and 2, all of which were logins.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntryV1 {
     pub id: u32,
     pub name: String,
     pub username: String,
-    pub password: String,
+    pub password: SecretString,
     pub notes: Option<String>,
 }
 
+/// Schema 1, which stored timestamps to the second.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VaultV1 {
     #[serde(with = "ts_seconds")]
     pub created_at: DateTime<Utc>,
     #[serde(with = "ts_seconds")]
     pub last_modified: DateTime<Utc>,
+    pub entries: Vec<EntryV1>,
+}
+
+/// Schema 2: timestamps keep their full precision.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaultV2 {
+    pub created_at: DateTime<Utc>,
+    pub last_modified: DateTime<Utc>,
+    pub entries: Vec<EntryV1>,
+}
+
+/// An entry as stored in schema 3, before custom fields.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntryV3 {
+    pub id: u32,
+    pub name: String,
+    pub notes: Option<String>,
+    pub kind: EntryKind,
+}
+
+/// Schema 3: entries have a kind.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaultV3 {
+    pub created_at: DateTime<Utc>,
+    pub last_modified: DateTime<Utc>,
+    pub entries: Vec<EntryV3>,
+}
+
+/// An entry as stored in schema 4, before URIs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntryV4 {
+    pub id: u32,
+    pub name: String,
+    pub notes: Option<String>,
+    pub kind: EntryKind,
+    pub fields: Vec<CustomField>,
+}
+
+/// Schema 4: entries have custom fields.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaultV4 {
+    pub created_at: DateTime<Utc>,
+    pub last_modified: DateTime<Utc>,
+    pub entries: Vec<EntryV4>,
+}
+
+/// An entry as stored in schema 5, before tags and folders.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntryV5 {
+    pub id: u32,
+    pub name: String,
+    pub notes: Option<String>,
+    pub kind: EntryKind,
+    pub fields: Vec<CustomField>,
+    pub uris: Vec<EntryUri>,
+}
+
+/// Schema 5: entries have URIs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaultV5 {
+    pub created_at: DateTime<Utc>,
+    pub last_modified: DateTime<Utc>,
+    pub entries: Vec<EntryV5>,
+}
+
+/// An entry as stored in schema 6, before entry timestamps.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntryV6 {
+    pub id: u32,
+    pub name: String,
+    pub notes: Option<String>,
+    pub kind: EntryKind,
+    pub fields: Vec<CustomField>,
+    pub uris: Vec<EntryUri>,
+    pub tags: BTreeSet<String>,
+    pub folder: Option<String>,
+}
+
+/// Schema 6: entries have tags and a folder.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaultV6 {
+    pub created_at: DateTime<Utc>,
+    pub last_modified: DateTime<Utc>,
+    pub entries: Vec<EntryV6>,
+}
+
+/// Schema 7: entries have their own timestamps.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaultV7 {
+    pub created_at: DateTime<Utc>,
+    pub last_modified: DateTime<Utc>,
+    pub entries: Vec<Entry>,
+}
+
+/// Schema 8: the vault remembers the next entry id, so the id of a deleted
+/// entry is never handed out again. Entries are as in schema 7.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaultV8 {
+    pub created_at: DateTime<Utc>,
+    pub last_modified: DateTime<Utc>,
+    /// The id the next added entry gets; higher than any id ever used.
+    pub next_id: u32,
     pub entries: Vec<Entry>,
 }
+
+impl VaultV8 {
+    /// Takes the next entry id for a new entry.
+    pub fn allocate_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+}
+
+/// A decrypted vault payload in any schema this version understands.
+#[derive(Debug, Clone)]
+pub enum VersionedVault {
+    V1(VaultV1),
+    V2(VaultV2),
+    V3(VaultV3),
+    V4(VaultV4),
+    V5(VaultV5),
+    V6(VaultV6),
+    V7(VaultV7),
+    V8(VaultV8),
+}
+
+impl VersionedVault {
+    /// Decodes a vault payload, refusing schemas newer than `SCHEMA_VERSION`.
+    pub fn decode(payload: &[u8]) -> Result<VersionedVault> {
+        Ok(match payload_schema(payload)? {
+            1 => VersionedVault::V1(decode_payload(payload, 1)?),
+            2 => VersionedVault::V2(decode_payload(payload, 2)?),
+            3 => VersionedVault::V3(decode_payload(payload, 3)?),
+            4 => VersionedVault::V4(decode_payload(payload, 4)?),
+            5 => VersionedVault::V5(decode_payload(payload, 5)?),
+            6 => VersionedVault::V6(decode_payload(payload, 6)?),
+            7 => VersionedVault::V7(decode_payload(payload, 7)?),
+            schema => VersionedVault::V8(decode_payload(payload, schema)?),
+        })
+    }
+
+    pub fn version(&self) -> u32 {
+        match self {
+            VersionedVault::V1(_) => 1,
+            VersionedVault::V2(_) => 2,
+            VersionedVault::V3(_) => 3,
+            VersionedVault::V4(_) => 4,
+            VersionedVault::V5(_) => 5,
+            VersionedVault::V6(_) => 6,
+            VersionedVault::V7(_) => 7,
+            VersionedVault::V8(_) => 8,
+        }
+    }
+
+    /// Brings the payload up to the current schema, one version at a time.
+    pub fn migrate(self) -> Vault {
+        match self {
+            VersionedVault::V1(v) => VersionedVault::V2(v1_to_v2(v)).migrate(),
+            VersionedVault::V2(v) => VersionedVault::V3(v2_to_v3(v)).migrate(),
+            VersionedVault::V3(v) => VersionedVault::V4(v3_to_v4(v)).migrate(),
+            VersionedVault::V4(v) => VersionedVault::V5(v4_to_v5(v)).migrate(),
+            VersionedVault::V5(v) => VersionedVault::V6(v5_to_v6(v)).migrate(),
+            VersionedVault::V6(v) => VersionedVault::V7(v6_to_v7(v)).migrate(),
+            VersionedVault::V7(v) => VersionedVault::V8(v7_to_v8(v)).migrate(),
+            VersionedVault::V8(v) => v,
+        }
+    }
+}
+
+/// An entry stored on its own, in a journal record or a SQLite row, in any
+/// schema this version understands. Schema 8 left entries as they were in 7.
+#[derive(Debug, Clone)]
+pub enum VersionedEntry {
+    V1(EntryV1),
+    V3(EntryV3),
+    V4(EntryV4),
+    V5(EntryV5),
+    V6(EntryV6),
+    V7(Entry),
+}
+
+impl VersionedEntry {
+    /// Decodes an entry payload, refusing schemas newer than `SCHEMA_VERSION`.
+    pub fn decode(payload: &[u8]) -> Result<VersionedEntry> {
+        Ok(match payload_schema(payload)? {
+            schema @ (1 | 2) => VersionedEntry::V1(decode_payload(payload, schema)?),
+            3 => VersionedEntry::V3(decode_payload(payload, 3)?),
+            4 => VersionedEntry::V4(decode_payload(payload, 4)?),
+            5 => VersionedEntry::V5(decode_payload(payload, 5)?),
+            6 => VersionedEntry::V6(decode_payload(payload, 6)?),
+            schema => VersionedEntry::V7(decode_payload(payload, schema)?),
+        })
+    }
+
+    pub fn migrate(self) -> Entry {
+        match self {
+            VersionedEntry::V1(e) => VersionedEntry::V3(entry_v1_to_v3(e)).migrate(),
+            VersionedEntry::V3(e) => VersionedEntry::V4(entry_v3_to_v4(e)).migrate(),
+            VersionedEntry::V4(e) => VersionedEntry::V5(entry_v4_to_v5(e)).migrate(),
+            VersionedEntry::V5(e) => VersionedEntry::V6(entry_v5_to_v6(e)).migrate(),
+            VersionedEntry::V6(e) => VersionedEntry::V7(entry_v6_to_v7(e)).migrate(),
+            VersionedEntry::V7(e) => e,
+        }
+    }
+}
+
+// Each migration turns one schema into the next and does nothing else, so
+// the chain can be followed from any stored version.
+fn v1_to_v2(v: VaultV1) -> VaultV2 {
+    VaultV2 {
+        created_at: v.created_at,
+        last_modified: v.last_modified,
+        entries: v.entries,
+    }
+}
+
+fn v2_to_v3(v: VaultV2) -> VaultV3 {
+    VaultV3 {
+        created_at: v.created_at,
+        last_modified: v.last_modified,
+        entries: v.entries.into_iter().map(entry_v1_to_v3).collect(),
+    }
+}
+
+fn entry_v1_to_v3(e: EntryV1) -> EntryV3 {
+    EntryV3 {
+        id: e.id,
+        name: e.name,
+        notes: e.notes,
+        kind: EntryKind::Login(Login {
+            username: e.username,
+            password: e.password,
+        }),
+    }
+}
+
+fn v3_to_v4(v: VaultV3) -> VaultV4 {
+    VaultV4 {
+        created_at: v.created_at,
+        last_modified: v.last_modified,
+        entries: v.entries.into_iter().map(entry_v3_to_v4).collect(),
+    }
+}
+
+fn entry_v3_to_v4(e: EntryV3) -> EntryV4 {
+    EntryV4 {
+        id: e.id,
+        name: e.name,
+        notes: e.notes,
+        kind: e.kind,
+        fields: Vec::new(),
+    }
+}
+
+fn v4_to_v5(v: VaultV4) -> VaultV5 {
+    VaultV5 {
+        created_at: v.created_at,
+        last_modified: v.last_modified,
+        entries: v.entries.into_iter().map(entry_v4_to_v5).collect(),
+    }
+}
+
+fn entry_v4_to_v5(e: EntryV4) -> EntryV5 {
+    EntryV5 {
+        id: e.id,
+        name: e.name,
+        notes: e.notes,
+        kind: e.kind,
+        fields: e.fields,
+        uris: Vec::new(),
+    }
+}
+
+fn v5_to_v6(v: VaultV5) -> VaultV6 {
+    VaultV6 {
+        created_at: v.created_at,
+        last_modified: v.last_modified,
+        entries: v.entries.into_iter().map(entry_v5_to_v6).collect(),
+    }
+}
+
+fn entry_v5_to_v6(e: EntryV5) -> EntryV6 {
+    EntryV6 {
+        id: e.id,
+        name: e.name,
+        notes: e.notes,
+        kind: e.kind,
+        fields: e.fields,
+        uris: e.uris,
+        tags: BTreeSet::new(),
+        folder: None,
+    }
+}
+
+fn v6_to_v7(v: VaultV6) -> VaultV7 {
+    VaultV7 {
+        created_at: v.created_at,
+        last_modified: v.last_modified,
+        entries: v.entries.into_iter().map(entry_v6_to_v7).collect(),
+    }
+}
+
+fn v7_to_v8(v: VaultV7) -> VaultV8 {
+    VaultV8 {
+        created_at: v.created_at,
+        last_modified: v.last_modified,
+        next_id: v.entries.iter().map(|e| e.id).max().unwrap_or(0) + 1,
+        entries: v.entries,
+    }
+}
+
+fn entry_v6_to_v7(e: EntryV6) -> Entry {
+    Entry {
+        id: e.id,
+        name: e.name,
+        notes: e.notes,
+        kind: e.kind,
+        fields: e.fields,
+        uris: e.uris,
+        tags: e.tags,
+        folder: e.folder,
+        created_at: None,
+        modified_at: None,
+        password_changed_at: None,
+        last_used_at: None,
+    }
+}
+
+// From schema 2 on, every encrypted payload (vault, journal record, SQLite
+// row) is `{ "schema": N, "payload": ... }`, so a reader learns the version
+// before decoding the rest. Schema 1 payloads are bare and have no `schema`.
+#[derive(Serialize)]
+struct Tagged<'a, T> {
+    schema: u32,
+    payload: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Untagged<T> {
+    payload: T,
+}
+
+#[derive(Deserialize)]
+struct Probe {
+    schema: Option<u32>,
+}
+
+/// Encodes `payload` tagged with the current schema version.
+pub(crate) fn encode_payload<T: Serialize>(payload: &T) -> Result<SecretBytes> {
+    // Sized by a first pass so the buffer never grows: growing would leave
+    // copies of the plaintext behind in freed memory.
+    let tagged = Tagged { schema: SCHEMA_VERSION, payload };
+    let mut counter = ByteCounter(0);
+    ser::into_writer(&tagged, &mut counter)?;
+    let mut buf = SecretBytes::new(Vec::with_capacity(counter.0));
+    let capacity = buf.capacity();
+    ser::into_writer(&tagged, &mut *buf)?;
+    debug_assert_eq!(buf.capacity(), capacity, "the payload buffer was reallocated");
+    Ok(buf)
+}
+
+// Counts the bytes written to it and discards them.
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The schema version `payload` was written with.
+pub(crate) fn payload_schema(payload: &[u8]) -> Result<u32> {
+    let probe: Probe = de::from_reader(payload)?;
+    match probe.schema.unwrap_or(1) {
+        schema @ 1..=SCHEMA_VERSION => Ok(schema),
+        schema => Err(Error::UnsupportedSchema(schema)),
+    }
+}
+
+/// Decodes a payload written with `schema` as `T`.
+pub(crate) fn decode_payload<T: DeserializeOwned>(payload: &[u8], schema: u32) -> Result<T> {
+    if schema == 1 {
+        return Ok(de::from_reader(payload)?);
+    }
+    let tagged: Untagged<T> = de::from_reader(payload)?;
+    Ok(tagged.payload)
+}
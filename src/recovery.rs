@@ -0,0 +1,69 @@
+use crate::error::{Error, Result};
+use crate::secret::SecretBytes;
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+// Recovery phrases use the BIP-39 English wordlist: 256 bits of secret plus an
+// 8-bit SHA-256 checksum, split into 24 words of 11 bits each.
+static WORDLIST: Lazy<Vec<&'static str>> = Lazy::new(|| include_str!("wordlist.txt").lines().collect());
+
+pub const PHRASE_WORDS: usize = 24;
+pub const SECRET_LEN: usize = 32;
+
+pub fn generate_secret() -> Zeroizing<[u8; SECRET_LEN]> {
+    let mut secret = Zeroizing::new([0u8; SECRET_LEN]);
+    rand::thread_rng().fill_bytes(secret.as_mut());
+    secret
+}
+
+fn checksum(secret: &[u8; SECRET_LEN]) -> u8 {
+    Sha256::digest(secret)[0]
+}
+
+pub fn encode_phrase(secret: &[u8; SECRET_LEN]) -> String {
+    let mut bits = SecretBytes::new(Vec::with_capacity(SECRET_LEN + 1));
+    bits.extend_from_slice(secret);
+    bits.push(checksum(secret));
+    (0..PHRASE_WORDS)
+        .map(|i| {
+            let index = (0..11).fold(0usize, |acc, b| {
+                let bit = i * 11 + b;
+                (acc << 1) | ((bits[bit / 8] >> (7 - bit % 8)) & 1) as usize
+            });
+            WORDLIST[index]
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn decode_phrase(phrase: &str) -> Result<Zeroizing<[u8; SECRET_LEN]>> {
+    let words: Zeroizing<Vec<String>> = Zeroizing::new(phrase.split_whitespace().map(|w| w.to_lowercase()).collect());
+    if words.len() != PHRASE_WORDS {
+        return Err(Error::InvalidInput(format!("A recovery phrase has {} words, got {}.", PHRASE_WORDS, words.len())));
+    }
+    let mut bits = Zeroizing::new([0u8; SECRET_LEN + 1]);
+    for (i, word) in words.iter().enumerate() {
+        let index = WORDLIST
+            .binary_search(&word.as_str())
+            .map_err(|_| Error::InvalidInput(format!("'{}' (word {}) is not in the recovery wordlist.", word, i + 1)))?;
+        for b in 0..11 {
+            if (index >> (10 - b)) & 1 == 1 {
+                let bit = i * 11 + b;
+                bits[bit / 8] |= 1 << (7 - bit % 8);
+            }
+        }
+    }
+    let mut secret = Zeroizing::new([0u8; SECRET_LEN]);
+    secret.copy_from_slice(&bits[..SECRET_LEN]);
+    if checksum(&secret) != bits[SECRET_LEN] {
+        return Err(Error::InvalidInput("Recovery phrase checksum does not match. Check the words and their order.".to_string()));
+    }
+    Ok(secret)
+}
+
+/// Canonical form of a phrase, used as the password of the recovery key slot.
+pub fn normalize_phrase(phrase: &str) -> Result<String> {
+    Ok(encode_phrase(&*decode_phrase(phrase)?))
+}
@@ -0,0 +1,192 @@
+use crate::error::{Error, Result};
+use crate::secret::SecretBytes;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+// Shamir secret sharing over GF(256) with the AES reduction polynomial
+// x^8 + x^4 + x^3 + x + 1. Every byte of the secret is the constant term of
+// its own random polynomial of degree `threshold - 1`; share `i` holds the
+// evaluations at x = i.
+//
+// A share is encoded as text:
+//
+//   RPS1-<threshold>-<index>-<set id>-<data>-<checksum>
+//
+// The set id is derived from the secret, so shares from different splits are
+// refused and a wrong combination is detected instead of yielding a bad key.
+// The checksum covers the rest of the share and catches typos.
+const PREFIX: &str = "RPS1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    pub threshold: u8,
+    pub index: u8,
+    pub set_id: [u8; 4],
+    pub data: SecretBytes,
+}
+
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80;
+        a <<= 1;
+        if carry != 0 {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+fn gf_inv(a: u8) -> u8 {
+    // a^254 == a^-1 in GF(256).
+    let mut result = 1u8;
+    let mut base = a;
+    let mut exp = 254u8;
+    while exp != 0 {
+        if exp & 1 != 0 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+fn set_id(secret: &[u8]) -> [u8; 4] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"rustpass share set");
+    hasher.update(secret);
+    let digest = hasher.finalize();
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Result<Vec<Share>> {
+    if threshold < 2 {
+        return Err(Error::InvalidInput("The threshold must be at least 2.".to_string()));
+    }
+    if shares < threshold {
+        return Err(Error::InvalidInput(format!("Cannot create {} shares with a threshold of {}.", shares, threshold)));
+    }
+    let id = set_id(secret);
+    let mut coefficients = vec![0u8; threshold as usize - 1];
+    let mut out: Vec<Share> = (1..=shares)
+        .map(|index| Share {
+            threshold,
+            index,
+            set_id: id,
+            data: SecretBytes::new(Vec::with_capacity(secret.len())),
+        })
+        .collect();
+    for &byte in secret {
+        rand::thread_rng().fill_bytes(&mut coefficients);
+        for share in out.iter_mut() {
+            // Horner's rule, highest coefficient first.
+            let y = coefficients
+                .iter()
+                .rev()
+                .fold(0u8, |acc, &c| gf_mul(acc, share.index) ^ c);
+            share.data.push(gf_mul(y, share.index) ^ byte);
+        }
+    }
+    Ok(out)
+}
+
+// Evaluates the polynomials through `shares` at `x`; x = 0 gives the secret.
+fn interpolate(shares: &[Share], x: u8) -> SecretBytes {
+    let mut out = SecretBytes::new(vec![0u8; shares[0].data.len()]);
+    for (i, share) in shares.iter().enumerate() {
+        // Lagrange basis polynomial for this share, evaluated at `x`.
+        let mut basis = 1u8;
+        for (j, other) in shares.iter().enumerate() {
+            if i != j {
+                basis = gf_mul(basis, gf_mul(other.index ^ x, gf_inv(other.index ^ share.index)));
+            }
+        }
+        for (out, &y) in out.iter_mut().zip(share.data.iter()) {
+            *out ^= gf_mul(y, basis);
+        }
+    }
+    out
+}
+
+pub fn combine(shares: &[Share]) -> Result<SecretBytes> {
+    let first = shares.first().ok_or_else(|| Error::InvalidInput("No shares given.".to_string()))?;
+    for (i, share) in shares.iter().enumerate() {
+        if share.set_id != first.set_id || share.threshold != first.threshold || share.data.len() != first.data.len() {
+            return Err(Error::InvalidInput(format!("Share {} does not belong to the same set as share 1.", i + 1)));
+        }
+        if shares[..i].iter().any(|s| s.index == share.index) {
+            return Err(Error::InvalidInput(format!("Share number {} was given more than once.", share.index)));
+        }
+    }
+    if shares.len() < first.threshold as usize {
+        return Err(Error::InvalidInput(format!("{} shares are needed, only {} given.", first.threshold, shares.len())));
+    }
+    let (used, extra) = shares.split_at(first.threshold as usize);
+    let secret = interpolate(used, 0);
+    if set_id(&secret) != first.set_id {
+        return Err(Error::InvalidInput("The shares do not reconstruct a valid secret. At least one share is wrong.".to_string()));
+    }
+    // The secret is right, so the polynomial is; any further share has to lie on it.
+    for share in extra {
+        if interpolate(used, share.index) != share.data {
+            return Err(Error::InvalidInput(format!("Share number {} does not match the others. It is wrong.", share.index)));
+        }
+    }
+    Ok(secret)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum(body: &str) -> String {
+    hex(&Sha256::digest(body.as_bytes())[..4])
+}
+
+impl Share {
+    pub fn encode(&self) -> String {
+        let body = format!("{}-{}-{}-{}-{}", PREFIX, self.threshold, self.index, hex(&self.set_id), hex(&self.data));
+        let check = checksum(&body);
+        format!("{}-{}", body, check)
+    }
+
+    pub fn decode(text: &str) -> Result<Share> {
+        let text = Zeroizing::new(text.trim().to_lowercase());
+        let (body, check) = text
+            .rsplit_once('-')
+            .ok_or_else(|| Error::InvalidInput("Not a rustpass recovery share.".to_string()))?;
+        let parts: Vec<&str> = body.split('-').collect();
+        if parts.len() != 5 || !parts[0].eq_ignore_ascii_case(PREFIX) {
+            return Err(Error::InvalidInput("Not a rustpass recovery share.".to_string()));
+        }
+        let body = format!("{}-{}", PREFIX, parts[1..].join("-"));
+        if checksum(&body) != check {
+            return Err(Error::InvalidInput("Share checksum does not match. Check it for typos.".to_string()));
+        }
+        let invalid = || Error::InvalidInput("Share is malformed.".to_string());
+        let threshold: u8 = parts[1].parse().map_err(|_| invalid())?;
+        let index: u8 = parts[2].parse().map_err(|_| invalid())?;
+        let set_id: [u8; 4] = unhex(parts[3]).and_then(|b| b.try_into().ok()).ok_or_else(invalid)?;
+        let data = SecretBytes::new(unhex(parts[4]).ok_or_else(invalid)?);
+        if index == 0 || threshold < 2 {
+            return Err(invalid());
+        }
+        Ok(Share { threshold, index, set_id, data })
+    }
+}
@@ -0,0 +1,445 @@
+use crate::crypto::{decrypt_with_aad, encrypt_with_aad, random_key, random_nonce, KdfParams, MasterKey};
+use crate::error::{Error, Result};
+use crate::format::{KeySlot, SlotKind, VaultHeader};
+use crate::journal::Journal;
+use crate::model::{encode_payload, Entry, Vault, VersionedEntry, SCHEMA_VERSION};
+use crate::secret::{SecretBytes, SecretKey};
+use crate::vault::{self, Unlocked, MASTER_SLOT_LABEL};
+use crate::verify::{Problem, Report};
+use chrono::{DateTime, Utc};
+use ciborium::{ser, de};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+// A SQLite vault keeps each entry in its own row, encrypted under the vault's
+// data key, so changing one entry rewrites one row instead of the whole
+// vault. The key slots are stored in the clear in the `meta` row, next to the
+// encrypted vault metadata, which authenticates them as associated data.
+// Entry rows are bound to their id the same way, so rows cannot be swapped,
+// and the metadata holds a hash over all entry rows, so a row that is
+// deleted, added or rolled back to an older version is noticed on open.
+pub const SQLITE_EXTENSION: &str = "sqlite";
+
+const SCHEMA: &str = "
+    CREATE TABLE meta (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        key_slots BLOB NOT NULL,
+        nonce BLOB NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE TABLE entries (
+        id INTEGER PRIMARY KEY,
+        nonce BLOB NOT NULL,
+        data BLOB NOT NULL
+    );
+";
+const META_AAD: &[u8] = b"rustpass-sqlite-meta";
+const ENTRY_AAD: &[u8] = b"rustpass-sqlite-entry";
+const ROWS_DOMAIN: &[u8] = b"rustpass-sqlite-rows";
+
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    created_at: DateTime<Utc>,
+    last_modified: DateTime<Utc>,
+    // Hash over the ids and contents of all entry rows; missing in files
+    // written before it was added.
+    #[serde(default)]
+    rows: Option<[u8; 32]>,
+    // See `Vault::next_id`; missing in files written before it was added.
+    #[serde(default)]
+    next_id: Option<u32>,
+}
+
+/// Key of SQLite vault `name` in a store's data directory, next to the
+/// `vault_<name>.cbor` files.
+pub fn sqlite_key(name: &str) -> String {
+    format!("vault_{}.{}", name, SQLITE_EXTENSION)
+}
+
+/// An unlocked SQLite vault. Every change is written straight away, in its
+/// own transaction, and fails with `Error::Conflict` if anyone else wrote
+/// to the vault since this handle last read or wrote it.
+pub struct SqliteVault {
+    conn: Connection,
+    key_slots: Vec<KeySlot>,
+    // Index of the key slot the vault was unlocked with.
+    slot: usize,
+    data_key: SecretKey,
+    created_at: DateTime<Utc>,
+    last_modified: DateTime<Utc>,
+    // The id the next added entry gets; ids are never reused.
+    next_id: u32,
+    // Hash of every entry row by id, as the metadata row authenticates them.
+    rows: BTreeMap<u32, [u8; 32]>,
+    // The metadata row as last read or written. It is re-encrypted on every
+    // write, so a different one means someone else wrote to the vault.
+    meta: Vec<u8>,
+}
+
+impl SqliteVault {
+    pub fn create(path: &Path, master: &MasterKey) -> Result<SqliteVault> {
+        let data_key = random_key();
+        let slot = vault::new_slot(MASTER_SLOT_LABEL, SlotKind::Password, master, KdfParams::default(), &data_key)?;
+        let now = Utc::now();
+        SqliteVault::init(path, vec![slot], data_key, now, now, 1, &[])
+    }
+
+    pub fn open(path: &Path, master: &MasterKey) -> Result<SqliteVault> {
+        SqliteVault::open_with(path, master, SlotKind::Password)
+    }
+
+    // Opens the vault with a key slot of `kind` and checks the entry rows
+    // against the hash in the metadata row.
+    pub(crate) fn open_with(path: &Path, master: &MasterKey, kind: SlotKind) -> Result<SqliteVault> {
+        let conn = connect(path)?;
+        let MetaRow { key_slots, slots_bytes, nonce, data } = read_meta(&conn)?;
+        let (slot, data_key) = vault::unlock_slot(&key_slots, master, kind)?;
+        let plaintext = decrypt_with_aad(&data_key, &to_nonce(&nonce)?, &data, &meta_aad(&slots_bytes))
+            .map_err(|_| Error::Corrupt("vault metadata failed authentication".to_string()))?;
+        let meta: Meta = de::from_reader(plaintext.as_slice())?;
+        let rows = read_row_hashes(&conn)?;
+        if meta.rows.is_some_and(|digest| digest != rows_digest(&rows)) {
+            return Err(Error::Corrupt(
+                "the entry rows do not match the vault metadata; rows were deleted, added or rolled back".to_string(),
+            ));
+        }
+        let next_id = meta.next_id.unwrap_or_else(|| rows.keys().next_back().map_or(1, |id| id + 1));
+        Ok(SqliteVault {
+            conn,
+            key_slots,
+            slot,
+            data_key,
+            created_at: meta.created_at,
+            last_modified: meta.last_modified,
+            next_id,
+            rows,
+            meta: data,
+        })
+    }
+
+    /// Builds a SQLite vault at `path` from the contents of a single-blob
+    /// `vault_<name>.cbor` file. Key slots and the data key carry over, so the
+    /// same passphrases unlock both.
+    pub fn import(path: &Path, vault_data: &[u8], legacy_salt: Option<&[u8]>, master: &MasterKey) -> Result<SqliteVault> {
+        SqliteVault::from_unlocked(path, vault::unlock_blob(vault_data, legacy_salt, master)?)
+    }
+
+    pub(crate) fn from_unlocked(path: &Path, unlocked: Unlocked) -> Result<SqliteVault> {
+        let v = unlocked.vault;
+        let mut db = SqliteVault::init(path, unlocked.header.key_slots, unlocked.data_key, v.created_at, v.last_modified, v.next_id, &v.entries)?;
+        db.slot = unlocked.slot;
+        Ok(db)
+    }
+
+    /// The vault in the single-blob `vault_<name>.cbor` format, under the
+    /// same key slots.
+    pub fn export(&self) -> Result<Vec<u8>> {
+        let vault = Vault {
+            created_at: self.created_at,
+            last_modified: self.last_modified,
+            next_id: self.next_id,
+            entries: self.entries()?,
+        };
+        vault::encode_vault(self.key_slots.clone(), &self.data_key, &vault)
+    }
+
+    fn init(
+        path: &Path,
+        key_slots: Vec<KeySlot>,
+        data_key: SecretKey,
+        created_at: DateTime<Utc>,
+        last_modified: DateTime<Utc>,
+        next_id: u32,
+        entries: &[Entry],
+    ) -> Result<SqliteVault> {
+        if path.exists() {
+            return Err(Error::InvalidInput(format!("{} already exists.", path.display())));
+        }
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        conn.execute("INSERT INTO meta (id, key_slots, nonce, data) VALUES (0, x'', x'', x'')", [])?;
+        let mut db = SqliteVault {
+            conn,
+            key_slots: Vec::new(),
+            slot: 0,
+            data_key,
+            created_at,
+            last_modified,
+            next_id,
+            rows: BTreeMap::new(),
+            meta: Vec::new(),
+        };
+        // Leave no half-written vault behind.
+        if let Err(e) = db.write(entries, &[], key_slots, last_modified) {
+            drop(db);
+            let _ = fs::remove_file(path);
+            return Err(e);
+        }
+        Ok(db)
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.last_modified
+    }
+
+    pub fn key_slots(&self) -> &[KeySlot] {
+        &self.key_slots
+    }
+
+    /// All entries, ordered by id.
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let tx = self.conn.unchecked_transaction()?;
+        check_meta(&tx, &self.meta)?;
+        let mut stmt = tx.prepare("SELECT id, nonce, data FROM entries ORDER BY id")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, Vec<u8>>(2)?)))?;
+        let mut entries = Vec::new();
+        for row in rows {
+            let (id, nonce, data) = row?;
+            entries.push(self.decrypt_entry(id, &nonce, &data)?);
+        }
+        if entries.len() != self.rows.len() {
+            return Err(Error::Corrupt("entry rows were deleted".to_string()));
+        }
+        Ok(entries)
+    }
+
+    pub fn entry(&self, id: u32) -> Result<Option<Entry>> {
+        let tx = self.conn.unchecked_transaction()?;
+        check_meta(&tx, &self.meta)?;
+        let row: Option<(Vec<u8>, Vec<u8>)> = tx
+            .query_row("SELECT nonce, data FROM entries WHERE id = ?1", params![id], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?;
+        if row.is_none() && self.rows.contains_key(&id) {
+            return Err(Error::Corrupt(format!("entry row {} was deleted", id)));
+        }
+        row.map(|(nonce, data)| self.decrypt_entry(id, &nonce, &data)).transpose()
+    }
+
+    /// Adds `entry` under a new id and returns that id; ids of removed
+    /// entries are not used again. Its
+    /// timestamps that are not set yet are set to now.
+    pub fn add_entry(&mut self, mut entry: Entry) -> Result<u32> {
+        let now = Utc::now();
+        entry.created_at.get_or_insert(now);
+        entry.modified_at.get_or_insert(now);
+        if entry.password().is_some() {
+            entry.password_changed_at.get_or_insert(now);
+        }
+        entry.id = self.next_id;
+        self.write([&entry], &[], self.key_slots.clone(), now)?;
+        Ok(entry.id)
+    }
+
+    /// Replaces the stored entry with the same id, setting its
+    /// `modified_at` to now, and `password_changed_at` too if its password
+    /// changed.
+    pub fn update_entry(&mut self, entry: &Entry) -> Result<()> {
+        let old = self.entry(entry.id)?.ok_or_else(|| Error::NotFound(format!("entry {}", entry.id)))?;
+        let now = Utc::now();
+        let mut entry = entry.clone();
+        entry.modified_at = Some(now);
+        if entry.password() != old.password() {
+            entry.password_changed_at = Some(now);
+        }
+        self.write([&entry], &[], self.key_slots.clone(), now)
+    }
+
+    /// Removes the entry with `id` and says whether there was one.
+    pub fn remove_entry(&mut self, id: u32) -> Result<bool> {
+        if !self.rows.contains_key(&id) {
+            return Ok(false);
+        }
+        self.write([], &[id], self.key_slots.clone(), Utc::now())?;
+        Ok(true)
+    }
+
+    // The whole vault, for a `Session` that edits it in memory and hands
+    // the entries it changed back to `write`.
+    pub(crate) fn unlocked(&self) -> Result<Unlocked> {
+        let vault = Vault {
+            created_at: self.created_at,
+            last_modified: self.last_modified,
+            next_id: self.next_id,
+            entries: self.entries()?,
+        };
+        Ok(Unlocked {
+            // There is no blob, so no payload nonce.
+            header: VaultHeader { nonce: [0; 24], key_slots: self.key_slots.clone() },
+            slot: self.slot,
+            data_key: self.data_key.clone(),
+            vault,
+            schema: SCHEMA_VERSION,
+            journal: Journal::new(&[]),
+        })
+    }
+
+    // Writes `entries` over the rows with their ids, deletes the rows in
+    // `removed` and rewrites the metadata row with `key_slots`, all in one
+    // transaction. Rows that are not named are left alone.
+    pub(crate) fn write<'a>(
+        &mut self,
+        entries: impl IntoIterator<Item = &'a Entry>,
+        removed: &[u32],
+        key_slots: Vec<KeySlot>,
+        last_modified: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut rows = self.rows.clone();
+        let mut next_id = self.next_id;
+        let tx = self.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        check_meta(&tx, &self.meta)?;
+        for entry in entries {
+            let (nonce, data) = encrypt_entry(&self.data_key, entry)?;
+            tx.execute(
+                "INSERT OR REPLACE INTO entries (id, nonce, data) VALUES (?1, ?2, ?3)",
+                params![entry.id, nonce.as_slice(), data],
+            )?;
+            rows.insert(entry.id, row_hash(entry.id, &nonce, &data));
+            next_id = next_id.max(entry.id + 1);
+        }
+        for id in removed {
+            tx.execute("DELETE FROM entries WHERE id = ?1", params![id])?;
+            rows.remove(id);
+        }
+        let mut slots_bytes = Vec::new();
+        ser::into_writer(&key_slots, &mut slots_bytes)?;
+        let mut buf = SecretBytes::new(Vec::new());
+        let meta = Meta {
+            created_at: self.created_at,
+            last_modified,
+            rows: Some(rows_digest(&rows)),
+            next_id: Some(next_id),
+        };
+        ser::into_writer(&meta, &mut *buf)?;
+        let nonce = random_nonce();
+        let data = encrypt_with_aad(&self.data_key, &nonce, &buf, &meta_aad(&slots_bytes))?;
+        tx.execute(
+            "UPDATE meta SET key_slots = ?1, nonce = ?2, data = ?3 WHERE id = 0",
+            params![slots_bytes, nonce.as_slice(), data],
+        )?;
+        tx.commit()?;
+        self.key_slots = key_slots;
+        self.last_modified = last_modified;
+        self.next_id = next_id;
+        self.rows = rows;
+        self.meta = data;
+        Ok(())
+    }
+
+    fn decrypt_entry(&self, id: u32, nonce: &[u8], data: &[u8]) -> Result<Entry> {
+        if self.rows.get(&id) != Some(&row_hash(id, nonce, data)) {
+            return Err(Error::Corrupt(format!("entry row {} does not match the vault metadata", id)));
+        }
+        let plaintext = decrypt_with_aad(&self.data_key, &to_nonce(nonce)?, data, &entry_aad(id))
+            .map_err(|_| Error::Corrupt(format!("entry row {} failed authentication", id)))?;
+        Ok(VersionedEntry::decode(&plaintext)?.migrate())
+    }
+}
+
+/// Key slots of the SQLite vault at `path`, readable without unlocking it.
+pub fn read_key_slots(path: &Path) -> Result<Vec<KeySlot>> {
+    Ok(read_meta(&connect(path)?)?.key_slots)
+}
+
+// Checks the SQLite vault at `path` the way `verify::verify_vault` checks a
+// vault file: its metadata row and key slots, and with `master` also every
+// entry row. A `master` that does not open it is an error, not a problem.
+pub(crate) fn verify(path: &Path, key: &str, name: &str, master: Option<&MasterKey>) -> Result<Report> {
+    let checked = match master {
+        Some(master) => SqliteVault::open(path, master).and_then(|db| db.entries()).map(drop),
+        None => read_key_slots(path).map(drop),
+    };
+    let problems = match checked {
+        Ok(()) => Vec::new(),
+        Err(e @ (Error::Io(_) | Error::Locked(_) | Error::WrongPassword | Error::KeyfileRequired)) => return Err(e),
+        Err(e) => vec![Problem { key: key.to_string(), message: e.to_string() }],
+    };
+    Ok(Report { vault: name.to_string(), problems, good_backup: None })
+}
+
+fn connect(path: &Path) -> Result<Connection> {
+    if !path.exists() {
+        return Err(Error::NotFound(format!("SQLite vault {}", path.display())));
+    }
+    Ok(Connection::open(path)?)
+}
+
+// The metadata row, with its key slots decoded.
+struct MetaRow {
+    key_slots: Vec<KeySlot>,
+    slots_bytes: Vec<u8>,
+    nonce: Vec<u8>,
+    data: Vec<u8>,
+}
+
+fn read_meta(conn: &Connection) -> Result<MetaRow> {
+    let (slots_bytes, nonce, data): (Vec<u8>, Vec<u8>, Vec<u8>) = conn
+        .query_row("SELECT key_slots, nonce, data FROM meta WHERE id = 0", [], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })
+        .optional()?
+        .ok_or_else(|| Error::Corrupt("the SQLite vault has no metadata row".to_string()))?;
+    let key_slots = de::from_reader(slots_bytes.as_slice()).map_err(|e| Error::Corrupt(format!("key slots: {}", e)))?;
+    Ok(MetaRow { key_slots, slots_bytes, nonce, data })
+}
+
+// Fails with `Error::Conflict` unless the metadata row is still `meta`.
+fn check_meta(conn: &Connection, meta: &[u8]) -> Result<()> {
+    let data: Vec<u8> = conn.query_row("SELECT data FROM meta WHERE id = 0", [], |row| row.get(0))?;
+    if data != meta {
+        return Err(Error::Conflict);
+    }
+    Ok(())
+}
+
+fn read_row_hashes(conn: &Connection) -> Result<BTreeMap<u32, [u8; 32]>> {
+    let mut stmt = conn.prepare("SELECT id, nonce, data FROM entries")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, Vec<u8>>(2)?)))?;
+    let mut hashes = BTreeMap::new();
+    for row in rows {
+        let (id, nonce, data) = row?;
+        hashes.insert(id, row_hash(id, &nonce, &data));
+    }
+    Ok(hashes)
+}
+
+fn encrypt_entry(data_key: &[u8; 32], entry: &Entry) -> Result<([u8; 24], Vec<u8>)> {
+    let buf = encode_payload(entry)?;
+    let nonce = random_nonce();
+    let data = encrypt_with_aad(data_key, &nonce, &buf, &entry_aad(entry.id))?;
+    Ok((nonce, data))
+}
+
+fn row_hash(id: u32, nonce: &[u8], data: &[u8]) -> [u8; 32] {
+    Sha256::new().chain_update(id.to_le_bytes()).chain_update(nonce).chain_update(data).finalize().into()
+}
+
+fn rows_digest(rows: &BTreeMap<u32, [u8; 32]>) -> [u8; 32] {
+    let mut hasher = Sha256::new_with_prefix(ROWS_DOMAIN);
+    for (id, hash) in rows {
+        hasher.update(id.to_le_bytes());
+        hasher.update(hash);
+    }
+    hasher.finalize().into()
+}
+
+fn meta_aad(slots_bytes: &[u8]) -> Vec<u8> {
+    [META_AAD, slots_bytes].concat()
+}
+
+fn entry_aad(id: u32) -> Vec<u8> {
+    [ENTRY_AAD, &id.to_le_bytes()].concat()
+}
+
+fn to_nonce(bytes: &[u8]) -> Result<[u8; 24]> {
+    bytes.try_into().map_err(|_| Error::Corrupt("row nonce has the wrong length".to_string()))
+}
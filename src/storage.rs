@@ -0,0 +1,428 @@
+use crate::config::Config;
+use crate::error::{Error, Result};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions, TryLockError, create_dir_all, read_dir, remove_file, rename};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+/// Where encrypted vault blobs are kept. Keys are flat names such as
+/// `vault_<name>.cbor`; a backend only ever sees ciphertext, so it needs no
+/// knowledge of the vault format or the keys protecting it.
+pub trait StorageBackend: Send + Sync {
+    /// The blob stored under `key`, or `Error::NotFound`.
+    fn read(&self, key: &str) -> Result<Vec<u8>>;
+
+    /// Replaces the blob under `key` so that readers see either the old or
+    /// the new contents, never a mix. The contents being replaced are kept
+    /// as `<key>.bak.1`, shifting older generations up to `<key>.bak.<backups>`.
+    fn write(&self, key: &str, data: &[u8], backups: usize) -> Result<()>;
+
+    /// Adds `data` to the end of the blob under `key`, creating it if needed.
+    /// Unlike `write` this keeps no backup, and a crash may leave only part
+    /// of `data` behind.
+    fn append(&self, key: &str, data: &[u8]) -> Result<()> {
+        let mut blob = match self.read(key) {
+            Ok(blob) => blob,
+            Err(Error::NotFound(_)) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        blob.extend_from_slice(data);
+        self.write(key, &blob, 0)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool>;
+
+    /// Keys of every stored blob, in no particular order.
+    fn list(&self) -> Result<Vec<String>>;
+
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+
+    /// Removes the blob under `key`. Removing a missing blob is not an error.
+    fn delete(&self, key: &str) -> Result<()>;
+
+    /// Exclusive lock on `key` against other processes or threads. Locking a
+    /// key this caller already holds succeeds, so nested library calls do not
+    /// wait on themselves. Gives up with `Error::Locked` after the configured
+    /// timeout.
+    fn lock(&self, key: &str) -> Result<VaultLock>;
+
+    /// Settings for the vaults in this backend.
+    fn config(&self) -> Result<Config> {
+        Ok(Config::default())
+    }
+
+    /// The directory the blobs live in, for backends that have one.
+    fn local_dir(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Exclusive lock on a stored vault, released when dropped.
+pub struct VaultLock {
+    _guard: Box<dyn Send>,
+}
+
+/// Key of backup generation `generation` of the blob `key`.
+pub fn backup_key(key: &str, generation: usize) -> String {
+    format!("{}.bak.{}", key, generation)
+}
+
+/// The original layout: one file per blob in a data directory, next to its
+/// `config.toml`.
+#[derive(Debug, Clone)]
+pub struct FsBackend {
+    root: PathBuf,
+}
+
+impl FsBackend {
+    pub fn new(root: impl Into<PathBuf>) -> FsBackend {
+        FsBackend { root: root.into() }
+    }
+
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+impl StorageBackend for FsBackend {
+    fn read(&self, key: &str) -> Result<Vec<u8>> {
+        let path = self.path(key);
+        std::fs::read(&path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Error::NotFound(format!("vault file {}", path.display())),
+            _ => e.into(),
+        })
+    }
+
+    fn write(&self, key: &str, data: &[u8], backups: usize) -> Result<()> {
+        create_dir_all(&self.root)?;
+        replace_file(&self.path(key), data, backups)
+    }
+
+    fn append(&self, key: &str, data: &[u8]) -> Result<()> {
+        create_dir_all(&self.root)?;
+        let path = self.path(key);
+        let created = !path.exists();
+        let mut f = OpenOptions::new().create(true).append(true).open(&path)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+        if created {
+            sync_dir(&path)?;
+        }
+        Ok(())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.path(key).exists())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let entries = match read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(keys),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                keys.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        Ok(keys)
+    }
+
+    // Lock files stay where they are: the caller may still hold the lock, and
+    // unlinking it would let another process lock a fresh file alongside.
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        rename(self.path(from), self.path(to))?;
+        sync_dir(&self.path(to))
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        match remove_file(self.path(key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn lock(&self, key: &str) -> Result<VaultLock> {
+        create_dir_all(&self.root)?;
+        let path = self.path(key);
+        let guard = lock_file(&path, self.config()?.lock_timeout_ms)?;
+        Ok(VaultLock { _guard: Box::new(guard) })
+    }
+
+    fn config(&self) -> Result<Config> {
+        Config::load(&self.root)
+    }
+
+    fn local_dir(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+}
+
+// The new contents are fully written and synced to a sibling file before it
+// is renamed over the old one, so an interrupted write leaves either the old
+// or the new vault on disk, never a partial one. The generation being replaced
+// is kept as `<file>.bak.1`, shifting older ones up to `<file>.bak.<backups>`.
+pub(crate) fn replace_file(path: &Path, data: &[u8], backups: usize) -> Result<()> {
+    let mut tmp_name = path
+        .file_name()
+        .ok_or_else(|| Error::InvalidInput(format!("Invalid vault path {}", path.display())))?
+        .to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut f = File::create(&tmp_path)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    drop(f);
+    if path.exists() {
+        rotate_backups(path, backups)?;
+    }
+    rename(&tmp_path, path)?;
+    sync_dir(path)
+}
+
+pub fn backup_path(path: &Path, generation: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".bak.{}", generation));
+    PathBuf::from(name)
+}
+
+fn rotate_backups(path: &Path, backups: usize) -> Result<()> {
+    // Generations beyond the configured count, e.g. after lowering it.
+    let mut stale = backups.max(1);
+    while backup_path(path, stale).exists() {
+        remove_file(backup_path(path, stale))?;
+        stale += 1;
+    }
+    if backups == 0 {
+        return Ok(());
+    }
+    for generation in (1..backups).rev() {
+        let from = backup_path(path, generation);
+        if from.exists() {
+            rename(&from, backup_path(path, generation + 1))?;
+        }
+    }
+    // A hard link keeps the live file untouched until the rename replaces it.
+    let first = backup_path(path, 1);
+    if std::fs::hard_link(path, &first).is_err() {
+        std::fs::copy(path, &first)?;
+    }
+    Ok(())
+}
+
+// Makes the rename itself durable, not just the file contents.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+// Lock files currently held by this process. The owning thread taking the
+// lock again just bumps the count, so library calls made while a caller holds
+// the vault lock do not wait on themselves; other threads wait for it like
+// another process would.
+static HELD_LOCKS: Lazy<Mutex<HashMap<PathBuf, HeldLock>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct HeldLock {
+    owner: ThreadId,
+    // Carries the OS lock; closing it releases the lock.
+    _file: File,
+    guards: usize,
+}
+
+struct FileLockGuard {
+    path: PathBuf,
+}
+
+impl Drop for FileLockGuard {
+    fn drop(&mut self) {
+        let mut held = HELD_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = held.get_mut(&self.path) {
+            entry.guards -= 1;
+            if entry.guards == 0 {
+                held.remove(&self.path);
+            }
+        }
+    }
+}
+
+pub fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".lock");
+    PathBuf::from(name)
+}
+
+// Advisory lock on `<path>.lock`, polled until `timeout_ms` runs out.
+fn lock_file(path: &Path, timeout_ms: u64) -> Result<FileLockGuard> {
+    let lock_path = lock_path(path);
+    let me = thread::current().id();
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    let mut file = None;
+    loop {
+        {
+            let mut held = HELD_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+            match held.get_mut(&lock_path) {
+                Some(entry) if entry.owner == me => {
+                    entry.guards += 1;
+                    return Ok(FileLockGuard { path: lock_path });
+                }
+                // Another thread of this process holds it.
+                Some(_) => {}
+                None => {
+                    let f = match file.take() {
+                        Some(f) => f,
+                        None => OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)?,
+                    };
+                    match f.try_lock() {
+                        Ok(()) => {
+                            held.insert(lock_path.clone(), HeldLock { owner: me, _file: f, guards: 1 });
+                            return Ok(FileLockGuard { path: lock_path });
+                        }
+                        Err(TryLockError::WouldBlock) => file = Some(f),
+                        Err(TryLockError::Error(e)) => return Err(e.into()),
+                    }
+                }
+            }
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::Locked(path.display().to_string()));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Keeps every blob in memory. Clones share the same blobs and locks, which
+/// makes it handy for tests and for embedding rustpass without touching disk.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    blobs: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    // Lock holders: the thread owning each locked key and its guard count.
+    locks: Arc<Mutex<HashMap<String, (ThreadId, usize)>>>,
+    config: Config,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+
+    pub fn with_config(config: Config) -> MemoryBackend {
+        MemoryBackend { config, ..MemoryBackend::default() }
+    }
+
+    fn blobs(&self) -> std::sync::MutexGuard<'_, HashMap<String, Vec<u8>>> {
+        self.blobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct MemoryLockGuard {
+    key: String,
+    locks: Arc<Mutex<HashMap<String, (ThreadId, usize)>>>,
+}
+
+impl Drop for MemoryLockGuard {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = locks.get_mut(&self.key) {
+            entry.1 -= 1;
+            if entry.1 == 0 {
+                locks.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn read(&self, key: &str) -> Result<Vec<u8>> {
+        self.blobs()
+            .get(key)
+            .cloned()
+            .ok_or_else(|| Error::NotFound(format!("vault file {}", key)))
+    }
+
+    fn write(&self, key: &str, data: &[u8], backups: usize) -> Result<()> {
+        let mut blobs = self.blobs();
+        let mut stale = backups.max(1);
+        while blobs.remove(&backup_key(key, stale)).is_some() {
+            stale += 1;
+        }
+        if let Some(old) = blobs.insert(key.to_string(), data.to_vec()) {
+            if backups > 0 {
+                for generation in (1..backups).rev() {
+                    if let Some(blob) = blobs.remove(&backup_key(key, generation)) {
+                        blobs.insert(backup_key(key, generation + 1), blob);
+                    }
+                }
+                blobs.insert(backup_key(key, 1), old);
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.blobs().entry(key.to_string()).or_default().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.blobs().contains_key(key))
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(self.blobs().keys().cloned().collect())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut blobs = self.blobs();
+        let blob = blobs
+            .remove(from)
+            .ok_or_else(|| Error::NotFound(format!("vault file {}", from)))?;
+        blobs.insert(to.to_string(), blob);
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.blobs().remove(key);
+        Ok(())
+    }
+
+    fn lock(&self, key: &str) -> Result<VaultLock> {
+        let me = thread::current().id();
+        let deadline = Instant::now() + Duration::from_millis(self.config.lock_timeout_ms);
+        loop {
+            {
+                let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+                let entry = locks.entry(key.to_string()).or_insert((me, 0));
+                if entry.0 == me {
+                    entry.1 += 1;
+                    let guard = MemoryLockGuard { key: key.to_string(), locks: self.locks.clone() };
+                    return Ok(VaultLock { _guard: Box::new(guard) });
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Locked(key.to_string()));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn config(&self) -> Result<Config> {
+        Ok(self.config.clone())
+    }
+}
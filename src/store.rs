@@ -0,0 +1,722 @@
+use crate::backup::{self, Snapshot};
+use crate::config::{self, Config};
+use crate::crypto::{KdfParams, MasterKey};
+use crate::error::{Error, Result};
+use crate::format::KeySlot;
+use crate::journal::{self, Record};
+use crate::folders;
+use crate::model::{validate_tag, Entry, Vault};
+use crate::recovery;
+use crate::secret::SecretString;
+#[cfg(feature = "sqlite")]
+use crate::sqlite::{self, SqliteVault};
+use crate::storage::{backup_key, FsBackend, StorageBackend};
+use crate::urls::{self, MatchQuality};
+use crate::vault::{self, Location, Unlocked};
+use crate::verify::{self, Report};
+use chrono::{DateTime, TimeDelta, Utc};
+#[cfg(feature = "sqlite")]
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const CURRENT_VAULT_KEY: &str = ".current_vault";
+
+/// The vaults in one storage backend, by default a data directory.
+pub struct VaultStore {
+    storage: Arc<dyn StorageBackend>,
+}
+
+impl VaultStore {
+    pub fn open(root: impl Into<PathBuf>) -> Result<VaultStore> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(VaultStore::with_backend(FsBackend::new(root)))
+    }
+
+    /// The store in the per-user data directory.
+    pub fn open_default() -> Result<VaultStore> {
+        VaultStore::open(config::default_data_dir()?)
+    }
+
+    pub fn with_backend(storage: impl StorageBackend + 'static) -> VaultStore {
+        VaultStore { storage: Arc::new(storage) }
+    }
+
+    pub fn storage(&self) -> &dyn StorageBackend {
+        &*self.storage
+    }
+
+    pub fn config(&self) -> Result<Config> {
+        self.storage.config()
+    }
+
+    /// Names of the vaults in the store, sorted.
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self
+            .storage
+            .list()?
+            .iter()
+            .filter_map(|key| {
+                let name = key.strip_prefix("vault_")?;
+                #[cfg(feature = "sqlite")]
+                if let Some(name) = name.strip_suffix(sqlite::SQLITE_EXTENSION).and_then(|n| n.strip_suffix('.')) {
+                    return Some(name);
+                }
+                name.strip_suffix(".cbor")
+            })
+            .map(str::to_string)
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    pub fn exists(&self, name: &str) -> Result<bool> {
+        #[cfg(feature = "sqlite")]
+        if self.sqlite_file(name).is_some() {
+            return Ok(true);
+        }
+        self.storage.exists(&vault::vault_key(name))
+    }
+
+    // The file of vault `name` if it is a SQLite vault.
+    #[cfg(feature = "sqlite")]
+    fn sqlite_file(&self, name: &str) -> Option<PathBuf> {
+        let path = self.storage.local_dir()?.join(sqlite::sqlite_key(name));
+        path.exists().then_some(path)
+    }
+
+    fn require(&self, name: &str) -> Result<()> {
+        if self.exists(name)? {
+            Ok(())
+        } else {
+            Err(Error::NotFound(format!("vault '{}'", name)))
+        }
+    }
+
+    fn location(&self, name: &str) -> Location<'_> {
+        Location::named(&*self.storage, name)
+    }
+
+    pub fn create(&self, name: &str, master: &MasterKey) -> Result<()> {
+        validate_name(name)?;
+        if self.exists(name)? {
+            return Err(Error::InvalidInput(format!("Vault '{}' already exists.", name)));
+        }
+        vault::create_at(&self.location(name), master)
+    }
+
+    /// Renames a vault together with its backups, journal and legacy salt
+    /// file. The selection follows the vault.
+    pub fn rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        validate_name(new_name)?;
+        self.require(old_name)?;
+        if self.exists(new_name)? {
+            return Err(Error::InvalidInput(format!("Vault '{}' already exists.", new_name)));
+        }
+        let (old, new) = (self.location(old_name), self.location(new_name));
+        let _lock = old.lock()?;
+        #[cfg(feature = "sqlite")]
+        if self.sqlite_file(old_name).is_some() {
+            self.storage.rename(&sqlite::sqlite_key(old_name), &sqlite::sqlite_key(new_name))?;
+            return self.follow_rename(old_name, new_name);
+        }
+        let backups = vault::backup_keys(self.storage(), &old.key)?;
+        self.storage.rename(&old.key, &new.key)?;
+        for (i, backup) in backups.iter().enumerate() {
+            self.storage.rename(backup, &backup_key(&new.key, i + 1))?;
+        }
+        for (from, to) in [
+            (journal::journal_key(&old.key), journal::journal_key(&new.key)),
+            (journal::head_key(&old.key), journal::head_key(&new.key)),
+        ] {
+            if self.storage.exists(&from)? {
+                self.storage.rename(&from, &to)?;
+            }
+        }
+        if self.storage.exists(&old.salt_key)? {
+            self.storage.rename(&old.salt_key, &new.salt_key)?;
+        }
+        self.follow_rename(old_name, new_name)
+    }
+
+    fn follow_rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        if self.selected().as_deref() == Some(old_name) {
+            self.select(new_name)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes a vault and its backups after checking `master` unlocks it.
+    pub fn delete(&self, name: &str, master: &MasterKey) -> Result<()> {
+        self.require(name)?;
+        let at = self.location(name);
+        let _lock = at.lock()?;
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = self.sqlite_file(name) {
+            SqliteVault::open(&path, master)?;
+            self.storage.delete(&sqlite::sqlite_key(name))?;
+            return self.deselect(name);
+        }
+        vault::open_at(&at, master)?;
+        for backup in vault::backup_keys(self.storage(), &at.key)? {
+            self.storage.delete(&backup)?;
+        }
+        journal::remove(self.storage(), &at.key)?;
+        self.storage.delete(&at.key)?;
+        self.storage.delete(&at.salt_key)?;
+        self.deselect(name)
+    }
+
+    fn deselect(&self, name: &str) -> Result<()> {
+        if self.selected().as_deref() == Some(name) {
+            self.storage.delete(CURRENT_VAULT_KEY)?;
+        }
+        Ok(())
+    }
+
+    /// The vault chosen with `select`, if any.
+    pub fn selected(&self) -> Option<String> {
+        self.storage
+            .read(CURRENT_VAULT_KEY)
+            .ok()
+            .map(|s| String::from_utf8_lossy(&s).trim().to_string())
+            .filter(|s| !s.is_empty())
+    }
+
+    pub fn select(&self, name: &str) -> Result<()> {
+        self.require(name)?;
+        self.storage.write(CURRENT_VAULT_KEY, name.as_bytes(), 0)
+    }
+
+    pub fn unlock(&self, name: &str, master: &MasterKey) -> Result<Session> {
+        self.require(name)?;
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = self.sqlite_file(name) {
+            let db = SqliteVault::open(&path, master)?;
+            let unlocked = db.unlocked()?;
+            return Ok(Session {
+                name: name.to_string(),
+                storage: self.storage.clone(),
+                master: master.clone(),
+                loaded_at: unlocked.vault.last_modified,
+                rows: Some(Rows { stored: unlocked.vault.clone(), db }),
+                unlocked,
+                dirty: false,
+            });
+        }
+        let at = self.location(name);
+        let _lock = at.lock()?;
+        let unlocked = vault::open_at(&at, master)?;
+        Ok(Session {
+            name: name.to_string(),
+            storage: self.storage.clone(),
+            master: master.clone(),
+            loaded_at: unlocked.vault.last_modified,
+            unlocked,
+            dirty: false,
+            #[cfg(feature = "sqlite")]
+            rows: None,
+        })
+    }
+
+    /// Key slots of a vault, readable without unlocking it.
+    pub fn key_slots(&self, name: &str) -> Result<Vec<KeySlot>> {
+        self.require(name)?;
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = self.sqlite_file(name) {
+            return sqlite::read_key_slots(&path);
+        }
+        vault::key_slots_at(&self.location(name)).map_err(|e| match e {
+            Error::InvalidInput(_) => Error::InvalidInput(format!("Vault '{}' uses an older format. Unlock it once to upgrade it.", name)),
+            e => e,
+        })
+    }
+
+    /// Unlocks a vault with its recovery phrase and sets a new master key.
+    pub fn recover(&self, name: &str, phrase: &str, new_master: &MasterKey) -> Result<()> {
+        self.require(name)?;
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = self.sqlite_file(name) {
+            let mut db = SqliteVault::open_with(&path, &vault::recovery_key(phrase)?, crate::format::SlotKind::Recovery)?;
+            let mut unlocked = db.unlocked()?;
+            unlocked.reset_master(new_master)?;
+            let last_modified = db.last_modified();
+            return db.write([], &[], unlocked.header.key_slots, last_modified);
+        }
+        vault::recover_at(&self.location(name), phrase, new_master)
+    }
+
+    /// Folds a vault's journal into a new vault file and returns the number
+    /// of records folded. SQLite vaults have no journal, so there is nothing
+    /// to fold; `master` is still checked.
+    pub fn compact(&self, name: &str, master: &MasterKey) -> Result<usize> {
+        self.require(name)?;
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = self.sqlite_file(name) {
+            SqliteVault::open(&path, master)?;
+            return Ok(0);
+        }
+        vault::compact_at(&self.location(name), master)
+    }
+
+    /// Keys of the existing `.bak.N` generations of a vault, newest first.
+    pub fn backups(&self, name: &str) -> Result<Vec<String>> {
+        vault::backup_keys(self.storage(), &vault::vault_key(name))
+    }
+
+    /// Number of journal records written since vault `name` was last
+    /// compacted; restoring a backup drops them.
+    pub fn journal_records(&self, name: &str) -> Result<usize> {
+        journal::record_count(self.storage(), &vault::vault_key(name))
+    }
+
+    /// Makes backup generation `backup`, one of the keys `backups` returns,
+    /// the live vault file again. The file it replaces is kept as the newest
+    /// generation, and its journal, which only applies to it, is removed.
+    /// That loses the changes in the journal, so unless `discard_journal`
+    /// a vault with journal records is refused with `Error::InvalidInput`.
+    /// Returns the number of records dropped.
+    pub fn restore_backup(&self, name: &str, backup: &str, discard_journal: bool) -> Result<usize> {
+        let at = self.location(name);
+        let _lock = at.lock()?;
+        if !vault::backup_keys(self.storage(), &at.key)?.iter().any(|k| k == backup) {
+            return Err(Error::NotFound(format!("backup {} of vault '{}'", backup, name)));
+        }
+        let records = journal::record_count(self.storage(), &at.key)?;
+        if records > 0 && !discard_journal {
+            return Err(Error::InvalidInput(format!(
+                "Restoring {} would drop the {} journal record(s) saved to vault '{}' since it was last compacted.",
+                backup, records, name
+            )));
+        }
+        let vault_data = self.storage.read(backup)?;
+        journal::remove(self.storage(), &at.key)?;
+        self.storage.write(&at.key, &vault_data, self.config()?.backups)?;
+        Ok(records)
+    }
+
+    /// Checks a vault for damage without changing it; see
+    /// `verify::verify_vault`.
+    pub fn verify(&self, name: &str, master: Option<&MasterKey>) -> Result<Report> {
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = self.sqlite_file(name) {
+            return sqlite::verify(&path, &sqlite::sqlite_key(name), name, master);
+        }
+        verify::verify_vault(self.storage(), name, master)
+    }
+
+    /// Default folder for snapshots, inside the data directory.
+    pub fn backups_dir(&self) -> Result<PathBuf> {
+        let root = self.storage.local_dir().ok_or_else(|| {
+            Error::InvalidInput("This store has no data directory; give the snapshot folder explicitly.".to_string())
+        })?;
+        backup::backups_dir(root)
+    }
+
+    pub fn snapshot(&self, name: &str, out_dir: &Path) -> Result<PathBuf> {
+        #[cfg(feature = "sqlite")]
+        self.require_blob(name)?;
+        backup::create_snapshot(self.storage(), name, out_dir)
+    }
+
+    /// Restores a snapshot taken with password `master` as vault `name`;
+    /// see `backup::restore_snapshot`. `current` opens the vault it replaces.
+    pub fn restore(&self, snapshot: &Snapshot, name: &str, master: &MasterKey, current: &MasterKey) -> Result<Vault> {
+        validate_name(name)?;
+        #[cfg(feature = "sqlite")]
+        self.require_blob(name)?;
+        backup::restore_snapshot(self.storage(), snapshot, name, master, current)
+    }
+
+    /// Writes vault `name` to a new per-entry SQLite vault at `path`.
+    #[cfg(feature = "sqlite")]
+    pub fn export_sqlite(&self, name: &str, master: &MasterKey, path: &Path) -> Result<SqliteVault> {
+        self.require(name)?;
+        self.require_blob(name)?;
+        let at = self.location(name);
+        let _lock = at.lock()?;
+        SqliteVault::from_unlocked(path, vault::open_at(&at, master)?)
+    }
+
+    /// Creates vault `name` from the SQLite vault at `path`, keeping its key
+    /// slots.
+    #[cfg(feature = "sqlite")]
+    pub fn import_sqlite(&self, path: &Path, name: &str, master: &MasterKey) -> Result<()> {
+        validate_name(name)?;
+        if self.exists(name)? {
+            return Err(Error::InvalidInput(format!("Vault '{}' already exists.", name)));
+        }
+        let vault_data = SqliteVault::open(path, master)?.export()?;
+        let at = self.location(name);
+        let _lock = at.lock()?;
+        self.storage.write(&at.key, &vault_data, self.config()?.backups)
+    }
+
+    /// Creates vault `name` as a SQLite vault in the data directory. It keeps
+    /// each entry in its own row, so a commit writes only the entries it
+    /// changed instead of a journal record.
+    #[cfg(feature = "sqlite")]
+    pub fn create_sqlite(&self, name: &str, master: &MasterKey) -> Result<()> {
+        validate_name(name)?;
+        if self.exists(name)? {
+            return Err(Error::InvalidInput(format!("Vault '{}' already exists.", name)));
+        }
+        let _lock = self.location(name).lock()?;
+        SqliteVault::create(&self.sqlite_path(name)?, master)?;
+        Ok(())
+    }
+
+    /// Turns vault `name` into a SQLite vault with the same key slots and
+    /// entries. Its vault file, journal, backups and legacy salt file are
+    /// removed once the SQLite vault is written.
+    #[cfg(feature = "sqlite")]
+    pub fn convert_to_sqlite(&self, name: &str, master: &MasterKey) -> Result<()> {
+        self.require(name)?;
+        self.require_blob(name)?;
+        let at = self.location(name);
+        let _lock = at.lock()?;
+        SqliteVault::from_unlocked(&self.sqlite_path(name)?, vault::open_at(&at, master)?)?;
+        for backup in vault::backup_keys(self.storage(), &at.key)? {
+            self.storage.delete(&backup)?;
+        }
+        journal::remove(self.storage(), &at.key)?;
+        self.storage.delete(&at.key)?;
+        self.storage.delete(&at.salt_key)
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn sqlite_path(&self, name: &str) -> Result<PathBuf> {
+        let root = self.storage.local_dir().ok_or_else(|| {
+            Error::InvalidInput("This store has no data directory to keep a SQLite vault in.".to_string())
+        })?;
+        Ok(root.join(sqlite::sqlite_key(name)))
+    }
+
+    // Snapshots and conversions work on vault files only.
+    #[cfg(feature = "sqlite")]
+    fn require_blob(&self, name: &str) -> Result<()> {
+        if self.sqlite_file(name).is_some() {
+            return Err(Error::InvalidInput(format!("Vault '{}' is a SQLite vault; this works on vault files only.", name)));
+        }
+        Ok(())
+    }
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+        return Err(Error::InvalidInput(format!("'{}' is not a valid vault name.", name)));
+    }
+    Ok(())
+}
+
+/// An unlocked vault. The data key is derived once, in `VaultStore::unlock`;
+/// entry changes stay in memory until `commit` appends them to the vault's
+/// journal, while key slot changes are written straight away as a new vault
+/// file, which also folds in the journal and any uncommitted entry changes.
+/// The master key is only kept to re-wrap the data key in `set_kdf`.
+///
+/// A session on a SQLite vault has no journal: `commit` writes the rows of
+/// the entries that changed, and key slot changes rewrite only the
+/// metadata row.
+pub struct Session {
+    name: String,
+    storage: Arc<dyn StorageBackend>,
+    master: MasterKey,
+    unlocked: Unlocked,
+    loaded_at: DateTime<Utc>,
+    dirty: bool,
+    #[cfg(feature = "sqlite")]
+    rows: Option<Rows>,
+}
+
+// The SQLite vault behind a session, and its entries as last read or
+// written, which edits are diffed against to find the rows to write.
+#[cfg(feature = "sqlite")]
+struct Rows {
+    db: SqliteVault,
+    stored: Vault,
+}
+
+impl Session {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn vault(&self) -> &Vault {
+        &self.unlocked.vault
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.unlocked.vault.entries
+    }
+
+    pub fn entry(&self, id: u32) -> Option<&Entry> {
+        self.entries().iter().find(|e| e.id == id)
+    }
+
+    /// Entries whose name matches `name`, ignoring ASCII case.
+    pub fn entries_named(&self, name: &str) -> Vec<&Entry> {
+        self.entries().iter().filter(|e| e.name.eq_ignore_ascii_case(name)).collect()
+    }
+
+    /// Entries with a URI matching the page `url`, best match first.
+    pub fn find_url(&self, url: &str) -> Result<Vec<(&Entry, MatchQuality)>> {
+        urls::find(self.entries(), url)
+    }
+
+    /// Adds `entry` under a new id and returns that id. Ids of deleted
+    /// entries are never used again.
+    pub fn add_entry(&mut self, mut entry: Entry) -> u32 {
+        entry.id = self.unlocked.vault.allocate_id();
+        let id = entry.id;
+        self.unlocked.vault.entries.push(entry);
+        self.dirty = true;
+        id
+    }
+
+    pub fn entry_mut(&mut self, id: u32) -> Option<&mut Entry> {
+        let entry = self.unlocked.vault.entries.iter_mut().find(|e| e.id == id)?;
+        self.dirty = true;
+        Some(entry)
+    }
+
+    pub fn remove_entry(&mut self, id: u32) -> Option<Entry> {
+        let entries = &mut self.unlocked.vault.entries;
+        let index = entries.iter().position(|e| e.id == id)?;
+        self.dirty = true;
+        Some(entries.remove(index))
+    }
+
+    /// Removes every entry `entries_named(name)` would return and says how
+    /// many there were.
+    pub fn remove_entries_named(&mut self, name: &str) -> usize {
+        let entries = &mut self.unlocked.vault.entries;
+        let before = entries.len();
+        entries.retain(|e| !e.name.eq_ignore_ascii_case(name));
+        let removed = before - entries.len();
+        self.dirty |= removed > 0;
+        removed
+    }
+
+    /// Records that entry `id` was used, so `commit` sets its
+    /// `last_used_at`, and says whether there is such an entry.
+    pub fn mark_used(&mut self, id: u32) -> bool {
+        match self.entry_mut(id) {
+            Some(entry) => {
+                entry.last_used_at = Some(Utc::now());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves folder `from` with its subfolders under the path `to` and
+    /// returns how many entries moved.
+    pub fn move_folder(&mut self, from: &str, to: &str) -> Result<usize> {
+        let (from, to) = (folders::normalize(from)?, folders::normalize(to)?);
+        if folders::contains(&from, &to) {
+            return Err(Error::InvalidInput(format!("Folder '{}' cannot be moved into itself.", from)));
+        }
+        let mut moved = 0;
+        for entry in &mut self.unlocked.vault.entries {
+            if let Some(folder) = entry.folder.as_deref().and_then(|f| folders::reparent(f, &from, &to)) {
+                entry.folder = Some(folder);
+                moved += 1;
+            }
+        }
+        if moved == 0 {
+            return Err(Error::NotFound(format!("folder '{}'", from)));
+        }
+        self.dirty = true;
+        Ok(moved)
+    }
+
+    /// Puts the entries with the given ids in `folder`, or in no folder,
+    /// and returns how many were not there yet.
+    pub fn set_folder(&mut self, ids: &[u32], folder: Option<&str>) -> Result<usize> {
+        let folder = folder.map(folders::normalize).transpose()?;
+        let mut moved = 0;
+        for entry in self.unlocked.vault.entries.iter_mut().filter(|e| ids.contains(&e.id)) {
+            if entry.folder != folder {
+                entry.folder = folder.clone();
+                moved += 1;
+            }
+        }
+        self.dirty |= moved > 0;
+        Ok(moved)
+    }
+
+    /// Adds `tag` to the entries with the given ids and returns how many
+    /// did not have it yet.
+    pub fn tag_entries(&mut self, ids: &[u32], tag: &str) -> Result<usize> {
+        validate_tag(tag)?;
+        let mut tagged = 0;
+        for entry in self.unlocked.vault.entries.iter_mut().filter(|e| ids.contains(&e.id)) {
+            if entry.tags.insert(tag.to_string()) {
+                tagged += 1;
+            }
+        }
+        self.dirty |= tagged > 0;
+        Ok(tagged)
+    }
+
+    /// Removes `tag` from the entries with the given ids and returns how
+    /// many had it.
+    pub fn untag_entries(&mut self, ids: &[u32], tag: &str) -> usize {
+        let mut untagged = 0;
+        for entry in self.unlocked.vault.entries.iter_mut().filter(|e| ids.contains(&e.id)) {
+            if entry.tags.remove(tag) {
+                untagged += 1;
+            }
+        }
+        self.dirty |= untagged > 0;
+        untagged
+    }
+
+    /// Whether there are entry changes `commit` has not written yet.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Appends the entry changes to the vault's journal as one record. Fails
+    /// with `Error::Conflict`, writing nothing, if anyone else saved the
+    /// vault since this session loaded or last committed it.
+    pub fn commit(&mut self) -> Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Some(rows) = &self.rows {
+            if journal::diff(&rows.stored, &self.unlocked.vault).is_empty() {
+                self.dirty = false;
+                return Ok(());
+            }
+            return self.write_rows(|_| Ok(()));
+        }
+        let at = Location::named(&*self.storage, &self.name);
+        let _lock = at.lock()?;
+        let edited = vault::refresh_at(&at, &mut self.unlocked, self.loaded_at)?;
+        let changes = journal::diff(&self.unlocked.vault, &edited);
+        if !changes.is_empty() {
+            if let Err(e) = self.unlocked.append(&at, self.next_modified(), changes) {
+                self.unlocked.vault = edited;
+                return Err(e);
+            }
+        }
+        self.loaded_at = self.unlocked.vault.last_modified;
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Keeps `last_modified` moving forward, even if the clock does not, so
+    // every commit is seen as a change.
+    fn next_modified(&self) -> DateTime<Utc> {
+        Utc::now().max(self.loaded_at + TimeDelta::microseconds(1))
+    }
+
+    // Applies `change` to the vault as it is stored now and writes it as a
+    // new vault file, all under the vault lock.
+    fn write_with(&mut self, change: impl FnOnce(&mut Unlocked) -> Result<()>) -> Result<()> {
+        #[cfg(feature = "sqlite")]
+        if self.rows.is_some() {
+            return self.write_rows(change);
+        }
+        let at = Location::named(&*self.storage, &self.name);
+        let _lock = at.lock()?;
+        let edited = vault::refresh_at(&at, &mut self.unlocked, self.loaded_at)?;
+        if self.dirty {
+            // Applied like a journal record so the entries are stamped the same.
+            let record = Record { at: self.next_modified(), changes: journal::diff(&self.unlocked.vault, &edited) };
+            journal::apply(&mut self.unlocked.vault, &record)?;
+        }
+        change(&mut self.unlocked)?;
+        self.unlocked.write(&at)?;
+        self.loaded_at = self.unlocked.vault.last_modified;
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Applies `change` and writes the rows of the entries edited since the
+    // SQLite vault was last read or written, with the key slots, in one
+    // transaction. On failure the key slots are left as they were.
+    #[cfg(feature = "sqlite")]
+    fn write_rows(&mut self, change: impl FnOnce(&mut Unlocked) -> Result<()>) -> Result<()> {
+        let at = self.next_modified();
+        let rows = self.rows.as_mut().expect("a SQLite session has rows");
+        let record = Record { at, changes: journal::diff(&rows.stored, &self.unlocked.vault) };
+        let mut stored = rows.stored.clone();
+        journal::apply(&mut stored, &record)?;
+        let (header, slot) = (self.unlocked.header.clone(), self.unlocked.slot);
+        let ids: BTreeSet<u32> = record.changes.iter().map(|c| c.entry_id()).collect();
+        let removed: Vec<u32> = ids.iter().copied().filter(|id| !stored.entries.iter().any(|e| e.id == *id)).collect();
+        let changed = stored.entries.iter().filter(|e| ids.contains(&e.id));
+        let written = change(&mut self.unlocked)
+            .and_then(|()| rows.db.write(changed, &removed, self.unlocked.header.key_slots.clone(), stored.last_modified));
+        if let Err(e) = written {
+            self.unlocked.header = header;
+            self.unlocked.slot = slot;
+            return Err(e);
+        }
+        rows.stored = stored.clone();
+        self.unlocked.vault = stored;
+        self.loaded_at = self.unlocked.vault.last_modified;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// The journal records written since the vault file was last compacted,
+    /// oldest first.
+    pub fn history(&self) -> &[Record] {
+        &self.unlocked.journal.records
+    }
+
+    /// Folds the journal, and any uncommitted entry changes, into a new
+    /// vault file.
+    pub fn compact(&mut self) -> Result<()> {
+        self.write_with(|_| Ok(()))
+    }
+
+    pub fn key_slots(&self) -> &[KeySlot] {
+        &self.unlocked.header.key_slots
+    }
+
+    /// KDF parameters of the key slot this session was unlocked with.
+    pub fn kdf_params(&self) -> Result<KdfParams> {
+        self.unlocked.kdf()
+    }
+
+    pub fn set_kdf(&mut self, kdf: KdfParams) -> Result<()> {
+        let master = self.master.clone();
+        self.write_with(|u| u.rekey(&master, Some(kdf)))
+    }
+
+    /// Replaces the secret of the key slot this session was unlocked with.
+    pub fn change_password(&mut self, new_master: &MasterKey) -> Result<()> {
+        self.write_with(|u| u.rekey(new_master, None))?;
+        self.master = new_master.clone();
+        Ok(())
+    }
+
+    pub fn add_key_slot(&mut self, label: &str, new_secret: &MasterKey) -> Result<()> {
+        self.write_with(|u| u.add_key_slot(label, new_secret))
+    }
+
+    pub fn remove_key_slot(&mut self, label: &str) -> Result<()> {
+        self.write_with(|u| u.remove_key_slot(label))
+    }
+
+    /// Creates or replaces the recovery key slot and returns the phrase for it.
+    pub fn set_recovery(&mut self) -> Result<SecretString> {
+        let phrase = SecretString::new(recovery::encode_phrase(&recovery::generate_secret()));
+        self.set_recovery_phrase(phrase.expose())?;
+        Ok(phrase)
+    }
+
+    pub fn set_recovery_phrase(&mut self, phrase: &str) -> Result<()> {
+        self.write_with(|u| u.set_recovery_phrase(phrase))
+    }
+
+    pub fn revoke_recovery(&mut self) -> Result<()> {
+        self.write_with(|u| u.revoke_recovery())
+    }
+}
@@ -0,0 +1,123 @@
+use crate::error::{Error, Result};
+use crate::secret::SecretBytes;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+// Time-based one-time passwords (RFC 6238): the HMAC of the number of
+// `period`-second steps since the epoch, truncated to `digits` decimal digits.
+// Secrets are given as base32, on their own or inside the `otpauth://totp/`
+// URIs that authenticator QR codes hold.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+/// A TOTP generator. The secret is wiped from memory when dropped.
+pub struct Totp {
+    secret: SecretBytes,
+    pub algorithm: Algorithm,
+    pub digits: u32,
+    /// Seconds each code is valid for.
+    pub period: u64,
+}
+
+impl Totp {
+    /// Parses a base32 secret (case and spaces ignored) or an
+    /// `otpauth://totp/` URI. Missing URI parameters take the usual
+    /// defaults of SHA-1, 6 digits and 30 seconds.
+    pub fn parse(value: &str) -> Result<Totp> {
+        let invalid = |why: &str| Error::InvalidInput(format!("Not a TOTP secret: {}.", why));
+        let mut totp = Totp {
+            secret: SecretBytes::new(Vec::new()),
+            algorithm: Algorithm::Sha1,
+            digits: 6,
+            period: 30,
+        };
+        let value = value.trim();
+        let secret = match value.strip_prefix("otpauth://") {
+            None => value,
+            Some(rest) => {
+                let rest = rest.strip_prefix("totp/").ok_or_else(|| invalid("only otpauth://totp/ URIs are supported"))?;
+                let (_, query) = rest.split_once('?').ok_or_else(|| invalid("the URI has no secret"))?;
+                let mut secret = None;
+                for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+                    match key {
+                        "secret" => secret = Some(value),
+                        "algorithm" => {
+                            totp.algorithm = match value.to_ascii_uppercase().as_str() {
+                                "SHA1" => Algorithm::Sha1,
+                                "SHA256" => Algorithm::Sha256,
+                                "SHA512" => Algorithm::Sha512,
+                                _ => return Err(invalid("unknown algorithm")),
+                            }
+                        }
+                        "digits" => totp.digits = value.parse().map_err(|_| invalid("bad digits"))?,
+                        "period" => totp.period = value.parse().map_err(|_| invalid("bad period"))?,
+                        _ => {}
+                    }
+                }
+                secret.ok_or_else(|| invalid("the URI has no secret"))?
+            }
+        };
+        if !(6..=8).contains(&totp.digits) || totp.period == 0 {
+            return Err(invalid("codes must have 6 to 8 digits and a non-zero period"));
+        }
+        totp.secret = base32_decode(secret).ok_or_else(|| invalid("the secret is not base32"))?;
+        if totp.secret.is_empty() {
+            return Err(invalid("the secret is empty"));
+        }
+        Ok(totp)
+    }
+
+    /// The code valid at `at`.
+    pub fn code_at(&self, at: DateTime<Utc>) -> String {
+        let step = (at.timestamp().max(0) as u64 / self.period).to_be_bytes();
+        let mac = match self.algorithm {
+            Algorithm::Sha1 => hmac::<Hmac<Sha1>>(&self.secret, &step),
+            Algorithm::Sha256 => hmac::<Hmac<Sha256>>(&self.secret, &step),
+            Algorithm::Sha512 => hmac::<Hmac<Sha512>>(&self.secret, &step),
+        };
+        let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+        let truncated = u32::from_be_bytes(mac[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+        format!("{:0width$}", truncated % 10u32.pow(self.digits), width = self.digits as usize)
+    }
+
+    /// Seconds until the code valid at `at` changes.
+    pub fn remaining_at(&self, at: DateTime<Utc>) -> u64 {
+        self.period - at.timestamp().max(0) as u64 % self.period
+    }
+}
+
+fn hmac<M: Mac + hmac::digest::KeyInit>(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// RFC 4648 base32, without padding; authenticator apps print secrets in
+// groups and in either case. The output is allocated at its full size up
+// front so growing it never leaves a copy of the secret behind.
+fn base32_decode(s: &str) -> Option<SecretBytes> {
+    let mut out = SecretBytes::new(Vec::with_capacity(s.len() * 5 / 8));
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.chars().filter(|c| !matches!(c, ' ' | '-' | '=')) {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
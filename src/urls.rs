@@ -0,0 +1,131 @@
+use crate::error::{Error, Result};
+use crate::model::{Entry, EntryUri, UriMatch};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashSet;
+use url::{Host, Url};
+
+// Base-domain matching follows the public suffix list
+// (https://publicsuffix.org), its ICANN and private sections alike, so
+// `alice.github.io` and `bob.github.io` count as different sites. Rules are
+// kept in the ASCII (punycode) form hosts have once parsed, with their `*.`
+// or `!` prefix.
+static PUBLIC_SUFFIXES: Lazy<HashSet<String>> = Lazy::new(|| {
+    include_str!("public_suffix_list.dat")
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|rule| !rule.starts_with("//"))
+        .filter_map(|rule| {
+            let (prefix, domain) = match (rule.strip_prefix("*."), rule.strip_prefix('!')) {
+                (Some(domain), _) => ("*.", domain),
+                (_, Some(domain)) => ("!", domain),
+                _ => ("", rule),
+            };
+            match Host::parse(domain) {
+                Ok(Host::Domain(domain)) => Some(format!("{}{}", prefix, domain)),
+                _ => None,
+            }
+        })
+        .collect()
+});
+
+/// How closely a URI matches a page; later variants are closer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MatchQuality {
+    BaseDomain,
+    Regex,
+    Host,
+    Prefix,
+}
+
+impl MatchQuality {
+    pub fn label(&self) -> &'static str {
+        match self {
+            MatchQuality::BaseDomain => "same domain",
+            MatchQuality::Regex => "regex",
+            MatchQuality::Host => "same host",
+            MatchQuality::Prefix => "prefix",
+        }
+    }
+}
+
+/// Parses a URL; one without a scheme, such as `example.com/login`, is
+/// taken to be https.
+pub fn parse(s: &str) -> Result<Url> {
+    let url = match Url::parse(s) {
+        Ok(url) if url.has_host() => Ok(url),
+        _ if !s.contains("://") => Url::parse(&format!("https://{}", s)),
+        result => result,
+    };
+    match url {
+        Ok(url) if url.has_host() => Ok(url),
+        Ok(_) => Err(Error::InvalidInput(format!("'{}' has no host.", s))),
+        Err(e) => Err(Error::InvalidInput(format!("'{}' is not a URL: {}.", s, e))),
+    }
+}
+
+/// The registrable domain of `host`: its public suffix and the label before
+/// it, so `example.co.uk` for `login.example.co.uk`. `None` when the host is
+/// itself a public suffix.
+pub fn base_domain(host: &str) -> Option<String> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let labels: Vec<&str> = host.split('.').collect();
+    // A host under no listed suffix falls under the implicit `*` rule.
+    let mut suffix_labels = 1;
+    for i in 0..labels.len() {
+        let candidate = labels[i..].join(".");
+        if PUBLIC_SUFFIXES.contains(&format!("!{}", candidate)) {
+            suffix_labels = labels.len() - i - 1;
+            break;
+        }
+        let wildcard = i + 1 < labels.len() && PUBLIC_SUFFIXES.contains(&format!("*.{}", labels[i + 1..].join(".")));
+        if wildcard || PUBLIC_SUFFIXES.contains(&candidate) {
+            suffix_labels = labels.len() - i;
+            break;
+        }
+    }
+    (labels.len() > suffix_labels).then(|| labels[labels.len() - suffix_labels - 1..].join("."))
+}
+
+/// How `uri` matches `page`, if it does. A base-domain URI on the page's
+/// very host counts as a host match.
+pub fn match_uri(uri: &EntryUri, page: &Url) -> Option<MatchQuality> {
+    match uri.mode {
+        UriMatch::Never => None,
+        UriMatch::Regex => Regex::new(&uri.uri).ok()?.is_match(page.as_str()).then_some(MatchQuality::Regex),
+        UriMatch::Prefix => page.as_str().starts_with(parse(&uri.uri).ok()?.as_str()).then_some(MatchQuality::Prefix),
+        UriMatch::Host => {
+            let uri = parse(&uri.uri).ok()?;
+            let same = uri.host() == page.host() && uri.port_or_known_default() == page.port_or_known_default();
+            same.then_some(MatchQuality::Host)
+        }
+        UriMatch::BaseDomain => {
+            let uri = parse(&uri.uri).ok()?;
+            if uri.host() == page.host() {
+                return Some(MatchQuality::Host);
+            }
+            match (uri.host(), page.host()) {
+                (Some(Host::Domain(a)), Some(Host::Domain(b))) => {
+                    let base = base_domain(a)?;
+                    (Some(&base) == base_domain(b).as_ref()).then_some(MatchQuality::BaseDomain)
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+/// The entries with a URI matching `page`, best first: by match quality,
+/// then by the length of the matching URI, then by name.
+pub fn find<'a>(entries: &'a [Entry], page: &str) -> Result<Vec<(&'a Entry, MatchQuality)>> {
+    let page = parse(page)?;
+    let mut found: Vec<_> = entries
+        .iter()
+        .filter_map(|entry| {
+            let best = entry.uris.iter().filter_map(|uri| Some((match_uri(uri, &page)?, uri.uri.len()))).max()?;
+            Some((entry, best))
+        })
+        .collect();
+    found.sort_by(|(a, a_match), (b, b_match)| b_match.cmp(a_match).then_with(|| a.name.cmp(&b.name)));
+    Ok(found.into_iter().map(|(entry, (quality, _))| (entry, quality)).collect())
+}
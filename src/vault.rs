@@ -1,135 +1,574 @@
-use crate::model::VaultV1;
-use crate::crypto::{derive_key, encrypt, decrypt};
-use anyhow::Context;
-use chrono::Utc;
-use ciborium::{ser, de};
-use directories::ProjectDirs;
-use rand::RngCore;
-use std::fs::{File, create_dir_all};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use crate::model::{encode_payload, Vault, VersionedVault, SCHEMA_VERSION};
+use crate::crypto::{derive_key, derive_key_with, MasterKey, decrypt, decrypt_with_aad, encrypt_with_aad, random_key, random_nonce, random_salt, unwrap_key, wrap_key, KdfParams};
+use crate::format::{self, KeySlot, SlotKind, VaultHeader};
+use crate::journal::{self, Change, Journal, Record};
+use crate::recovery;
+use crate::secret::{SecretKey, SecretString};
+use crate::storage::{backup_key, lock_path, FsBackend, StorageBackend};
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
 
+pub use crate::storage::{backup_path, VaultLock};
+
+pub const MASTER_SLOT_LABEL: &str = "master";
+pub const RECOVERY_SLOT_LABEL: &str = "recovery";
 
 const VAULT_FILE: &str = "vault.cbor";
-#[allow(dead_code)]
-fn vault_path() -> anyhow::Result<PathBuf> {
-    let proj_dirs = ProjectDirs::from("com", "rustpass", "RustPass").context("Could not determine project directory")?;
-    let dir = proj_dirs.data_dir();
-    create_dir_all(dir)?;
-        Ok(dir.join(VAULT_FILE))
+const SALT_FILE: &str = "salt.bin";
+
+// One vault in a storage backend: its blob and, for vaults from before the
+// file header, the sibling salt blob.
+pub(crate) struct Location<'a> {
+    pub(crate) storage: &'a dyn StorageBackend,
+    pub(crate) key: String,
+    pub(crate) salt_key: String,
+}
+
+impl<'a> Location<'a> {
+    pub(crate) fn named(storage: &'a dyn StorageBackend, name: &str) -> Location<'a> {
+        Location {
+            storage,
+            key: vault_key(name),
+            salt_key: legacy_salt_key(name),
+        }
     }
 
-const SALT_FILE: &str = "salt.bin";
+    fn unnamed(storage: &'a dyn StorageBackend) -> Location<'a> {
+        Location {
+            storage,
+            key: VAULT_FILE.to_string(),
+            salt_key: SALT_FILE.to_string(),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> Result<VaultLock> {
+        self.storage.lock(&self.key)
+    }
+}
+
+#[allow(dead_code)]
+pub fn init(root: &Path, master: &MasterKey) -> Result<()> {
+    create_at(&Location::unnamed(&FsBackend::new(root)), master)
+}
+
 #[allow(dead_code)]
-fn salt_path() -> anyhow::Result<PathBuf> {
-    let proj_dirs = ProjectDirs::from("com", "rustpass", "RustPass").context("Could not determine project directory")?;
-    let dir = proj_dirs.data_dir();
-    create_dir_all(dir)?;
-    Ok(dir.join(SALT_FILE))
+pub fn load(root: &Path, master: &MasterKey) -> Result<Vault> {
+    load_at(&Location::unnamed(&FsBackend::new(root)), master)
 }
 
-#[allow(clippy::result_large_err)]
 #[allow(dead_code)]
-pub fn init(master: &str) -> anyhow::Result<()> {
-    let salt = {
-        let mut salt = [0u8; 16];
-        rand::thread_rng().fill_bytes(&mut salt);
-        salt
+pub fn save(root: &Path, master: &MasterKey, vault: &Vault) -> Result<()> {
+    save_at(&Location::unnamed(&FsBackend::new(root)), master, vault, None)
+}
+
+// A vault opened with one of its key slots. `slot` is the index of the slot
+// the supplied secret unwrapped; `vault` has the journal already applied and
+// is migrated to the current schema from `schema`, the one its blob is in.
+pub(crate) struct Unlocked {
+    pub(crate) header: VaultHeader,
+    pub(crate) slot: usize,
+    pub(crate) data_key: SecretKey,
+    pub(crate) vault: Vault,
+    pub(crate) schema: u32,
+    pub(crate) journal: Journal,
+}
+
+impl Unlocked {
+    fn own_slot(&self) -> Result<&KeySlot> {
+        self.header
+            .key_slots
+            .get(self.slot)
+            .ok_or_else(|| Error::InvalidInput("The key slot this vault was unlocked with has been removed.".to_string()))
+    }
+
+    pub(crate) fn kdf(&self) -> Result<KdfParams> {
+        Ok(self.own_slot()?.kdf)
+    }
+
+    // Replaces the key slot the vault was unlocked with by one for
+    // `new_master` and `kdf`. The data key and therefore the payload stay the
+    // same.
+    pub(crate) fn rekey(&mut self, new_master: &MasterKey, kdf: Option<KdfParams>) -> Result<()> {
+        let old = self.own_slot()?;
+        let slot = new_slot(&old.label, old.kind, new_master, kdf.unwrap_or(old.kdf), &self.data_key)?;
+        self.header.key_slots[self.slot] = slot;
+        Ok(())
+    }
+
+    pub(crate) fn add_key_slot(&mut self, label: &str, new_secret: &MasterKey) -> Result<()> {
+        if self.header.key_slots.iter().any(|s| s.label == label) {
+            return Err(Error::InvalidInput(format!("A key slot labelled '{}' already exists.", label)));
+        }
+        let slot = new_slot(label, SlotKind::Password, new_secret, self.kdf()?, &self.data_key)?;
+        self.header.key_slots.push(slot);
+        Ok(())
+    }
+
+    pub(crate) fn remove_key_slot(&mut self, label: &str) -> Result<()> {
+        let slots = &mut self.header.key_slots;
+        let index = slots
+            .iter()
+            .position(|s| s.label == label)
+            .ok_or_else(|| Error::NotFound(format!("key slot '{}'", label)))?;
+        let password_slots = slots.iter().filter(|s| s.kind == SlotKind::Password).count();
+        if slots[index].kind == SlotKind::Password && password_slots == 1 {
+            return Err(Error::InvalidInput("Refusing to remove the last key slot; the vault could never be unlocked again.".to_string()));
+        }
+        slots.remove(index);
+        // Keep `slot` pointing at the same slot; removing it leaves nothing to
+        // point at, which `own_slot` reports.
+        if index < self.slot {
+            self.slot -= 1;
+        } else if index == self.slot {
+            self.slot = usize::MAX;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_recovery_phrase(&mut self, phrase: &str) -> Result<()> {
+        let phrase = recovery_key(phrase)?;
+        let slot = new_slot(RECOVERY_SLOT_LABEL, SlotKind::Recovery, &phrase, KdfParams::default(), &self.data_key)?;
+        let own = self.own_slot().ok().map(|s| s.label.clone());
+        self.header.key_slots.retain(|s| s.kind != SlotKind::Recovery);
+        self.header.key_slots.push(slot);
+        self.relocate(own);
+        Ok(())
+    }
+
+    pub(crate) fn revoke_recovery(&mut self) -> Result<()> {
+        let before = self.header.key_slots.len();
+        let own = self.own_slot().ok().map(|s| s.label.clone());
+        self.header.key_slots.retain(|s| s.kind != SlotKind::Recovery);
+        if self.header.key_slots.len() == before {
+            return Err(Error::NotFound("recovery phrase".to_string()));
+        }
+        self.relocate(own);
+        Ok(())
+    }
+
+    // Replaces the master key slot, or adds one if there is none, with one
+    // for `new_master`.
+    pub(crate) fn reset_master(&mut self, new_master: &MasterKey) -> Result<()> {
+        let slots = &mut self.header.key_slots;
+        let kdf = slots
+            .iter()
+            .find(|s| s.label == MASTER_SLOT_LABEL)
+            .map(|s| s.kdf)
+            .unwrap_or_default();
+        let slot = new_slot(MASTER_SLOT_LABEL, SlotKind::Password, new_master, kdf, &self.data_key)?;
+        match slots.iter().position(|s| s.label == MASTER_SLOT_LABEL) {
+            Some(i) => slots[i] = slot,
+            None => slots.insert(0, slot),
+        }
+        Ok(())
+    }
+
+    // Swaps in `header`, as re-read from disk, keeping `slot` on the slot with
+    // the same label.
+    pub(crate) fn refresh_header(&mut self, header: VaultHeader) {
+        let own = self.own_slot().ok().map(|s| s.label.clone());
+        self.header = header;
+        self.relocate(own);
+    }
+
+    // Points `slot` at the slot labelled `label` after the slot list changed.
+    fn relocate(&mut self, label: Option<String>) {
+        self.slot = label
+            .and_then(|label| self.header.key_slots.iter().position(|s| s.label == label))
+            .unwrap_or(usize::MAX);
+    }
+
+    fn replay(&mut self, journal: Journal) -> Result<()> {
+        for record in &journal.records {
+            journal::apply(&mut self.vault, record)?;
+        }
+        self.journal = journal;
+        Ok(())
+    }
+
+    // Records `changes`, made at `time`, in the journal and applies them. A
+    // blob in an older schema is rewritten instead, so the first save after
+    // a migration stores the vault in the current one.
+    pub(crate) fn append(&mut self, at: &Location, time: DateTime<Utc>, changes: Vec<Change>) -> Result<()> {
+        let record = Record { at: time, changes };
+        journal::apply(&mut self.vault, &record)?;
+        if self.schema < SCHEMA_VERSION {
+            return self.write(at);
+        }
+        self.journal.append(at.storage, &at.key, &self.data_key, record)
+    }
+
+    // Writes the whole vault as a new blob with the journal folded in, then
+    // removes the journal. Replacing a blob in an older schema keeps it as a
+    // backup even when backups are turned off.
+    pub(crate) fn write(&mut self, at: &Location) -> Result<()> {
+        let keep_original = self.schema < SCHEMA_VERSION;
+        let vault_data = write_vault(at, self.header.key_slots.clone(), &self.data_key, &self.vault, keep_original)?;
+        journal::remove(at.storage, &at.key)?;
+        self.schema = SCHEMA_VERSION;
+        self.journal = Journal::new(&vault_data);
+        Ok(())
+    }
+}
+
+pub(crate) fn new_slot(label: &str, kind: SlotKind, master: &MasterKey, kdf: KdfParams, data_key: &[u8; 32]) -> Result<KeySlot> {
+    let salt = random_salt();
+    let kek = derive_key_with(master.password.expose(), master.keyfile_bytes(), &salt, &kdf)?;
+    let (wrapped_key, nonce) = wrap_key(&kek, data_key)?;
+    Ok(KeySlot {
+        label: label.to_string(),
+        kind,
+        keyfile: master.keyfile.is_some(),
+        kdf,
+        salt: salt.to_vec(),
+        nonce,
+        wrapped_key,
+    })
+}
+
+pub(crate) fn unlock_slot(key_slots: &[KeySlot], master: &MasterKey, kind: SlotKind) -> Result<(usize, SecretKey)> {
+    let mut needs_keyfile = false;
+    for (i, slot) in key_slots.iter().enumerate().filter(|(_, s)| s.kind == kind) {
+        let keyfile = match (slot.keyfile, master.keyfile_bytes()) {
+            (true, None) => {
+                needs_keyfile = true;
+                continue;
+            }
+            (true, keyfile) => keyfile,
+            (false, _) => None,
+        };
+        let kek = derive_key_with(master.password.expose(), keyfile, &slot.salt, &slot.kdf)?;
+        if let Ok(data_key) = unwrap_key(&kek, &slot.nonce, &slot.wrapped_key) {
+            return Ok((i, data_key));
+        }
+    }
+    if kind == SlotKind::Recovery {
+        return Err(Error::InvalidInput("The recovery phrase does not unlock this vault.".to_string()));
+    }
+    if needs_keyfile {
+        return Err(Error::KeyfileRequired);
+    }
+    Err(Error::WrongPassword)
+}
+
+/// Encrypts `vault` into the single-blob file format under `data_key`.
+pub(crate) fn encode_vault(key_slots: Vec<KeySlot>, data_key: &[u8; 32], vault: &Vault) -> Result<Vec<u8>> {
+    let header = VaultHeader {
+        nonce: random_nonce(),
+        key_slots,
     };
-    let key = derive_key(master, &salt);
-    let vault = VaultV1 {
+    let buf = encode_payload(vault)?;
+    let mut vault_data = format::encode_header(&header)?;
+    let ciphertext = encrypt_with_aad(data_key, &header.nonce, &buf, &vault_data)?;
+    vault_data.extend_from_slice(&ciphertext);
+    Ok(vault_data)
+}
+
+fn write_vault(at: &Location, key_slots: Vec<KeySlot>, data_key: &[u8; 32], vault: &Vault, keep_original: bool) -> Result<Vec<u8>> {
+    let vault_data = encode_vault(key_slots, data_key, vault)?;
+    let mut backups = at.storage.config()?.backups;
+    if keep_original {
+        backups = backups.max(1);
+    }
+    at.storage.write(&at.key, &vault_data, backups)?;
+    Ok(vault_data)
+}
+
+// Decodes a vault payload of any known schema, migrated to the current one,
+// along with the schema it was stored in.
+fn decode_vault(plaintext: &[u8]) -> Result<(Vault, u32)> {
+    let versioned = VersionedVault::decode(plaintext)?;
+    let schema = versioned.version();
+    Ok((versioned.migrate(), schema))
+}
+
+pub(crate) fn create_at(at: &Location, master: &MasterKey) -> Result<()> {
+    let _lock = at.lock()?;
+    let vault = Vault {
         created_at: Utc::now(),
         last_modified: Utc::now(),
+        next_id: 1,
         entries: Vec::new(),
     };
-    let mut buf = Vec::new();
-    ser::into_writer(&vault, &mut buf)?;
-    let (ciphertext, nonce) = encrypt(&key, &buf);
+    let data_key = random_key();
+    let slot = new_slot(MASTER_SLOT_LABEL, SlotKind::Password, master, KdfParams::default(), &data_key)?;
+    write_vault(at, vec![slot], &data_key, &vault, false)?;
+    Ok(())
+}
 
-    let mut vault_data = Vec::new();
-    vault_data.extend_from_slice(&nonce);
-    vault_data.extend_from_slice(&ciphertext);
+// Vaults written before the self-describing header existed are a bare
+// `nonce || ciphertext` blob with the Argon2 salt kept in a sibling file.
+fn read_legacy_salt(at: &Location) -> Result<[u8; 16]> {
+    at.storage
+        .read(&at.salt_key)
+        .ok()
+        .and_then(|salt| salt.get(..16).and_then(|s| s.try_into().ok()))
+        .ok_or_else(|| Error::Corrupt(format!("salt file {} is missing or unreadable", at.salt_key)))
+}
 
-    let vault_file = vault_path()?;
-    let mut f = File::create(vault_file)?;
-    f.write_all(&vault_data)?;
+fn open_legacy(vault_data: &[u8], salt: &[u8], master: &MasterKey) -> Result<Vault> {
+    let (nonce, ciphertext) = vault_data.split_at_checked(24).ok_or(Error::Truncated)?;
+    let nonce: &[u8; 24] = nonce.try_into().map_err(|_| Error::Truncated)?;
+    let key = derive_key(master.password.expose(), salt)?;
+    let plaintext = decrypt(&key, ciphertext, nonce)?;
+    Ok(decode_vault(&plaintext)?.0)
+}
 
-    let salt_file = salt_path()?;
-    let mut sf = File::create(salt_file)?;
-    sf.write_all(&salt)?;
+fn open_v1(file: &format::VaultFile, master: &MasterKey) -> Result<(Vault, KdfParams)> {
+    let header = file.header_v1()?;
+    let key = derive_key_with(master.password.expose(), None, &header.salt, &header.kdf)?;
+    let plaintext = decrypt_with_aad(&key, &header.nonce, &file.ciphertext, &file.aad)?;
+    Ok((decode_vault(&plaintext)?.0, header.kdf))
+}
 
-    Ok(())
+// Rewrites a vault from an older layout under a fresh data key, keeping the
+// KDF parameters the user had and the original file as a backup.
+fn migrate(at: &Location, master: &MasterKey, kdf: KdfParams, vault: Vault) -> Result<Unlocked> {
+    let data_key = random_key();
+    let slot = new_slot(MASTER_SLOT_LABEL, SlotKind::Password, master, kdf, &data_key)?;
+    let vault_data = write_vault(at, vec![slot], &data_key, &vault, true)?;
+    let header = format::decode(&vault_data)?.header()?;
+    let journal = Journal::new(&vault_data);
+    Ok(Unlocked { header, slot: 0, data_key, vault, schema: SCHEMA_VERSION, journal })
 }
 
-#[allow(clippy::result_large_err)]
-#[allow(dead_code)]
-pub fn load(master: &str) -> anyhow::Result<VaultV1> {
-    let salt_file = salt_path()?;
-    if !salt_file.exists() {
-        anyhow::bail!("Vault not found. Please initialize your vault first (option 1).");
+pub(crate) fn open_at(at: &Location, master: &MasterKey) -> Result<Unlocked> {
+    open_with(at, master, SlotKind::Password)
+}
+
+fn open_with(at: &Location, master: &MasterKey, kind: SlotKind) -> Result<Unlocked> {
+    let vault_data = at.storage.read(&at.key)?;
+
+    if !format::has_magic(&vault_data) {
+        if vault_data.len() < 24 {
+            return Err(Error::Truncated);
+        }
+        let salt = read_legacy_salt(at)?;
+        let vault = open_legacy(&vault_data, &salt, master)?;
+        let unlocked = migrate(at, master, KdfParams::default(), vault)?;
+        let _ = at.storage.delete(&at.salt_key);
+        return Ok(unlocked);
     }
-    let mut salt = [0u8; 16];
-    File::open(salt_file)?.read_exact(&mut salt)?;
 
-    let key = derive_key(master, &salt);
+    let file = format::decode(&vault_data)?;
+    if file.version == 1 {
+        let (vault, kdf) = open_v1(&file, master)?;
+        return migrate(at, master, kdf, vault);
+    }
+
+    let mut unlocked = open_v2(&vault_data, &file, master, kind)?;
+    let journal = Journal::read(at.storage, &at.key, &vault_data, &unlocked.data_key)?;
+    unlocked.replay(journal)?;
+    Ok(unlocked)
+}
 
-    let vault_file = vault_path()?;
-    if !vault_file.exists() {
-        anyhow::bail!("Vault not found. Please initialize your vault first (option 1).");
+// Opens the blob `vault_data`, decoded as `file`, without its journal.
+fn open_v2(vault_data: &[u8], file: &format::VaultFile, master: &MasterKey, kind: SlotKind) -> Result<Unlocked> {
+    let header = file.header()?;
+    let (slot, data_key) = unlock_slot(&header.key_slots, master, kind)?;
+    // The key slot already proved the secret right, so a failure here means
+    // the payload or header was damaged.
+    let plaintext = decrypt_with_aad(&data_key, &header.nonce, &file.ciphertext, &file.aad)
+        .map_err(|_| Error::Corrupt("vault contents failed authentication".to_string()))?;
+    let (vault, schema) = decode_vault(&plaintext)?;
+    let journal = Journal::new(vault_data);
+    Ok(Unlocked { header, slot, data_key, vault, schema, journal })
+}
+
+/// Decrypts the contents of a vault file of any format version without
+/// migrating or writing anything. `legacy_salt` is only needed for vaults
+/// that predate the file header.
+pub fn decrypt_vault(vault_data: &[u8], legacy_salt: Option<&[u8]>, master: &MasterKey) -> Result<Vault> {
+    if !format::has_magic(vault_data) {
+        if vault_data.len() < 24 {
+            return Err(Error::Truncated);
+        }
+        let salt = legacy_salt.ok_or_else(|| Error::Corrupt("legacy vault is missing its salt file".to_string()))?;
+        return open_legacy(vault_data, salt, master);
+    }
+    let file = format::decode(vault_data)?;
+    if file.version == 1 {
+        return Ok(open_v1(&file, master)?.0);
     }
-    let mut vault_data = Vec::new();
-    File::open(vault_file)?.read_to_end(&mut vault_data)?;
+    Ok(open_v2(vault_data, &file, master, SlotKind::Password)?.vault)
+}
 
-    let nonce = &vault_data[..24];
-    let ciphertext = &vault_data[24..];
+/// Like `decrypt_vault`, with the vault's journal and journal head, as
+/// stored next to it, applied on top.
+pub fn decrypt_vault_with_journal(
+    vault_data: &[u8],
+    legacy_salt: Option<&[u8]>,
+    journal: Option<&[u8]>,
+    journal_head: Option<&[u8]>,
+    master: &MasterKey,
+) -> Result<Vault> {
+    if journal.is_none() && journal_head.is_none() {
+        return decrypt_vault(vault_data, legacy_salt, master);
+    }
+    let file = format::decode(vault_data)?;
+    let mut unlocked = open_v2(vault_data, &file, master, SlotKind::Password)?;
+    let journal = Journal::parse(vault_data, journal, journal_head, &unlocked.data_key)?;
+    unlocked.replay(journal)?;
+    Ok(unlocked.vault)
+}
 
-    let plaintext = decrypt(&key, ciphertext, nonce.try_into().unwrap())?;
-    let vault: VaultV1 = de::from_reader(plaintext.as_slice())?;
-    Ok(vault)
+// Unlocks vault file contents of any format version without writing them.
+// Vaults from before key slots get a fresh data key wrapped for `master`,
+// just as `migrate` would give them.
+#[cfg(feature = "sqlite")]
+pub(crate) fn unlock_blob(vault_data: &[u8], legacy_salt: Option<&[u8]>, master: &MasterKey) -> Result<Unlocked> {
+    let (vault, kdf) = if format::has_magic(vault_data) {
+        let file = format::decode(vault_data)?;
+        if file.version == format::FORMAT_VERSION {
+            return open_v2(vault_data, &file, master, SlotKind::Password);
+        }
+        open_v1(&file, master)?
+    } else {
+        (decrypt_vault(vault_data, legacy_salt, master)?, KdfParams::default())
+    };
+    let data_key = random_key();
+    let slot = new_slot(MASTER_SLOT_LABEL, SlotKind::Password, master, kdf, &data_key)?;
+    let header = VaultHeader {
+        nonce: random_nonce(),
+        key_slots: vec![slot],
+    };
+    let journal = Journal::new(vault_data);
+    Ok(Unlocked { header, slot: 0, data_key, vault, schema: 1, journal })
 }
 
-#[allow(clippy::result_large_err)]
-#[allow(dead_code)]
-pub fn save(master: &str, vault: &VaultV1) -> anyhow::Result<()> {
-    let salt_file = salt_path()?;
-    let mut salt = [0u8; 16];
-    File::open(salt_file)?.read_exact(&mut salt)?;
+fn load_at(at: &Location, master: &MasterKey) -> Result<Vault> {
+    // Unlocking may migrate an old vault in place.
+    let _lock = at.lock()?;
+    Ok(open_at(at, master)?.vault)
+}
 
-    let key = derive_key(master, &salt);
+// Appends whatever differs between the stored vault and `vault` to the
+// journal, as of `vault.last_modified`. With `loaded_at`, the save is refused
+// if the stored vault no longer has the `last_modified` time the caller's
+// copy was loaded with.
+fn save_at(at: &Location, master: &MasterKey, vault: &Vault, loaded_at: Option<DateTime<Utc>>) -> Result<()> {
+    let _lock = at.lock()?;
+    let mut unlocked = open_at(at, master)?;
+    if loaded_at.is_some_and(|t| t != unlocked.vault.last_modified) {
+        return Err(Error::Conflict);
+    }
+    let changes = journal::diff(&unlocked.vault, vault);
+    if changes.is_empty() && vault.last_modified == unlocked.vault.last_modified {
+        return Ok(());
+    }
+    unlocked.append(at, vault.last_modified, changes)
+}
 
-    let mut buf = Vec::new();
-    ser::into_writer(vault, &mut buf)?;
-    let (ciphertext, nonce) = encrypt(&key, &buf);
+// Folds the journal into a new blob and says how many records it held. A
+// blob in an older schema is rewritten even without records.
+pub(crate) fn compact_at(at: &Location, master: &MasterKey) -> Result<usize> {
+    let _lock = at.lock()?;
+    let mut unlocked = open_at(at, master)?;
+    let records = unlocked.journal.records.len();
+    if records == 0 && unlocked.schema == SCHEMA_VERSION {
+        // Nothing to fold, but a stale or torn journal may be left over.
+        return journal::remove(at.storage, &at.key).map(|_| 0);
+    }
+    unlocked.write(at)?;
+    Ok(records)
+}
 
-    let mut vault_data = Vec::new();
-    vault_data.extend_from_slice(&nonce);
-    vault_data.extend_from_slice(&ciphertext);
+// Unlocks the vault at `at` under its lock, applies `change` and writes it.
+fn update_at(at: &Location, master: &MasterKey, change: impl FnOnce(&mut Unlocked) -> Result<()>) -> Result<()> {
+    let _lock = at.lock()?;
+    let mut unlocked = open_at(at, master)?;
+    change(&mut unlocked)?;
+    unlocked.write(at)
+}
+
+// Reloads `unlocked` with the header, vault and journal currently stored,
+// provided the vault still opens with its data key and nobody saved it since
+// it was loaded at `loaded_at`, and returns the vault `unlocked` held before.
+// Callers hold the vault lock.
+pub(crate) fn refresh_at(at: &Location, unlocked: &mut Unlocked, loaded_at: DateTime<Utc>) -> Result<Vault> {
+    let vault_data = at.storage.read(&at.key)?;
+    if !format::has_magic(&vault_data) {
+        return Err(Error::Conflict);
+    }
+    let file = format::decode(&vault_data)?;
+    if file.version != format::FORMAT_VERSION {
+        return Err(Error::Conflict);
+    }
+    let header = file.header()?;
+    let plaintext = decrypt_with_aad(&unlocked.data_key, &header.nonce, &file.ciphertext, &file.aad).map_err(|_| Error::Conflict)?;
+    let (mut stored, schema) = decode_vault(&plaintext)?;
+    let journal = Journal::read(at.storage, &at.key, &vault_data, &unlocked.data_key)?;
+    for record in &journal.records {
+        journal::apply(&mut stored, record)?;
+    }
+    if stored.last_modified != loaded_at {
+        return Err(Error::Conflict);
+    }
+    unlocked.refresh_header(header);
+    unlocked.schema = schema;
+    unlocked.journal = journal;
+    Ok(std::mem::replace(&mut unlocked.vault, stored))
+}
+
+pub(crate) fn key_slots_at(at: &Location) -> Result<Vec<KeySlot>> {
+    let vault_data = at.storage.read(&at.key)?;
+    if !format::has_magic(&vault_data) || format::decode(&vault_data)?.version < format::FORMAT_VERSION {
+        return Err(Error::InvalidInput("The vault uses an older format. Unlock it once to upgrade it.".to_string()));
+    }
+    Ok(format::decode(&vault_data)?.header()?.key_slots)
+}
 
-    let vault_file = vault_path()?;
-    let mut f = File::create(vault_file)?;
-    f.write_all(&vault_data)?;
+// Unlocks the vault with its recovery phrase and replaces the master key slot
+// with one for `new_master`.
+pub(crate) fn recover_at(at: &Location, phrase: &str, new_master: &MasterKey) -> Result<()> {
+    let phrase = recovery_key(phrase)?;
+    let _lock = at.lock()?;
+    let mut unlocked = open_with(at, &phrase, SlotKind::Recovery)?;
+    unlocked.reset_master(new_master)?;
+    unlocked.write(at)
+}
 
-    Ok(())
+// The secret a recovery phrase stands for, as the recovery slot wraps it.
+pub(crate) fn recovery_key(phrase: &str) -> Result<MasterKey> {
+    Ok(MasterKey {
+        password: SecretString::new(recovery::normalize_phrase(phrase)?),
+        keyfile: None,
+    })
 }
 
-pub fn find_entry<'a>(vault: &'a VaultV1, name: &str) -> Option<&'a crate::model::Entry> {
+/// Keys of the existing `.bak.N` generations of the blob `key`, newest first.
+pub fn backup_keys(storage: &dyn StorageBackend, key: &str) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    for generation in 1.. {
+        let backup = backup_key(key, generation);
+        if !storage.exists(&backup)? {
+            break;
+        }
+        keys.push(backup);
+    }
+    Ok(keys)
+}
+
+pub fn find_entry<'a>(vault: &'a Vault, name: &str) -> Option<&'a crate::model::Entry> {
     vault.entries.iter().find(|e| e.name.eq_ignore_ascii_case(name))
 }
 
 #[allow(dead_code)]
-pub fn find_entry_mut<'a>(vault: &'a mut VaultV1, name: &str) -> Option<&'a mut crate::model::Entry> {
+pub fn find_entry_mut<'a>(vault: &'a mut Vault, name: &str) -> Option<&'a mut crate::model::Entry> {
     vault.entries.iter_mut().find(|e| e.name.eq_ignore_ascii_case(name))
 }
 
 #[allow(dead_code)]
-pub fn remove_entry(vault: &mut VaultV1, name: &str) -> bool {
+pub fn remove_entry(vault: &mut Vault, name: &str) -> bool {
     let len_before = vault.entries.len();
     vault.entries.retain(|e| !e.name.eq_ignore_ascii_case(name));
     len_before != vault.entries.len()
 }
 
 #[allow(dead_code)]
-pub fn edit_entry(vault: &mut VaultV1, name: &str, new_entry: crate::model::Entry) -> bool {
+pub fn edit_entry(vault: &mut Vault, name: &str, new_entry: crate::model::Entry) -> bool {
     if let Some(entry) = find_entry_mut(vault, name) {
         *entry = new_entry;
         true
@@ -138,38 +577,111 @@ pub fn edit_entry(vault: &mut VaultV1, name: &str, new_entry: crate::model::Entr
     }
 }
 
-pub fn load_named(name: &str, master: &str) -> anyhow::Result<VaultV1> {
-    let proj_dirs = directories::ProjectDirs::from("com", "rustpass", "RustPass").unwrap();
-    let dir = proj_dirs.data_dir();
-    let salt_path = dir.join(format!("salt_{}.bin", name));
-    let vault_path = dir.join(format!("vault_{}.cbor", name));
-    let mut salt = [0u8; 16];
-    File::open(salt_path)?.read_exact(&mut salt)?;
-    let key = derive_key(master, &salt);
-    let mut vault_data = Vec::new();
-    File::open(vault_path)?.read_to_end(&mut vault_data)?;
-    let nonce = &vault_data[..24];
-    let ciphertext = &vault_data[24..];
-    let plaintext = decrypt(&key, ciphertext, nonce.try_into().unwrap())?;
-    let vault: VaultV1 = de::from_reader(plaintext.as_slice())?;
-    Ok(vault)
-}
-
-pub fn save_named(name: &str, master: &str, vault: &VaultV1) -> anyhow::Result<()> {
-    let proj_dirs = directories::ProjectDirs::from("com", "rustpass", "RustPass").unwrap();
-    let dir = proj_dirs.data_dir();
-    let salt_path = dir.join(format!("salt_{}.bin", name));
-    let vault_path = dir.join(format!("vault_{}.cbor", name));
-    let mut salt = [0u8; 16];
-    File::open(salt_path)?.read_exact(&mut salt)?;
-    let key = derive_key(master, &salt);
-    let mut buf = Vec::new();
-    ser::into_writer(vault, &mut buf)?;
-    let (ciphertext, nonce) = encrypt(&key, &buf);
-    let mut vault_data = Vec::new();
-    vault_data.extend_from_slice(&nonce);
-    vault_data.extend_from_slice(&ciphertext);
-    let mut f = File::create(vault_path)?;
-    f.write_all(&vault_data)?;
-    Ok(())
+pub fn vault_key(name: &str) -> String {
+    format!("vault_{}.cbor", name)
+}
+
+pub fn legacy_salt_key(name: &str) -> String {
+    format!("salt_{}.bin", name)
+}
+
+pub fn vault_file_path(root: &Path, name: &str) -> Result<PathBuf> {
+    create_dir_all(root)?;
+    Ok(root.join(vault_key(name)))
+}
+
+/// Existing `.bak.N` generations of a named vault, newest first.
+pub fn backup_files_named(root: &Path, name: &str) -> Result<Vec<PathBuf>> {
+    let fs = FsBackend::new(root);
+    Ok(backup_keys(&fs, &vault_key(name))?.iter().map(|key| fs.path(key)).collect())
+}
+
+pub fn legacy_salt_path(root: &Path, name: &str) -> Result<PathBuf> {
+    create_dir_all(root)?;
+    Ok(root.join(legacy_salt_key(name)))
+}
+
+pub fn create_named(root: &Path, name: &str, master: &MasterKey) -> Result<()> {
+    create_at(&Location::named(&FsBackend::new(root), name), master)
+}
+
+pub fn kdf_params_named(root: &Path, name: &str, master: &MasterKey) -> Result<KdfParams> {
+    let fs = FsBackend::new(root);
+    let at = Location::named(&fs, name);
+    let _lock = at.lock()?;
+    open_at(&at, master)?.kdf()
+}
+
+pub fn set_kdf_named(root: &Path, name: &str, master: &MasterKey, kdf: KdfParams) -> Result<()> {
+    update_at(&Location::named(&FsBackend::new(root), name), master, |u| u.rekey(master, Some(kdf)))
+}
+
+pub fn change_password_named(root: &Path, name: &str, old_master: &MasterKey, new_master: &MasterKey) -> Result<()> {
+    update_at(&Location::named(&FsBackend::new(root), name), old_master, |u| u.rekey(new_master, None))
+}
+
+pub fn key_slots_named(root: &Path, name: &str) -> Result<Vec<KeySlot>> {
+    key_slots_at(&Location::named(&FsBackend::new(root), name)).map_err(|e| match e {
+        Error::InvalidInput(_) => Error::InvalidInput(format!("Vault '{}' uses an older format. Unlock it once to upgrade it.", name)),
+        e => e,
+    })
+}
+
+pub fn add_key_slot_named(root: &Path, name: &str, master: &MasterKey, label: &str, new_secret: &MasterKey) -> Result<()> {
+    update_at(&Location::named(&FsBackend::new(root), name), master, |u| u.add_key_slot(label, new_secret))
+}
+
+pub fn remove_key_slot_named(root: &Path, name: &str, master: &MasterKey, label: &str) -> Result<()> {
+    update_at(&Location::named(&FsBackend::new(root), name), master, |u| u.remove_key_slot(label))
+}
+
+// Creates or replaces the recovery key slot and returns the phrase for it.
+pub fn set_recovery_named(root: &Path, name: &str, master: &MasterKey) -> Result<SecretString> {
+    let phrase = SecretString::new(recovery::encode_phrase(&recovery::generate_secret()));
+    set_recovery_phrase_named(root, name, master, phrase.expose())?;
+    Ok(phrase)
+}
+
+pub fn set_recovery_phrase_named(root: &Path, name: &str, master: &MasterKey, phrase: &str) -> Result<()> {
+    update_at(&Location::named(&FsBackend::new(root), name), master, |u| u.set_recovery_phrase(phrase))
+}
+
+pub fn revoke_recovery_named(root: &Path, name: &str, master: &MasterKey) -> Result<()> {
+    update_at(&Location::named(&FsBackend::new(root), name), master, |u| {
+        u.revoke_recovery()
+            .map_err(|_| Error::NotFound(format!("recovery phrase for vault '{}'", name)))
+    })
+}
+
+pub fn recover_named(root: &Path, name: &str, phrase: &str, new_master: &MasterKey) -> Result<()> {
+    recover_at(&Location::named(&FsBackend::new(root), name), phrase, new_master)
+}
+
+pub fn load_named(root: &Path, name: &str, master: &MasterKey) -> Result<Vault> {
+    load_at(&Location::named(&FsBackend::new(root), name), master)
+}
+
+pub fn save_named(root: &Path, name: &str, master: &MasterKey, vault: &Vault) -> Result<()> {
+    save_at(&Location::named(&FsBackend::new(root), name), master, vault, None)
+}
+
+/// Saves `vault` only if nobody else saved the vault since it was loaded,
+/// i.e. its `last_modified` on disk is still `loaded_at`.
+pub fn save_named_checked(root: &Path, name: &str, master: &MasterKey, vault: &Vault, loaded_at: DateTime<Utc>) -> Result<()> {
+    save_at(&Location::named(&FsBackend::new(root), name), master, vault, Some(loaded_at))
+}
+
+/// Folds the named vault's journal into a new vault file and returns the
+/// number of records folded.
+pub fn compact_named(root: &Path, name: &str, master: &MasterKey) -> Result<usize> {
+    compact_at(&Location::named(&FsBackend::new(root), name), master)
+}
+
+pub fn lock_file_path(root: &Path, name: &str) -> Result<PathBuf> {
+    Ok(lock_path(&vault_file_path(root, name)?))
+}
+
+/// Holds the named vault's lock for a whole read-modify-write cycle.
+pub fn lock_named(root: &Path, name: &str) -> Result<VaultLock> {
+    FsBackend::new(root).lock(&vault_key(name))
 }
@@ -0,0 +1,300 @@
+use crate::crypto::{decrypt, decrypt_with_aad, derive_key, derive_key_with, MasterKey};
+use crate::error::{Error, Result};
+use crate::format::{self, SlotKind, VaultFile};
+use crate::journal::{self, read_optional, Journal};
+use crate::model::{Vault, VersionedVault};
+use crate::storage::StorageBackend;
+use crate::vault;
+use std::collections::HashSet;
+use std::fmt;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+const WRAPPED_KEY_LEN: usize = 32 + TAG_LEN;
+
+/// One thing wrong with a stored vault.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// Key of the blob the problem was found in.
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+/// What `verify_vault` found.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub vault: String,
+    pub problems: Vec<Problem>,
+    /// The newest `.bak.N` generation that passes the same checks, looked
+    /// for only when the vault itself does not.
+    pub good_backup: Option<String>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks vault `name` without changing anything: that its file is there and
+/// of a sane size, its header and key slots, and the salt file of vaults from
+/// before the header. With `master`, the payload and journal must also
+/// authenticate and decode. Damage is reported in the `Report`; errors are
+/// left for failing to read or lock the vault, and for a `master` that does
+/// not open it (`Error::WrongPassword`), which is no sign of damage.
+pub fn verify_vault(storage: &dyn StorageBackend, name: &str, master: Option<&MasterKey>) -> Result<Report> {
+    let key = vault::vault_key(name);
+    let _lock = storage.lock(&key)?;
+    let salt_key = vault::legacy_salt_key(name);
+    let legacy_salt = read_optional(storage, &salt_key)?;
+    let mut checker = Checker {
+        salt_key,
+        legacy_salt: legacy_salt.as_deref(),
+        master,
+        problems: Vec::new(),
+        locked_out: None,
+    };
+    match read_optional(storage, &key)? {
+        Some(data) => {
+            let journal = read_optional(storage, &journal::journal_key(&key))?;
+            let head = read_optional(storage, &journal::head_key(&key))?;
+            checker.check_file(&key, &data, Some((journal.as_deref(), head.as_deref())));
+        }
+        None => checker.problem(&key, "the vault file is missing"),
+    }
+    if let Some(e) = checker.locked_out.take() {
+        return Err(e);
+    }
+    let mut report = Report {
+        vault: name.to_string(),
+        problems: std::mem::take(&mut checker.problems),
+        good_backup: None,
+    };
+    if report.is_ok() {
+        return Ok(report);
+    }
+    // Backups are whole vault files written at compaction, with no journal.
+    for backup in vault::backup_keys(storage, &key)? {
+        checker.check_file(&backup, &storage.read(&backup)?, None);
+        if std::mem::take(&mut checker.problems).is_empty() {
+            report.good_backup = Some(backup);
+            break;
+        }
+    }
+    Ok(report)
+}
+
+// The journal and journal head of a live vault, where present.
+type JournalFiles<'a> = (Option<&'a [u8]>, Option<&'a [u8]>);
+
+struct Checker<'a> {
+    salt_key: String,
+    legacy_salt: Option<&'a [u8]>,
+    master: Option<&'a MasterKey>,
+    problems: Vec<Problem>,
+    // Why `master` did not open the live vault file.
+    locked_out: Option<Error>,
+}
+
+impl Checker<'_> {
+    fn problem(&mut self, key: &str, message: impl Into<String>) {
+        self.problems.push(Problem { key: key.to_string(), message: message.into() });
+    }
+
+    // The live vault file failing to open with a well-formed header or salt
+    // file most likely means the password is wrong, which is not damage; a
+    // backup that does not open is reported like any other problem.
+    fn locked_out(&mut self, key: &str, live: bool, error: Error, message: impl Into<String>) {
+        if live {
+            self.locked_out = Some(error);
+        } else {
+            self.problem(key, message);
+        }
+    }
+
+    fn check_file(&mut self, key: &str, data: &[u8], journal: Option<JournalFiles>) {
+        let vault = if format::has_magic(data) {
+            self.check_headered(key, data, journal)
+        } else {
+            self.check_legacy(key, data, journal.is_some())
+        };
+        if let Some(vault) = vault {
+            let mut ids = HashSet::new();
+            for entry in &vault.entries {
+                if !ids.insert(entry.id) {
+                    self.problem(key, format!("more than one entry has id {}", entry.id));
+                }
+            }
+        }
+    }
+
+    fn check_legacy(&mut self, key: &str, data: &[u8], live: bool) -> Option<Vault> {
+        if data.is_empty() {
+            self.problem(key, "the file is empty");
+            return None;
+        }
+        if data.len() < NONCE_LEN + TAG_LEN {
+            self.problem(key, format!("the file is {} bytes, too short to hold a vault", data.len()));
+            return None;
+        }
+        let salt_key = self.salt_key.clone();
+        let salt = match self.legacy_salt {
+            None => {
+                self.problem(&salt_key, "the salt file this vault (from before the file header) needs is missing");
+                return None;
+            }
+            Some(salt) if salt.len() != SALT_LEN => {
+                self.problem(&salt_key, format!("the salt file is {} bytes instead of {}", salt.len(), SALT_LEN));
+                return None;
+            }
+            Some(salt) => salt,
+        };
+        let master = self.master?;
+        let derived = match derive_key(master.password.expose(), salt) {
+            Ok(derived) => derived,
+            Err(e) => {
+                self.problem(key, e.to_string());
+                return None;
+            }
+        };
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        match decrypt(&derived, ciphertext, nonce.try_into().ok()?) {
+            Ok(plaintext) => self.decode_payload(key, &plaintext),
+            Err(_) => {
+                self.locked_out(key, live, Error::WrongPassword, "the payload does not decrypt: the password or salt file is wrong, or the file is damaged");
+                None
+            }
+        }
+    }
+
+    fn check_headered(&mut self, key: &str, data: &[u8], journal: Option<JournalFiles>) -> Option<Vault> {
+        let file = match format::decode(data) {
+            Ok(file) => file,
+            Err(Error::Truncated) => {
+                self.problem(key, format!("the file is truncated: its {} bytes end inside the header", data.len()));
+                return None;
+            }
+            Err(Error::UnsupportedVersion(version)) => {
+                self.problem(key, format!("unsupported format version {}", version));
+                return None;
+            }
+            Err(e) => {
+                self.problem(key, e.to_string());
+                return None;
+            }
+        };
+        if file.ciphertext.len() < TAG_LEN {
+            self.problem(key, format!("the payload is {} bytes, too short to be authenticated", file.ciphertext.len()));
+            return None;
+        }
+        if file.version == 1 {
+            return self.check_v1(key, &file, journal.is_some());
+        }
+        let header = match file.header() {
+            Ok(header) => header,
+            Err(e) => {
+                self.problem(key, e.to_string());
+                return None;
+            }
+        };
+        if header.key_slots.is_empty() {
+            self.problem(key, "the header has no key slots");
+            return None;
+        }
+        for slot in &header.key_slots {
+            if slot.salt.len() != SALT_LEN {
+                self.problem(key, format!("key slot '{}' has a {}-byte salt instead of {}", slot.label, slot.salt.len(), SALT_LEN));
+            }
+            if slot.wrapped_key.len() != WRAPPED_KEY_LEN {
+                self.problem(key, format!("key slot '{}' holds a {}-byte wrapped key instead of {}", slot.label, slot.wrapped_key.len(), WRAPPED_KEY_LEN));
+            }
+        }
+        if !header.key_slots.iter().any(|s| s.kind == SlotKind::Password) {
+            self.problem(key, "no passphrase key slot is left; only the recovery phrase opens the vault");
+        }
+        let master = self.master?;
+        let data_key = match vault::unlock_slot(&header.key_slots, master, SlotKind::Password) {
+            Ok((_, data_key)) => data_key,
+            Err(e @ (Error::WrongPassword | Error::KeyfileRequired)) => {
+                let message = format!("no key slot opens: {}", e);
+                self.locked_out(key, journal.is_some(), e, message);
+                return None;
+            }
+            Err(e) => {
+                self.problem(key, format!("no key slot opens: {}", e));
+                return None;
+            }
+        };
+        let Ok(plaintext) = decrypt_with_aad(&data_key, &header.nonce, &file.ciphertext, &file.aad) else {
+            self.problem(key, "the vault contents failed authentication: the file was damaged or tampered with");
+            return None;
+        };
+        let mut vault = self.decode_payload(key, &plaintext)?;
+        if let Some((records, head)) = journal {
+            let journal_key = journal::journal_key(key);
+            match Journal::parse(data, records, head, &data_key) {
+                Ok(journal) => {
+                    for record in &journal.records {
+                        if let Err(e) = journal::apply(&mut vault, record) {
+                            self.problem(&journal_key, e.to_string());
+                            break;
+                        }
+                    }
+                }
+                Err(Error::Truncated) => self.problem(&journal_key, "records counted by the journal head are missing"),
+                Err(e) => self.problem(&journal_key, e.to_string()),
+            }
+        }
+        Some(vault)
+    }
+
+    fn check_v1(&mut self, key: &str, file: &VaultFile, live: bool) -> Option<Vault> {
+        let header = match file.header_v1() {
+            Ok(header) => header,
+            Err(e) => {
+                self.problem(key, e.to_string());
+                return None;
+            }
+        };
+        if header.salt.len() != SALT_LEN {
+            self.problem(key, format!("the header salt is {} bytes instead of {}", header.salt.len(), SALT_LEN));
+            return None;
+        }
+        let master = self.master?;
+        let derived = match derive_key_with(master.password.expose(), None, &header.salt, &header.kdf) {
+            Ok(derived) => derived,
+            Err(e) => {
+                self.problem(key, e.to_string());
+                return None;
+            }
+        };
+        match decrypt_with_aad(&derived, &header.nonce, &file.ciphertext, &file.aad) {
+            Ok(plaintext) => self.decode_payload(key, &plaintext),
+            Err(_) => {
+                self.locked_out(key, live, Error::WrongPassword, "the payload does not decrypt: the password is wrong or the file is damaged");
+                None
+            }
+        }
+    }
+
+    fn decode_payload(&mut self, key: &str, plaintext: &[u8]) -> Option<Vault> {
+        match VersionedVault::decode(plaintext) {
+            Ok(vault) => Some(vault.migrate()),
+            Err(e @ Error::UnsupportedSchema(_)) => {
+                self.problem(key, e.to_string());
+                None
+            }
+            Err(e) => {
+                self.problem(key, format!("the decrypted payload is not a valid vault: {}", e));
+                None
+            }
+        }
+    }
+}
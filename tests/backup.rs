@@ -0,0 +1,97 @@
+use rustpass::model::{Entry, EntryKind, Login};
+use rustpass::storage::MemoryBackend;
+use rustpass::store::VaultStore;
+use rustpass::{backup, Error};
+use std::fs;
+
+#[test]
+fn snapshot_restores_only_with_the_right_password() {
+    let name = "snapshot-restore-test";
+    let master = "unit-test-master";
+    let out = std::env::temp_dir().join(format!("rustpass-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&out);
+    let store = VaultStore::with_backend(MemoryBackend::new());
+    store.create(name, &master.into()).expect("Vault creation should succeed");
+
+    let mut session = store.unlock(name, &master.into()).unwrap();
+    session.add_entry(Entry::new(
+        "kept",
+        EntryKind::Login(Login {
+            username: "user".to_string(),
+            password: "pass".into(),
+        }),
+    ));
+    session.commit().unwrap();
+    let path = store.snapshot(name, &out).expect("snapshot should be written");
+    assert_eq!(backup::list_snapshots(&out).unwrap(), vec![path.clone()]);
+
+    session.remove_entries_named("kept");
+    session.commit().unwrap();
+
+    let snapshot = backup::read_snapshot(&path).unwrap();
+    assert_eq!(snapshot.vault, name);
+    assert!(matches!(store.restore(&snapshot, name, &"wrong".into(), &master.into()), Err(Error::WrongPassword)));
+    assert!(store.unlock(name, &master.into()).unwrap().entries().is_empty(), "a failed restore must not touch the vault");
+
+    let restored = store.restore(&snapshot, name, &master.into(), &master.into()).expect("restore should succeed");
+    assert_eq!(restored.entries.len(), 1);
+    assert_eq!(store.unlock(name, &master.into()).unwrap().entries()[0].name, "kept");
+
+    let _ = fs::remove_dir_all(&out);
+}
+
+#[test]
+fn restore_keeps_the_replaced_vault_with_its_journal() {
+    let name = "snapshot-replace-test";
+    let master = "unit-test-master";
+    let out = std::env::temp_dir().join(format!("rustpass-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&out);
+    let store = VaultStore::with_backend(MemoryBackend::new());
+    store.create(name, &master.into()).unwrap();
+    let snapshot = backup::read_snapshot(&store.snapshot(name, &out).unwrap()).unwrap();
+
+    // The live vault moves on under a new password, with its latest entry
+    // only in the journal.
+    let mut session = store.unlock(name, &master.into()).unwrap();
+    session.change_password(&"new-master".into()).unwrap();
+    session.add_entry(Entry::new(
+        "journaled",
+        EntryKind::Login(Login {
+            username: "user".to_string(),
+            password: "pass".into(),
+        }),
+    ));
+    session.commit().unwrap();
+    assert_eq!(session.history().len(), 1);
+
+    assert!(matches!(store.restore(&snapshot, name, &master.into(), &master.into()), Err(Error::WrongPassword)));
+    assert_eq!(store.unlock(name, &"new-master".into()).unwrap().entries().len(), 1, "a failed restore must not touch the vault");
+
+    store.restore(&snapshot, name, &master.into(), &"new-master".into()).unwrap();
+    assert!(store.unlock(name, &master.into()).unwrap().entries().is_empty());
+    let replaced = store.backups(name).unwrap()[0].clone();
+    assert_eq!(store.restore_backup(name, &replaced, false).unwrap(), 0);
+    assert_eq!(store.unlock(name, &"new-master".into()).unwrap().entries()[0].name, "journaled");
+
+    let _ = fs::remove_dir_all(&out);
+}
+
+#[test]
+fn restoring_a_backup_drops_the_journal_only_when_told_to() {
+    let master = "unit-test-master";
+    let store = VaultStore::with_backend(MemoryBackend::new());
+    store.create("v", &master.into()).unwrap();
+    let mut session = store.unlock("v", &master.into()).unwrap();
+    session.add_entry(Entry::new("compacted", EntryKind::Login(Login { username: "user".to_string(), password: "pass".into() })));
+    session.compact().unwrap();
+    session.add_entry(Entry::new("journaled", EntryKind::Login(Login { username: "user".to_string(), password: "pass".into() })));
+    session.commit().unwrap();
+    assert_eq!(store.journal_records("v").unwrap(), 1);
+
+    let backup = store.backups("v").unwrap()[0].clone();
+    assert!(matches!(store.restore_backup("v", &backup, false), Err(Error::InvalidInput(_))));
+    assert_eq!(store.unlock("v", &master.into()).unwrap().entries().len(), 2, "a refused restore changes nothing");
+    assert_eq!(store.restore_backup("v", &backup, true).unwrap(), 1);
+    assert_eq!(store.journal_records("v").unwrap(), 0);
+    assert!(store.unlock("v", &master.into()).unwrap().entries().is_empty());
+}
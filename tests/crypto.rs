@@ -1,25 +1,44 @@
-use rustpass::crypto;
+use rustpass::{crypto, Error};
 
 #[test]
 fn encrypt_decrypt_roundtrip() {
     let master = "test-master-password";
     let salt = b"0123456789abcdef";
-    let key = crypto::derive_key(master, salt);
+    let key = crypto::derive_key(master, salt).unwrap();
     let plaintext = b"super secret data";
-    let (ciphertext, nonce) = crypto::encrypt(&key, plaintext);
+    let (ciphertext, nonce) = crypto::encrypt(&key, plaintext).unwrap();
     let decrypted = crypto::decrypt(&key, &ciphertext, &nonce).expect("Decryption should succeed");
-    assert_eq!(decrypted, plaintext);
+    assert_eq!(decrypted.as_slice(), plaintext);
 }
 
 #[test]
 fn decrypt_with_wrong_password_fails() {
     let master = "test-master-password";
     let salt = b"0123456789abcdef";
-    let key = crypto::derive_key(master, salt);
+    let key = crypto::derive_key(master, salt).unwrap();
     let plaintext = b"super secret data";
-    let (ciphertext, nonce) = crypto::encrypt(&key, plaintext);
+    let (ciphertext, nonce) = crypto::encrypt(&key, plaintext).unwrap();
 
-    let wrong_key = crypto::derive_key("wrong-password", salt);
+    let wrong_key = crypto::derive_key("wrong-password", salt).unwrap();
     let result = crypto::decrypt(&wrong_key, &ciphertext, &nonce);
-    assert!(result.is_err(), "Decryption with wrong password should fail");
+    assert!(matches!(result, Err(Error::WrongPassword)), "Decryption with wrong password should fail");
+}
+
+#[test]
+fn wrapped_key_only_unwraps_with_right_kek() {
+    let kek = crypto::derive_key("test-master-password", b"0123456789abcdef").unwrap();
+    let data_key = crypto::random_key();
+    let (wrapped, nonce) = crypto::wrap_key(&kek, &data_key).expect("Wrapping should succeed");
+    assert_eq!(crypto::unwrap_key(&kek, &nonce, &wrapped).unwrap(), data_key);
+
+    let wrong_kek = crypto::derive_key("wrong-password", b"0123456789abcdef").unwrap();
+    assert!(crypto::unwrap_key(&wrong_kek, &nonce, &wrapped).is_err());
+}
+
+#[test]
+fn secret_string_debug_is_redacted() {
+    let secret = rustpass::secret::SecretString::from("hunter2");
+    let debug = format!("{:?}", secret);
+    assert!(!debug.contains("hunter2"));
+    assert_eq!(secret.expose(), "hunter2");
 }
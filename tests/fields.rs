@@ -0,0 +1,98 @@
+use rustpass::journal::{Change, EntryField};
+use rustpass::model::{CustomField, Entry, EntryKind, FieldType, SecureNote};
+use rustpass::storage::MemoryBackend;
+use rustpass::store::VaultStore;
+use rustpass::totp::{Algorithm, Totp};
+use rustpass::Error;
+use chrono::{TimeZone, Utc};
+
+fn field(label: &str, value: &str, kind: FieldType) -> CustomField {
+    CustomField { label: label.to_string(), value: value.into(), kind }
+}
+
+fn note(name: &str) -> Entry {
+    Entry::new(name, EntryKind::SecureNote(SecureNote { text: "text".into() }))
+}
+
+#[test]
+fn test_fields_are_validated() {
+    let mut entry = note("Bank");
+    entry.fields = vec![
+        field("Branch", "Downtown", FieldType::Text),
+        field("PIN", "1234", FieldType::Hidden),
+        field("Portal", "https://bank.example.com", FieldType::Url),
+        field("Advisor", "ann@bank.example.com", FieldType::Email),
+        field("Opened", "2019-04-30", FieldType::Date),
+        field("2FA", "JBSW Y3DP EHPK 3PXP", FieldType::Totp),
+    ];
+    entry.validate().unwrap();
+    assert_eq!(entry.field("PIN").unwrap().value.expose(), "1234");
+    assert!(entry.field("pin").is_none());
+
+    for bad in [
+        field("Portal", "bank.example.com", FieldType::Url),
+        field("Advisor", "ann", FieldType::Email),
+        field("Opened", "2019-02-30", FieldType::Date),
+        field("2FA", "not base32!", FieldType::Totp),
+        field(" ", "value", FieldType::Text),
+    ] {
+        let mut entry = note("Bank");
+        entry.fields.push(bad.clone());
+        assert!(matches!(entry.validate(), Err(Error::InvalidInput(_))), "{:?} should be refused", bad);
+    }
+    entry.fields.push(field("Branch", "Uptown", FieldType::Text));
+    assert!(entry.validate().is_err(), "labels are unique");
+
+    assert_eq!("TOTP".parse::<FieldType>().unwrap(), FieldType::Totp);
+    assert!("secret".parse::<FieldType>().is_err());
+}
+
+#[test]
+fn test_totp_codes_match_rfc_6238() {
+    // Test vectors from RFC 6238, appendix B.
+    let sha1 = Totp::parse("otpauth://totp/Example:alice?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&digits=8&issuer=Example").unwrap();
+    let sha256 = Totp::parse("otpauth://totp/x?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZA&algorithm=SHA256&digits=8").unwrap();
+    assert_eq!(sha256.algorithm, Algorithm::Sha256);
+    for (at, sha1_code, sha256_code) in [
+        (59, "94287082", "46119246"),
+        (1_111_111_109, "07081804", "68084774"),
+        (2_000_000_000, "69279037", "90698825"),
+    ] {
+        let at = Utc.timestamp_opt(at, 0).unwrap();
+        assert_eq!(sha1.code_at(at), sha1_code);
+        assert_eq!(sha256.code_at(at), sha256_code);
+    }
+
+    let plain = Totp::parse("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap();
+    assert_eq!((plain.digits, plain.period), (6, 30));
+    assert_eq!(plain.code_at(Utc.timestamp_opt(59, 0).unwrap()), "287082");
+    assert_eq!(plain.remaining_at(Utc.timestamp_opt(59, 0).unwrap()), 1);
+    assert!(Totp::parse("otpauth://hotp/x?secret=GEZDGNBV&counter=1").is_err());
+    assert!(Totp::parse("otpauth://totp/x?secret=GEZDGNBV&digits=4").is_err());
+}
+
+#[test]
+fn test_fields_are_journaled_in_order() {
+    let master = "unit-test-master";
+    let store = VaultStore::with_backend(MemoryBackend::new());
+    store.create("v", &master.into()).unwrap();
+    let mut session = store.unlock("v", &master.into()).unwrap();
+    let id = session.add_entry(note("Bank"));
+    session.commit().unwrap();
+
+    let entry = session.entry_mut(id).unwrap();
+    entry.fields.push(field("PIN", "1234", FieldType::Hidden));
+    entry.fields.push(field("Branch", "Downtown", FieldType::Text));
+    session.commit().unwrap();
+    match &session.history()[1].changes[..] {
+        [Change::EditField { field: EntryField::Fields(fields), .. }] => assert_eq!(fields.len(), 2),
+        changes => panic!("unexpected changes {:?}", changes),
+    }
+
+    let reopened = store.unlock("v", &master.into()).unwrap();
+    let labels: Vec<_> = reopened.entry(id).unwrap().fields.iter().map(|f| f.label.as_str()).collect();
+    assert_eq!(labels, ["PIN", "Branch"]);
+    assert_eq!(reopened.entry(id).unwrap().field("PIN").unwrap().kind, FieldType::Hidden);
+    store.compact("v", &master.into()).unwrap();
+    assert_eq!(store.unlock("v", &master.into()).unwrap().entry(id).unwrap().fields, session.entry(id).unwrap().fields);
+}
@@ -0,0 +1,79 @@
+use rustpass::folders;
+use rustpass::journal::{Change, EntryField};
+use rustpass::model::{Entry, EntryKind, SecureNote};
+use rustpass::storage::MemoryBackend;
+use rustpass::store::VaultStore;
+use rustpass::Error;
+
+fn entry(name: &str, folder: Option<&str>) -> Entry {
+    let mut entry = Entry::new(name, EntryKind::SecureNote(SecureNote { text: "text".into() }));
+    entry.folder = folder.map(str::to_string);
+    entry
+}
+
+#[test]
+fn test_folder_paths_and_counts() {
+    assert_eq!(folders::normalize(" /work//aws / prod/").unwrap(), "work/aws/prod");
+    assert!(folders::normalize("//").is_err());
+    assert!(folders::contains("work/aws", "work/aws"));
+    assert!(folders::contains("work/aws", "work/aws/prod"));
+    assert!(!folders::contains("work/aws", "work/aws-old"));
+    assert_eq!(folders::reparent("work/aws/prod", "work/aws", "archive").as_deref(), Some("archive/prod"));
+    assert_eq!(folders::reparent("work/gcp", "work/aws", "archive"), None);
+
+    let entries = vec![
+        entry("root", Some("work/aws/prod")),
+        entry("ci", Some("work/aws")),
+        entry("old", Some("work-old")),
+        entry("bank", None),
+    ];
+    let counts: Vec<_> = folders::counts(&entries).into_iter().map(|(path, n)| (path.join("/"), n)).collect();
+    assert_eq!(counts, [("work".to_string(), 2), ("work/aws".to_string(), 2), ("work/aws/prod".to_string(), 1), ("work-old".to_string(), 1)]);
+
+    assert!(entries[0].in_folder("work"));
+    assert!(!entries[2].in_folder("work"));
+    assert!(matches!(entry("bad", Some("work/")).validate(), Err(Error::InvalidInput(_))));
+    let mut tagged = entry("tagged", None);
+    tagged.tags.insert("two words".to_string());
+    assert!(tagged.validate().is_err());
+}
+
+#[test]
+fn test_folders_move_and_tags_apply_in_bulk() {
+    let master = "unit-test-master";
+    let store = VaultStore::with_backend(MemoryBackend::new());
+    store.create("v", &master.into()).unwrap();
+    let mut session = store.unlock("v", &master.into()).unwrap();
+    let prod = session.add_entry(entry("root", Some("work/aws/prod")));
+    let ci = session.add_entry(entry("ci", Some("work/aws")));
+    let home = session.add_entry(entry("bank", None));
+    session.commit().unwrap();
+
+    assert_eq!(session.move_folder("work/aws", "archive/amazon").unwrap(), 2);
+    assert!(matches!(session.move_folder("nowhere", "x"), Err(Error::NotFound(_))));
+    for into in ["archive/amazon", "archive/amazon/old"] {
+        assert!(matches!(session.move_folder("archive/amazon", into), Err(Error::InvalidInput(_))));
+    }
+    assert_eq!(session.tag_entries(&[prod, ci], "cloud").unwrap(), 2);
+    assert_eq!(session.tag_entries(&[prod], "cloud").unwrap(), 0);
+    assert!(session.tag_entries(&[home], "").is_err());
+    session.commit().unwrap();
+    let changes = &session.history()[1].changes;
+    assert!(changes.iter().any(|c| matches!(c, Change::EditField { id, field: EntryField::Folder(Some(f)) } if *id == prod && f == "archive/amazon/prod")));
+    assert!(changes.iter().any(|c| matches!(c, Change::EditField { id, field: EntryField::Tags(t) } if *id == ci && t.contains("cloud"))));
+
+    assert_eq!(session.untag_entries(&[prod, ci, home], "cloud"), 2);
+    session.commit().unwrap();
+
+    assert_eq!(session.set_folder(&[home, home, ci], Some("archive/amazon")).unwrap(), 1, "ci is already there");
+    assert_eq!(session.set_folder(&[ci], Some("/archive/amazon/")).unwrap(), 0);
+    assert_eq!(session.set_folder(&[home], None).unwrap(), 1);
+    session.commit().unwrap();
+    assert_eq!(session.set_folder(&[home], None).unwrap(), 0);
+    assert!(!session.is_dirty());
+
+    let reopened = store.unlock("v", &master.into()).unwrap();
+    assert_eq!(reopened.entry(ci).unwrap().folder.as_deref(), Some("archive/amazon"));
+    assert!(reopened.entry(prod).unwrap().tags.is_empty());
+    assert_eq!(reopened.entry(home).unwrap().folder, None);
+}
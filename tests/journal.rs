@@ -0,0 +1,193 @@
+use rustpass::journal::{self, Change};
+use rustpass::model::{Entry, EntryKind, Login};
+use rustpass::storage::{MemoryBackend, StorageBackend};
+use rustpass::store::VaultStore;
+use rustpass::{vault, Error};
+
+fn entry(name: &str) -> Entry {
+    Entry::new(
+        name,
+        EntryKind::Login(Login {
+            username: "user".to_string(),
+            password: "pass".into(),
+        }),
+    )
+}
+
+// The `length | record` frames of a stored journal.
+fn frames(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        frames.push(rest[..4 + len].to_vec());
+        rest = &rest[4 + len..];
+    }
+    frames
+}
+
+// A vault with three commits in its journal.
+fn journaled_vault(storage: &MemoryBackend, master: &str) -> VaultStore {
+    let store = VaultStore::with_backend(storage.clone());
+    store.create("v", &master.into()).unwrap();
+    let mut session = store.unlock("v", &master.into()).unwrap();
+    let id = session.add_entry(entry("Github"));
+    session.commit().unwrap();
+    session.entry_mut(id).unwrap().name = "GitHub".to_string();
+    session.entry_mut(id).unwrap().as_login_mut().unwrap().password = "new-pass".into();
+    session.commit().unwrap();
+    session.add_entry(entry("Mail"));
+    session.remove_entry(id);
+    session.commit().unwrap();
+    store
+}
+
+#[test]
+fn test_commits_are_journaled_until_compacted() {
+    let storage = MemoryBackend::new();
+    let master = "unit-test-master";
+    let store = journaled_vault(&storage, master);
+    let key = vault::vault_key("v");
+    assert!(storage.exists(&journal::journal_key(&key)).unwrap());
+    assert!(vault::backup_keys(&storage, &key).unwrap().is_empty(), "commits must not rewrite the vault file");
+
+    let session = store.unlock("v", &master.into()).unwrap();
+    let names: Vec<_> = session.entries().iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, vec!["Mail"]);
+    let history: Vec<_> = session.history().iter().map(|r| r.changes.len()).collect();
+    assert_eq!(history, vec![1, 2, 2]);
+    assert!(matches!(session.history()[1].changes[0], Change::RenameEntry { id: 1, .. }));
+
+    assert_eq!(store.compact("v", &master.into()).unwrap(), 3);
+    assert!(!storage.exists(&journal::journal_key(&key)).unwrap());
+    assert!(!storage.exists(&journal::head_key(&key)).unwrap());
+    let session = store.unlock("v", &master.into()).unwrap();
+    assert!(session.history().is_empty());
+    assert_eq!(session.entries().len(), 1);
+    assert_eq!(session.entries()[0].name, "Mail");
+    assert_eq!(store.compact("v", &master.into()).unwrap(), 0);
+}
+
+#[test]
+fn test_deleted_ids_are_not_reused() {
+    let master = "unit-test-master";
+    let store = VaultStore::with_backend(MemoryBackend::new());
+    store.create("v", &master.into()).unwrap();
+    let mut session = store.unlock("v", &master.into()).unwrap();
+    let github = session.add_entry(entry("Github"));
+    session.commit().unwrap();
+    session.remove_entry(github);
+    let mail = session.add_entry(entry("Mail"));
+    assert_ne!(mail, github);
+    session.commit().unwrap();
+    let changes = &session.history().last().unwrap().changes;
+    assert!(changes.iter().any(|c| matches!(c, Change::DeleteEntry { id } if *id == github)));
+    assert!(changes.iter().any(|c| matches!(c, Change::AddEntry(e) if e.id == mail)));
+
+    // Replaying the journal and compacting both keep the next id.
+    session.remove_entry(mail);
+    session.commit().unwrap();
+    let mut reopened = store.unlock("v", &master.into()).unwrap();
+    let bank = reopened.add_entry(entry("Bank"));
+    assert!(bank > mail);
+    reopened.commit().unwrap();
+    reopened.remove_entry(bank);
+    reopened.commit().unwrap();
+    store.compact("v", &master.into()).unwrap();
+    let mut compacted = store.unlock("v", &master.into()).unwrap();
+    assert!(compacted.entries().is_empty());
+    assert!(compacted.add_entry(entry("Shop")) > bank);
+}
+
+#[test]
+fn test_reordered_or_truncated_journals_are_rejected() {
+    let storage = MemoryBackend::new();
+    let master = "unit-test-master";
+    let store = journaled_vault(&storage, master);
+    let journal_key = journal::journal_key(&vault::vault_key("v"));
+    let original = storage.read(&journal_key).unwrap();
+    let records = frames(&original);
+    assert_eq!(records.len(), 3);
+
+    let swapped = [records[0].clone(), records[2].clone(), records[1].clone()].concat();
+    storage.write(&journal_key, &swapped, 0).unwrap();
+    assert!(matches!(store.unlock("v", &master.into()), Err(Error::Corrupt(_))));
+
+    let dropped_middle = [records[0].clone(), records[2].clone()].concat();
+    storage.write(&journal_key, &dropped_middle, 0).unwrap();
+    assert!(matches!(store.unlock("v", &master.into()), Err(Error::Corrupt(_))));
+
+    let dropped_last = [records[0].clone(), records[1].clone()].concat();
+    storage.write(&journal_key, &dropped_last, 0).unwrap();
+    assert!(matches!(store.unlock("v", &master.into()), Err(Error::Truncated)));
+
+    let dropped_first = [records[1].clone(), records[2].clone()].concat();
+    storage.write(&journal_key, &dropped_first, 0).unwrap();
+    assert!(matches!(store.unlock("v", &master.into()), Err(Error::Truncated)));
+
+    // A torn append past the head is ignored, then replaced by the next record.
+    storage.write(&journal_key, &[original.as_slice(), &records[0][..10]].concat(), 0).unwrap();
+    let mut session = store.unlock("v", &master.into()).expect("a torn last record is not an error");
+    assert_eq!(session.history().len(), 3);
+    session.add_entry(entry("Bank"));
+    session.commit().unwrap();
+    assert_eq!(frames(&storage.read(&journal_key).unwrap()).len(), 4);
+    assert_eq!(store.unlock("v", &master.into()).unwrap().entries().len(), 2);
+}
+
+#[test]
+fn test_journal_heads_are_required() {
+    let storage = MemoryBackend::new();
+    let master = "unit-test-master";
+    let store = journaled_vault(&storage, master);
+    let key = vault::vault_key("v");
+    let (journal_key, head_key) = (journal::journal_key(&key), journal::head_key(&key));
+    let journal = storage.read(&journal_key).unwrap();
+    let head = storage.read(&head_key).unwrap();
+
+    storage.delete(&head_key).unwrap();
+    assert!(matches!(store.unlock("v", &master.into()), Err(Error::Truncated)));
+    storage.write(&head_key, b"not a head", 0).unwrap();
+    assert!(matches!(store.unlock("v", &master.into()), Err(Error::Corrupt(_))));
+
+    // Compaction interrupted before the journal was removed: the old journal
+    // still matches its own head, and the new blob already holds it.
+    storage.write(&head_key, &head, 0).unwrap();
+    store.compact("v", &master.into()).unwrap();
+    storage.write(&journal_key, &journal, 0).unwrap();
+    storage.write(&head_key, &head, 0).unwrap();
+    let mut session = store.unlock("v", &master.into()).expect("a stale journal with its head is left over from compaction");
+    assert!(session.history().is_empty());
+    assert_eq!(session.entries()[0].name, "Mail");
+    session.add_entry(entry("Bank"));
+    session.commit().unwrap();
+    assert_eq!(frames(&storage.read(&journal_key).unwrap()).len(), 1);
+    assert_eq!(store.unlock("v", &master.into()).unwrap().entries().len(), 2);
+
+    // Without its head, a journal written against another blob is refused.
+    store.compact("v", &master.into()).unwrap();
+    storage.write(&journal_key, &journal, 0).unwrap();
+    assert!(matches!(store.unlock("v", &master.into()), Err(Error::Truncated)));
+}
+
+#[test]
+fn test_snapshots_carry_the_journal() {
+    let storage = MemoryBackend::new();
+    let master = "unit-test-master";
+    let store = journaled_vault(&storage, master);
+    let out = std::env::temp_dir().join(format!("rustpass-test-journal-snapshot-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&out);
+    let path = store.snapshot("v", &out).unwrap();
+    let snapshot = rustpass::backup::read_snapshot(&path).unwrap();
+    assert_eq!(snapshot.decrypt(&master.into()).unwrap().entries[0].name, "Mail");
+
+    store.compact("v", &master.into()).unwrap();
+    let mut session = store.unlock("v", &master.into()).unwrap();
+    session.add_entry(entry("Later"));
+    session.commit().unwrap();
+    store.restore(&snapshot, "v", &master.into(), &master.into()).unwrap();
+    let session = store.unlock("v", &master.into()).unwrap();
+    assert_eq!(session.entries().len(), 1);
+    assert_eq!(session.history().len(), 3);
+    let _ = std::fs::remove_dir_all(&out);
+}
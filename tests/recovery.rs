@@ -0,0 +1,35 @@
+use rustpass::recovery;
+
+#[test]
+fn phrase_roundtrip() {
+    let secret = recovery::generate_secret();
+    let phrase = recovery::encode_phrase(&secret);
+    assert_eq!(phrase.split(' ').count(), recovery::PHRASE_WORDS);
+    assert_eq!(recovery::decode_phrase(&phrase).unwrap(), secret);
+    assert_eq!(recovery::decode_phrase(&phrase.to_uppercase()).unwrap(), secret);
+}
+
+#[test]
+fn known_vector_matches_bip39() {
+    let secret = [0u8; 32];
+    let phrase = recovery::encode_phrase(&secret);
+    assert_eq!(phrase, format!("{} art", "abandon ".repeat(23).trim_end()));
+}
+
+#[test]
+fn bad_phrases_are_rejected() {
+    let phrase = recovery::encode_phrase(&recovery::generate_secret());
+    let mut words: Vec<&str> = phrase.split(' ').collect();
+
+    assert!(recovery::decode_phrase(&words[..23].join(" ")).is_err(), "wrong word count");
+
+    let original = words[0];
+    words[0] = "notaword";
+    assert!(recovery::decode_phrase(&words.join(" ")).is_err(), "unknown word");
+
+    words[0] = original;
+    words.swap(0, 1);
+    if words[0] != words[1] {
+        assert!(recovery::decode_phrase(&words.join(" ")).is_err(), "swapped words should fail the checksum");
+    }
+}
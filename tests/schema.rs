@@ -0,0 +1,125 @@
+use rustpass::model::{Entry, EntryKind, EntryV1, Login, VaultV1, VersionedVault, SCHEMA_VERSION};
+use rustpass::store::VaultStore;
+use rustpass::{config, crypto, format, vault, Error};
+use chrono::{TimeZone, Utc};
+use std::fs;
+use std::path::PathBuf;
+
+fn test_root(test: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("rustpass-test-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    root
+}
+
+fn entry(name: &str) -> Entry {
+    Entry::new(
+        name,
+        EntryKind::Login(Login {
+            username: "user".to_string(),
+            password: "pass".into(),
+        }),
+    )
+}
+
+// The data key of a vault blob, unwrapped from its first key slot.
+fn data_key(vault_data: &[u8], master: &str) -> rustpass::secret::SecretKey {
+    let header = format::decode(vault_data).unwrap().header().unwrap();
+    let slot = &header.key_slots[0];
+    let kek = crypto::derive_key_with(master, None, &slot.salt, &slot.kdf).unwrap();
+    crypto::unwrap_key(&kek, &slot.nonce, &slot.wrapped_key).unwrap()
+}
+
+fn unseal(vault_data: &[u8], master: &str) -> Vec<u8> {
+    let file = format::decode(vault_data).unwrap();
+    let header = file.header().unwrap();
+    crypto::decrypt_with_aad(&data_key(vault_data, master), &header.nonce, &file.ciphertext, &file.aad)
+        .unwrap()
+        .to_vec()
+}
+
+// Replaces the payload of a vault blob, keeping its header and data key.
+fn reseal(vault_data: &[u8], master: &str, payload: &[u8]) -> Vec<u8> {
+    let file = format::decode(vault_data).unwrap();
+    let header = file.header().unwrap();
+    let ciphertext = crypto::encrypt_with_aad(&data_key(vault_data, master), &header.nonce, payload, &file.aad).unwrap();
+    [file.aad.as_slice(), &ciphertext].concat()
+}
+
+#[test]
+fn test_schema_1_vault_is_migrated_and_original_kept() {
+    let root = test_root("schema-migration");
+    fs::write(config::config_path(&root), "backups = 0\n").unwrap();
+    let master = "unit-test-master";
+    let store = VaultStore::open(&root).unwrap();
+    store.create("personal", &master.into()).unwrap();
+    let path = vault::vault_file_path(&root, "personal").unwrap();
+
+    let github = EntryV1 {
+        id: 1,
+        name: "Github".to_string(),
+        username: "octocat".to_string(),
+        password: "pass".into(),
+        notes: None,
+    };
+    let v1 = VaultV1 {
+        created_at: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+        last_modified: Utc.timestamp_opt(1_700_000_100, 0).unwrap(),
+        entries: vec![github],
+    };
+    let mut payload = Vec::new();
+    ciborium::ser::into_writer(&v1, &mut payload).unwrap();
+    let original = reseal(&fs::read(&path).unwrap(), master, &payload);
+    fs::write(&path, &original).unwrap();
+
+    let mut session = store.unlock("personal", &master.into()).unwrap();
+    assert_eq!(session.vault().last_modified, v1.last_modified);
+    assert_eq!(session.entries()[0].name, "Github");
+    assert_eq!(session.entries()[0].as_login().unwrap().username, "octocat");
+    assert!(store.verify("personal", Some(&master.into())).unwrap().is_ok());
+
+    // The first save rewrites the file in the current schema and keeps the
+    // original, although backups are turned off.
+    session.add_entry(entry("Mail"));
+    session.commit().unwrap();
+    assert!(session.history().is_empty());
+    let stored = VersionedVault::decode(&unseal(&fs::read(&path).unwrap(), master)).unwrap();
+    assert_eq!(stored.version(), SCHEMA_VERSION);
+    assert_eq!(stored.migrate().entries.len(), 2);
+    let backups = vault::backup_files_named(&root, "personal").unwrap();
+    assert_eq!(backups.len(), 1);
+    assert_eq!(fs::read(&backups[0]).unwrap(), original);
+    assert!(matches!(VersionedVault::decode(&unseal(&original, master)).unwrap(), VersionedVault::V1(_)));
+
+    // Later saves go to the journal again.
+    session.add_entry(entry("Bank"));
+    session.commit().unwrap();
+    assert_eq!(session.history().len(), 1);
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_future_schema_is_refused() {
+    let root = test_root("schema-future");
+    let master = "unit-test-master";
+    let store = VaultStore::open(&root).unwrap();
+    store.create("personal", &master.into()).unwrap();
+    let path = vault::vault_file_path(&root, "personal").unwrap();
+
+    #[derive(serde::Serialize)]
+    struct Tagged {
+        schema: u32,
+        payload: Vec<String>,
+    }
+    let future = SCHEMA_VERSION + 1;
+    let mut payload = Vec::new();
+    ciborium::ser::into_writer(&Tagged { schema: future, payload: vec!["holograms".to_string()] }, &mut payload).unwrap();
+    fs::write(&path, reseal(&fs::read(&path).unwrap(), master, &payload)).unwrap();
+
+    assert!(matches!(store.unlock("personal", &master.into()), Err(Error::UnsupportedSchema(v)) if v == future));
+    let report = store.verify("personal", Some(&master.into())).unwrap();
+    assert!(report.problems.iter().any(|p| p.message.contains("newer rustpass")));
+
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,61 @@
+use rustpass::shamir::{self, Share};
+
+#[test]
+fn any_threshold_subset_recovers_the_secret() {
+    let secret = b"0123456789abcdef0123456789abcdef";
+    let shares = shamir::split(secret, 3, 5).expect("split should succeed");
+    assert_eq!(shares.len(), 5);
+
+    for subset in [[0, 1, 2], [0, 2, 4], [4, 3, 1], [1, 2, 3]] {
+        let picked: Vec<Share> = subset.iter().map(|&i| shares[i].clone()).collect();
+        assert_eq!(shamir::combine(&picked).unwrap().as_slice(), secret);
+    }
+}
+
+#[test]
+fn shares_roundtrip_through_text() {
+    let shares = shamir::split(&[7u8; 32], 2, 3).unwrap();
+    for share in &shares {
+        assert_eq!(&Share::decode(&share.encode()).unwrap(), share);
+    }
+}
+
+#[test]
+fn bad_share_sets_are_rejected() {
+    let secret = [42u8; 32];
+    let shares = shamir::split(&secret, 3, 5).unwrap();
+
+    assert!(shamir::combine(&shares[..2]).is_err(), "too few shares");
+    let duplicated = vec![shares[0].clone(), shares[0].clone(), shares[1].clone()];
+    assert!(shamir::combine(&duplicated).is_err(), "duplicate shares");
+
+    let other = shamir::split(&[1u8; 32], 3, 5).unwrap();
+    let mixed = vec![shares[0].clone(), shares[1].clone(), other[2].clone()];
+    assert!(shamir::combine(&mixed).is_err(), "shares from another split");
+
+    let mut corrupted = shares[..3].to_vec();
+    corrupted[2].data[0] ^= 1;
+    assert!(shamir::combine(&corrupted).is_err(), "a wrong share must not give a silent wrong key");
+
+    let mut text = shares[0].encode();
+    let last = text.pop().unwrap();
+    text.push(if last == '0' { '1' } else { '0' });
+    assert!(Share::decode(&text).is_err(), "typo should fail the checksum");
+
+    assert!(shamir::split(&secret, 1, 3).is_err());
+    assert!(shamir::split(&secret, 4, 3).is_err());
+}
+
+#[test]
+fn shares_beyond_the_threshold_are_checked_too() {
+    let secret = [9u8; 32];
+    let shares = shamir::split(&secret, 2, 4).unwrap();
+    assert_eq!(shamir::combine(&shares).unwrap().as_slice(), secret);
+
+    let mut wrong_extra = shares.clone();
+    wrong_extra[3].data[5] ^= 0x80;
+    match shamir::combine(&wrong_extra) {
+        Err(rustpass::Error::InvalidInput(message)) => assert!(message.contains("Share number 4"), "{}", message),
+        other => panic!("a wrong extra share must be rejected, got {:?}", other),
+    }
+}
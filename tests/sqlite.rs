@@ -0,0 +1,154 @@
+#![cfg(feature = "sqlite")]
+
+use rustpass::model::{Entry, EntryKind, Login};
+use rustpass::sqlite::SqliteVault;
+use rustpass::storage::MemoryBackend;
+use rustpass::store::VaultStore;
+use rustpass::Error;
+use std::fs;
+use std::path::PathBuf;
+
+fn test_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rustpass-test-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn entry(name: &str) -> Entry {
+    Entry::new(
+        name,
+        EntryKind::Login(Login {
+            username: "user".to_string(),
+            password: "pass".into(),
+        }),
+    )
+}
+
+#[test]
+fn test_sqlite_round_trips_through_the_blob_format() {
+    let dir = test_dir("sqlite-round-trip");
+    let path = dir.join("personal.sqlite");
+    let master = "unit-test-master";
+    let store = VaultStore::with_backend(MemoryBackend::new());
+    store.create("personal", &master.into()).unwrap();
+    let mut session = store.unlock("personal", &master.into()).unwrap();
+    session.add_entry(entry("Github"));
+    session.add_entry(entry("Mail"));
+    session.commit().unwrap();
+
+    store.export_sqlite("personal", &master.into(), &path).expect("export should succeed");
+    assert!(matches!(SqliteVault::open(&path, &"wrong".into()), Err(Error::WrongPassword)));
+    let mut sqlite = SqliteVault::open(&path, &master.into()).unwrap();
+    assert_eq!(sqlite.entries().unwrap().len(), 2);
+
+    let id = sqlite.add_entry(entry("Bank")).unwrap();
+    assert_eq!(id, 3);
+    let mut github = sqlite.entry(1).unwrap().unwrap();
+    github.as_login_mut().unwrap().username = "octocat".to_string();
+    sqlite.update_entry(&github).unwrap();
+    assert!(sqlite.remove_entry(2).unwrap());
+    assert!(!sqlite.remove_entry(2).unwrap());
+    drop(sqlite);
+
+    store.import_sqlite(&path, "imported", &master.into()).expect("import should succeed");
+    let imported = store.unlock("imported", &master.into()).unwrap();
+    let names: Vec<_> = imported.entries().iter().map(|e| (e.id, e.name.as_str(), e.as_login().unwrap().username.as_str())).collect();
+    assert_eq!(names, vec![(1, "Github", "octocat"), (3, "Bank", "user")]);
+    assert!(matches!(store.import_sqlite(&path, "imported", &master.into()), Err(Error::InvalidInput(_))));
+
+    let mut sqlite = SqliteVault::open(&path, &master.into()).unwrap();
+    assert!(sqlite.remove_entry(3).unwrap());
+    assert_eq!(sqlite.add_entry(entry("Shop")).unwrap(), 4, "the id of a removed entry is not reused");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_sqlite_rows_are_authenticated_as_a_set() {
+    let dir = test_dir("sqlite-rows");
+    let path = dir.join("v.sqlite");
+    let master = "unit-test-master";
+    let mut sqlite = SqliteVault::create(&path, &master.into()).unwrap();
+    sqlite.add_entry(entry("one")).unwrap();
+    sqlite.add_entry(entry("two")).unwrap();
+    drop(sqlite);
+    let pristine = fs::read(&path).unwrap();
+
+    let tampered = |sql: &str| {
+        fs::write(&path, &pristine).unwrap();
+        rusqlite::Connection::open(&path).unwrap().execute_batch(sql).unwrap();
+        SqliteVault::open(&path, &master.into())
+    };
+    let swapped = tampered(
+        "UPDATE entries SET id = 99 WHERE id = 1;
+         UPDATE entries SET id = 1 WHERE id = 2;
+         UPDATE entries SET id = 2 WHERE id = 99;",
+    );
+    assert!(matches!(swapped, Err(Error::Corrupt(_))));
+    assert!(matches!(tampered("DELETE FROM entries WHERE id = 2;"), Err(Error::Corrupt(_))));
+
+    // Roll entry 1 back to the row it had before it was updated.
+    fs::write(&path, &pristine).unwrap();
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let old: (Vec<u8>, Vec<u8>) = conn.query_row("SELECT nonce, data FROM entries WHERE id = 1", [], |r| Ok((r.get(0)?, r.get(1)?))).unwrap();
+    let mut sqlite = SqliteVault::open(&path, &master.into()).unwrap();
+    let mut one = sqlite.entry(1).unwrap().unwrap();
+    one.name = "uno".to_string();
+    sqlite.update_entry(&one).unwrap();
+    drop(sqlite);
+    conn.execute("UPDATE entries SET nonce = ?1, data = ?2 WHERE id = 1", rusqlite::params![old.0, old.1]).unwrap();
+    assert!(matches!(SqliteVault::open(&path, &master.into()), Err(Error::Corrupt(_))));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_sqlite_sessions_write_only_changed_rows() {
+    let dir = test_dir("sqlite-session");
+    let master = "unit-test-master";
+    let store = VaultStore::open(&dir).unwrap();
+    store.create_sqlite("personal", &master.into()).unwrap();
+    assert_eq!(store.list().unwrap(), vec!["personal"]);
+    let mut session = store.unlock("personal", &master.into()).unwrap();
+    let github = session.add_entry(entry("Github"));
+    session.add_entry(entry("Mail"));
+    session.commit().unwrap();
+
+    let path = dir.join("vault_personal.sqlite");
+    let rows = || -> Vec<(u32, Vec<u8>)> {
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let mut stmt = conn.prepare("SELECT id, data FROM entries ORDER BY id").unwrap();
+        stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap().map(Result::unwrap).collect()
+    };
+    let before = rows();
+    session.entry_mut(github).unwrap().as_login_mut().unwrap().username = "octocat".to_string();
+    session.commit().unwrap();
+    let after = rows();
+    assert_ne!(after[0], before[0]);
+    assert_eq!(after[1], before[1], "an unchanged entry keeps its row");
+    assert!(session.history().is_empty(), "SQLite vaults have no journal");
+
+    let mut other = store.unlock("personal", &master.into()).unwrap();
+    assert_eq!(other.entry(github).unwrap().as_login().unwrap().username, "octocat");
+    session.remove_entry(github);
+    session.commit().unwrap();
+    other.add_entry(entry("Bank"));
+    assert!(matches!(other.commit(), Err(Error::Conflict)));
+
+    session.add_key_slot("backup", &"second-secret".into()).unwrap();
+    assert_eq!(store.unlock("personal", &"second-secret".into()).unwrap().entries().len(), 1);
+    assert!(store.verify("personal", Some(&master.into())).unwrap().is_ok());
+
+    store.create("work", &master.into()).unwrap();
+    let mut work = store.unlock("work", &master.into()).unwrap();
+    work.add_entry(entry("Jira"));
+    work.commit().unwrap();
+    store.convert_to_sqlite("work", &master.into()).unwrap();
+    assert!(!dir.join("vault_work.cbor").exists());
+    assert_eq!(store.unlock("work", &master.into()).unwrap().entries()[0].name, "Jira");
+    assert!(matches!(store.convert_to_sqlite("work", &master.into()), Err(Error::InvalidInput(_))));
+    assert_eq!(store.list().unwrap(), vec!["personal", "work"]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
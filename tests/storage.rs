@@ -0,0 +1,75 @@
+use rustpass::config::Config;
+use rustpass::storage::{FsBackend, MemoryBackend, StorageBackend};
+use rustpass::Error;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// The same contract, checked against every backend.
+fn check_backend(storage: &dyn StorageBackend) {
+    assert!(matches!(storage.read("blob"), Err(Error::NotFound(_))));
+    for generation in 1..=4u8 {
+        storage.write("blob", &[generation], 2).unwrap();
+    }
+    assert_eq!(storage.read("blob").unwrap(), vec![4]);
+    assert_eq!(storage.read("blob.bak.1").unwrap(), vec![3]);
+    assert_eq!(storage.read("blob.bak.2").unwrap(), vec![2]);
+    assert!(!storage.exists("blob.bak.3").unwrap(), "only the configured number of generations is kept");
+
+    storage.append("log", b"ab").unwrap();
+    storage.append("log", b"c").unwrap();
+    assert_eq!(storage.read("log").unwrap(), b"abc");
+    storage.delete("log").unwrap();
+
+    storage.rename("blob", "other").unwrap();
+    assert!(!storage.exists("blob").unwrap());
+    assert_eq!(storage.read("other").unwrap(), vec![4]);
+    let mut keys = storage.list().unwrap();
+    keys.retain(|k| !k.ends_with(".lock"));
+    keys.sort();
+    assert_eq!(keys, vec!["blob.bak.1", "blob.bak.2", "other"]);
+
+    storage.delete("other").unwrap();
+    storage.delete("other").expect("deleting a missing blob is not an error");
+    assert!(!storage.exists("other").unwrap());
+
+    let outer = storage.lock("other").unwrap();
+    let _inner = storage.lock("other").expect("a held lock can be taken again by its holder");
+    drop(outer);
+}
+
+#[test]
+fn test_fs_and_memory_backends_behave_alike() {
+    let root = std::env::temp_dir().join(format!("rustpass-test-storage-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    check_backend(&FsBackend::new(&root));
+    check_backend(&MemoryBackend::new());
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_memory_lock_blocks_other_threads() {
+    let storage = MemoryBackend::with_config(Config { lock_timeout_ms: 100, ..Config::default() });
+    let _lock = storage.lock("vault").unwrap();
+    let other = storage.clone();
+    let result = std::thread::spawn(move || other.lock("vault").map(|_| ())).join().unwrap();
+    assert!(matches!(result, Err(Error::Locked(_))));
+}
+
+#[test]
+fn test_fs_lock_excludes_other_threads() {
+    let root = std::env::temp_dir().join(format!("rustpass-test-storage-threads-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let storage: Arc<dyn StorageBackend> = Arc::new(FsBackend::new(&root));
+    let lock = storage.lock("vault").unwrap();
+    let other = storage.clone();
+    let waiter = std::thread::spawn(move || {
+        let started = Instant::now();
+        let _lock = other.lock("vault").unwrap();
+        started.elapsed()
+    });
+    std::thread::sleep(Duration::from_millis(300));
+    drop(lock);
+    assert!(waiter.join().unwrap() >= Duration::from_millis(250), "another thread waits for the holder to let go");
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,89 @@
+use rustpass::model::{Entry, EntryKind, Login};
+use rustpass::storage::MemoryBackend;
+use rustpass::store::VaultStore;
+use rustpass::Error;
+
+fn test_store() -> VaultStore {
+    VaultStore::with_backend(MemoryBackend::new())
+}
+
+fn entry(name: &str) -> Entry {
+    Entry::new(
+        name,
+        EntryKind::Login(Login {
+            username: "user".to_string(),
+            password: "pass".into(),
+        }),
+    )
+}
+
+#[test]
+fn test_store_creates_renames_and_deletes_vaults() {
+    let store = test_store();
+    let master = "unit-test-master";
+    store.create("b", &master.into()).expect("Vault creation should succeed");
+    store.create("a", &master.into()).expect("Vault creation should succeed");
+    assert!(matches!(store.create("a", &master.into()), Err(Error::InvalidInput(_))));
+    assert!(matches!(store.create("../x", &master.into()), Err(Error::InvalidInput(_))));
+    assert_eq!(store.list().unwrap(), vec!["a", "b"]);
+
+    store.select("a").unwrap();
+    store.rename("a", "c").unwrap();
+    assert_eq!(store.list().unwrap(), vec!["b", "c"]);
+    assert_eq!(store.selected().as_deref(), Some("c"), "the selection should follow a renamed vault");
+
+    assert!(matches!(store.delete("c", &"wrong".into()), Err(Error::WrongPassword)));
+    store.delete("c", &master.into()).expect("delete should succeed");
+    assert_eq!(store.list().unwrap(), vec!["b"]);
+    assert_eq!(store.selected(), None);
+    assert!(matches!(store.unlock("c", &master.into()), Err(Error::NotFound(_))));
+}
+
+#[test]
+fn test_session_entry_changes_need_commit() {
+    let store = test_store();
+    let master = "unit-test-master";
+    store.create("v", &master.into()).unwrap();
+
+    let mut session = store.unlock("v", &master.into()).unwrap();
+    let first = session.add_entry(entry("Github"));
+    let second = session.add_entry(entry("github"));
+    assert_eq!((first, second), (1, 2));
+    assert!(session.is_dirty());
+    assert!(store.unlock("v", &master.into()).unwrap().entries().is_empty(), "nothing is written before commit");
+
+    session.commit().expect("commit should succeed");
+    assert!(!session.is_dirty());
+    session.entry_mut(first).unwrap().as_login_mut().unwrap().username = "someone".to_string();
+    assert_eq!(session.remove_entries_named("GITHUB"), 2);
+    session.add_entry(entry("Mail"));
+    session.commit().expect("a second commit from the same session should succeed");
+
+    let reopened = store.unlock("v", &master.into()).unwrap();
+    assert_eq!(reopened.entries().len(), 1);
+    assert_eq!(reopened.entries()[0].name, "Mail");
+    assert_eq!(reopened.entries()[0].id, 3, "ids of deleted entries are not reused");
+}
+
+#[test]
+fn test_sessions_do_not_overwrite_each_other() {
+    let store = test_store();
+    let master = "unit-test-master";
+    store.create("v", &master.into()).unwrap();
+
+    let mut mine = store.unlock("v", &master.into()).unwrap();
+    let mut theirs = store.unlock("v", &master.into()).unwrap();
+    theirs.add_entry(entry("theirs"));
+    theirs.commit().unwrap();
+
+    mine.add_entry(entry("mine"));
+    assert!(matches!(mine.commit(), Err(Error::Conflict)));
+
+    // Key slot changes are merged with the vault as it is on disk.
+    theirs.add_key_slot("bob", &"bob-secret".into()).unwrap();
+    let bob = store.unlock("v", &"bob-secret".into()).unwrap();
+    assert_eq!(bob.entries()[0].name, "theirs");
+    theirs.change_password(&"new-master".into()).unwrap();
+    assert!(matches!(store.unlock("v", &master.into()), Err(Error::WrongPassword)));
+    assert_eq!(store.key_slots("v").unwrap().len(), 2);
+}
@@ -0,0 +1,91 @@
+use chrono::{TimeZone, Utc};
+use rustpass::journal::{self, Change, EntryField, Record};
+use rustpass::model::{Entry, EntryKind, EntryTime, Login, SecureNote, Vault};
+use rustpass::storage::MemoryBackend;
+use rustpass::store::VaultStore;
+
+fn login(name: &str) -> Entry {
+    Entry::new(
+        name,
+        EntryKind::Login(Login {
+            username: "user".to_string(),
+            password: "pass".into(),
+        }),
+    )
+}
+
+#[test]
+fn test_commits_stamp_entry_times() {
+    let master = "unit-test-master";
+    let store = VaultStore::with_backend(MemoryBackend::new());
+    store.create("v", &master.into()).unwrap();
+    let mut session = store.unlock("v", &master.into()).unwrap();
+    let id = session.add_entry(login("Github"));
+    session.commit().unwrap();
+    let added = session.entry(id).unwrap().clone();
+    let created = added.created_at.expect("adding stamps the creation time");
+    assert_eq!(added.modified_at, Some(created));
+    assert_eq!(added.password_changed_at, Some(created));
+    assert_eq!(added.last_used_at, None);
+
+    session.entry_mut(id).unwrap().name = "GitHub".to_string();
+    session.commit().unwrap();
+    let renamed = session.entry(id).unwrap().clone();
+    assert!(renamed.modified_at > Some(created));
+    assert_eq!(renamed.password_changed_at, Some(created), "a rename keeps the password time");
+
+    session.entry_mut(id).unwrap().as_login_mut().unwrap().password = "hunter2".into();
+    session.commit().unwrap();
+    let changed = session.entry(id).unwrap().clone();
+    assert!(changed.password_changed_at > renamed.modified_at);
+    assert_eq!(changed.modified_at, changed.password_changed_at);
+
+    assert!(session.mark_used(id));
+    assert!(!session.mark_used(99));
+    session.commit().unwrap();
+    let used = session.entry(id).unwrap().clone();
+    assert!(used.last_used_at.is_some());
+    assert_eq!(used.modified_at, changed.modified_at, "using an entry does not modify it");
+    assert!(matches!(session.history().last().unwrap().changes[..], [Change::UseEntry { id: used }] if used == id));
+
+    let reopened = store.unlock("v", &master.into()).unwrap();
+    store.compact("v", &master.into()).unwrap();
+    let compacted = store.unlock("v", &master.into()).unwrap();
+    for which in [EntryTime::Created, EntryTime::Modified, EntryTime::PasswordChanged, EntryTime::LastUsed] {
+        assert_eq!(reopened.entry(id).unwrap().time(which), used.time(which));
+        assert_eq!(compacted.entry(id).unwrap().time(which), used.time(which));
+    }
+}
+
+#[test]
+fn test_replayed_records_stamp_their_own_time() {
+    let mut vault = Vault {
+        created_at: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+        last_modified: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+        next_id: 1,
+        entries: Vec::new(),
+    };
+    let added_at = Utc.timestamp_opt(1_700_000_100, 0).unwrap();
+    let mut note = Entry::new("Safe", EntryKind::SecureNote(SecureNote { text: "12-34".into() }));
+    note.id = 1;
+    let record = Record { at: added_at, changes: vec![Change::AddEntry(note)] };
+    journal::apply(&mut vault, &record).unwrap();
+    let entry = &vault.entries[0];
+    assert_eq!(entry.created_at, Some(added_at));
+    assert_eq!(entry.modified_at, Some(added_at));
+    assert_eq!(entry.password_changed_at, None, "a note has no password");
+    assert_eq!(vault.next_id, 2, "replaying an add uses up its id");
+
+    let edited_at = Utc.timestamp_opt(1_700_000_200, 0).unwrap();
+    let record = Record {
+        at: edited_at,
+        changes: vec![Change::EditField { id: 1, field: EntryField::Notes(Some("in the attic".to_string())) }, Change::UseEntry { id: 1 }],
+    };
+    journal::apply(&mut vault, &record).unwrap();
+    let entry = &vault.entries[0];
+    assert_eq!(entry.created_at, Some(added_at));
+    assert_eq!(entry.modified_at, Some(edited_at));
+    assert_eq!(entry.last_used_at, Some(edited_at));
+    assert_eq!("changed".parse::<EntryTime>().unwrap(), EntryTime::PasswordChanged);
+    assert!("yesterday".parse::<EntryTime>().is_err());
+}
@@ -0,0 +1,84 @@
+use rustpass::journal::{Change, EntryField};
+use rustpass::model::{Entry, EntryKind, EntryUri, Login, UriMatch};
+use rustpass::storage::MemoryBackend;
+use rustpass::store::VaultStore;
+use rustpass::urls::{self, base_domain, MatchQuality};
+
+fn login(name: &str, uris: &[(&str, UriMatch)]) -> Entry {
+    let mut entry = Entry::new(
+        name,
+        EntryKind::Login(Login {
+            username: "user".to_string(),
+            password: "pass".into(),
+        }),
+    );
+    entry.uris = uris.iter().map(|(uri, mode)| EntryUri::new(*uri, *mode)).collect();
+    entry
+}
+
+#[test]
+fn test_base_domain_follows_public_suffix_rules() {
+    assert_eq!(base_domain("login.example.co.uk").as_deref(), Some("example.co.uk"));
+    assert_eq!(base_domain("www.example.com").as_deref(), Some("example.com"));
+    assert_eq!(base_domain("example.com").as_deref(), Some("example.com"));
+    assert_eq!(base_domain("co.uk"), None, "a public suffix has no registrable domain");
+    // Private suffixes separate sites under a shared host.
+    assert_eq!(base_domain("alice.github.io").as_deref(), Some("alice.github.io"));
+    // Wildcard and exception rules: `*.ck` and `!www.ck`.
+    assert_eq!(base_domain("shop.foo.ck").as_deref(), Some("shop.foo.ck"));
+    assert_eq!(base_domain("www.ck").as_deref(), Some("www.ck"));
+    // Hosts under no listed suffix fall under the implicit `*` rule.
+    assert_eq!(base_domain("a.b.internal-tld").as_deref(), Some("b.internal-tld"));
+}
+
+#[test]
+fn test_find_ranks_matches_by_quality() {
+    let entries = vec![
+        login("Domain", &[("https://www.example.co.uk", UriMatch::BaseDomain)]),
+        login("Host", &[("login.example.co.uk", UriMatch::Host)]),
+        login("Prefix", &[("https://login.example.co.uk/x", UriMatch::Prefix)]),
+        login("Regex", &[(r"^https://[a-z]+\.example\.co\.uk/", UriMatch::Regex)]),
+        login("Never", &[("https://login.example.co.uk", UriMatch::Never)]),
+        login("Other", &[("https://example.com", UriMatch::BaseDomain), ("https://login.other.co.uk", UriMatch::Host)]),
+        login("Port", &[("https://login.example.co.uk:8443", UriMatch::Host)]),
+    ];
+    let found = urls::find(&entries, "https://login.example.co.uk/x?next=1").unwrap();
+    let ranked: Vec<_> = found.iter().map(|(entry, quality)| (entry.name.as_str(), *quality)).collect();
+    assert_eq!(
+        ranked,
+        [
+            ("Prefix", MatchQuality::Prefix),
+            ("Host", MatchQuality::Host),
+            ("Regex", MatchQuality::Regex),
+            ("Domain", MatchQuality::BaseDomain),
+        ]
+    );
+
+    let exact = login("Exact", &[("https://login.example.co.uk", UriMatch::BaseDomain)]);
+    assert_eq!(urls::match_uri(&exact.uris[0], &urls::parse("login.example.co.uk").unwrap()), Some(MatchQuality::Host));
+    assert!(urls::find(&entries, "not a url").is_err());
+    assert!(login("Bad", &[("(", UriMatch::Regex)]).validate().is_err());
+    assert!(login("Bad", &[("https://", UriMatch::Host)]).validate().is_err());
+}
+
+#[test]
+fn test_uris_are_journaled() {
+    let master = "unit-test-master";
+    let store = VaultStore::with_backend(MemoryBackend::new());
+    store.create("v", &master.into()).unwrap();
+    let mut session = store.unlock("v", &master.into()).unwrap();
+    let id = session.add_entry(login("Github", &[("github.com", UriMatch::BaseDomain)]));
+    session.commit().unwrap();
+    session.entry_mut(id).unwrap().uris.push(EntryUri::new("https://gist.github.com/", UriMatch::Prefix));
+    session.commit().unwrap();
+    assert!(matches!(
+        &session.history()[1].changes[..],
+        [Change::EditField { field: EntryField::Uris(uris), .. }] if uris.len() == 2
+    ));
+
+    let reopened = store.unlock("v", &master.into()).unwrap();
+    assert_eq!(reopened.entry(id).unwrap().uris, session.entry(id).unwrap().uris);
+    let found = reopened.find_url("https://gist.github.com/octocat").unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].1, MatchQuality::Prefix);
+}
@@ -1,57 +1,355 @@
-use rustpass::vault;
-use rustpass::model::Entry;
+use rustpass::{config, crypto, format, vault, Error};
+use rustpass::model::{Entry, EntryKind, Login};
 use std::fs;
+use std::path::PathBuf;
 use chrono::Utc;
 
-fn cleanup() {
-    // Remove vault and salt files if they exist
-    let proj_dirs = directories::ProjectDirs::from("com", "rustpass", "RustPass").unwrap();
-    let dir = proj_dirs.data_dir();
-    let vault_file = dir.join("vault.cbor");
-    let salt_file = dir.join("salt.bin");
-    let _ = fs::remove_file(vault_file);
-    let _ = fs::remove_file(salt_file);
+// Every test works in its own data directory, never the user's real one.
+fn test_root(test: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("rustpass-test-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    root
 }
 
 #[test]
 fn test_init_creates_files() {
-    cleanup();
+    let root = test_root("init_creates_files");
     let master = "unit-test-master";
-    vault::init(master).expect("Vault init should succeed");
+    vault::init(&root, &master.into()).expect("Vault init should succeed");
 
-    let proj_dirs = directories::ProjectDirs::from("com", "rustpass", "RustPass").unwrap();
-    let dir = proj_dirs.data_dir();
-    assert!(dir.join("vault.cbor").exists(), "vault.cbor should exist after init");
-    assert!(dir.join("salt.bin").exists(), "salt.bin should exist after init");
+    assert!(root.join("vault.cbor").exists(), "vault.cbor should exist after init");
+    assert!(!root.join("salt.bin").exists(), "salt.bin should no longer be written by init");
 
-    cleanup();
+    let _ = fs::remove_dir_all(&root);
 }
 
 #[test]
 fn test_add_entry_save_load_integrity() {
-    cleanup();
+    let root = test_root("add_entry_save_load_integrity");
     let master = "unit-test-master";
-    vault::init(master).expect("Vault init should succeed");
+    vault::init(&root, &master.into()).expect("Vault init should succeed");
 
-    let mut vault_data = vault::load(master).expect("Should load vault after init");
-    let entry = Entry {
-        name: "TestEntry".to_string(),
-        username: "user".to_string(),
-        password: "pass".to_string(),
-        notes: Some("note".to_string()),
-        id: 0, // Replace with a valid id value as per Entry struct definition
-    };
+    let mut vault_data = vault::load(&root, &master.into()).expect("Should load vault after init");
+    let mut entry = Entry::new(
+        "TestEntry",
+        EntryKind::Login(Login {
+            username: "user".to_string(),
+            password: "pass".into(),
+        }),
+    );
+    entry.notes = Some("note".to_string());
     vault_data.entries.push(entry.clone());
     vault_data.last_modified = Utc::now();
-    vault::save(master, &vault_data).expect("Should save vault");
+    vault::save(&root, &master.into(), &vault_data).expect("Should save vault");
 
-    let loaded = vault::load(master).expect("Should load vault after save");
+    let loaded = vault::load(&root, &master.into()).expect("Should load vault after save");
     assert_eq!(loaded.entries.len(), 1);
     let loaded_entry = &loaded.entries[0];
     assert_eq!(loaded_entry.name, entry.name);
-    assert_eq!(loaded_entry.username, entry.username);
-    assert_eq!(loaded_entry.password, entry.password);
+    assert_eq!(loaded_entry.kind, entry.kind);
     assert_eq!(loaded_entry.notes, entry.notes);
 
-    cleanup();
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_legacy_vault_is_migrated_on_unlock() {
+    let name = "legacy-migration-test";
+    let root = test_root(name);
+    let master = "unit-test-master";
+    let vault_file = vault::vault_file_path(&root, name).unwrap();
+    let salt_file = vault::legacy_salt_path(&root, name).unwrap();
+
+    let legacy = rustpass::model::VaultV1 {
+        created_at: Utc::now(),
+        last_modified: Utc::now(),
+        entries: Vec::new(),
+    };
+    let salt = crypto::random_salt();
+    let key = crypto::derive_key(master, &salt).unwrap();
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(&legacy, &mut buf).unwrap();
+    let (ciphertext, nonce) = crypto::encrypt(&key, &buf).unwrap();
+    let mut vault_data = nonce.to_vec();
+    vault_data.extend_from_slice(&ciphertext);
+    fs::write(&vault_file, &vault_data).unwrap();
+    fs::write(&salt_file, salt).unwrap();
+
+    vault::load_named(&root, name, &master.into()).expect("Legacy vault should unlock");
+    assert!(format::has_magic(&fs::read(&vault_file).unwrap()), "vault should be rewritten with a header");
+    assert!(!salt_file.exists(), "salt file should be removed after migration");
+    vault::load_named(&root, name, &master.into()).expect("Migrated vault should unlock");
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_tampered_header_is_rejected() {
+    let name = "tampered-header-test";
+    let root = test_root(name);
+    let master = "unit-test-master";
+    vault::create_named(&root, name, &master.into()).expect("Vault creation should succeed");
+    let vault_file = vault::vault_file_path(&root, name).unwrap();
+
+    let data = fs::read(&vault_file).unwrap();
+    let file = format::decode(&data).unwrap();
+    let mut header = file.header().unwrap();
+    header.key_slots[0].label = "tampered".to_string();
+    let mut tampered = format::encode_header(&header).unwrap();
+    tampered.extend_from_slice(&file.ciphertext);
+    fs::write(&vault_file, &tampered).unwrap();
+
+    assert!(matches!(vault::load_named(&root, name, &master.into()), Err(Error::Corrupt(_))), "tampered header must fail authentication");
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_set_kdf_reencrypts_with_new_params() {
+    let name = "set-kdf-test";
+    let root = test_root(name);
+    let master = "unit-test-master";
+    vault::create_named(&root, name, &master.into()).expect("Vault creation should succeed");
+
+    let kdf = crypto::KdfParams {
+        m_cost: 8 * 1024,
+        t_cost: 3,
+        p_cost: 2,
+        ..Default::default()
+    };
+    vault::set_kdf_named(&root, name, &master.into(), kdf).expect("set-kdf should succeed");
+    assert_eq!(vault::kdf_params_named(&root, name, &master.into()).unwrap(), kdf);
+    vault::load_named(&root, name, &master.into()).expect("Vault should unlock with new params");
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_change_password_rekeys_vault() {
+    let name = "change-password-test";
+    let root = test_root(name);
+    vault::create_named(&root, name, &"old-master".into()).expect("Vault creation should succeed");
+    let header_before = format::decode(&fs::read(vault::vault_file_path(&root, name).unwrap()).unwrap()).unwrap().header().unwrap();
+
+    assert!(vault::change_password_named(&root, name, &"wrong".into(), &"new-master".into()).is_err());
+    vault::change_password_named(&root, name, &"old-master".into(), &"new-master".into()).expect("change should succeed");
+
+    let header_after = format::decode(&fs::read(vault::vault_file_path(&root, name).unwrap()).unwrap()).unwrap().header().unwrap();
+    assert_ne!(header_before.key_slots[0].salt, header_after.key_slots[0].salt, "a fresh salt should be generated");
+    assert!(matches!(vault::load_named(&root, name, &"old-master".into()), Err(Error::WrongPassword)));
+    vault::load_named(&root, name, &"new-master".into()).expect("new password should unlock");
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_v1_header_vault_is_migrated_to_wrapped_key() {
+    let name = "v1-header-migration-test";
+    let root = test_root(name);
+    let master = "unit-test-master";
+    let vault_file = vault::vault_file_path(&root, name).unwrap();
+
+    let legacy = rustpass::model::VaultV1 {
+        created_at: Utc::now(),
+        last_modified: Utc::now(),
+        entries: Vec::new(),
+    };
+    let header = format::VaultHeaderV1 {
+        kdf: crypto::KdfParams::default(),
+        salt: crypto::random_salt().to_vec(),
+        nonce: crypto::random_nonce(),
+    };
+    let mut header_buf = Vec::new();
+    ciborium::ser::into_writer(&header, &mut header_buf).unwrap();
+    let mut vault_data = format::MAGIC.to_vec();
+    vault_data.extend_from_slice(&1u16.to_le_bytes());
+    vault_data.extend_from_slice(&(header_buf.len() as u32).to_le_bytes());
+    vault_data.extend_from_slice(&header_buf);
+    let key = crypto::derive_key_with(master, None, &header.salt, &header.kdf).unwrap();
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(&legacy, &mut buf).unwrap();
+    let ciphertext = crypto::encrypt_with_aad(&key, &header.nonce, &buf, &vault_data).unwrap();
+    vault_data.extend_from_slice(&ciphertext);
+    fs::write(&vault_file, &vault_data).unwrap();
+
+    vault::load_named(&root, name, &master.into()).expect("Version 1 vault should unlock");
+    let file = format::decode(&fs::read(&vault_file).unwrap()).unwrap();
+    assert_eq!(file.version, format::FORMAT_VERSION);
+    assert_eq!(file.header().unwrap().key_slots.len(), 1);
+    vault::load_named(&root, name, &master.into()).expect("Migrated vault should unlock");
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_key_slots_unlock_independently() {
+    let name = "key-slots-test";
+    let root = test_root(name);
+    vault::create_named(&root, name, &"alice-pass".into()).expect("Vault creation should succeed");
+    vault::add_key_slot_named(&root, name, &"alice-pass".into(), "bob", &"bob-pass".into()).expect("adding a slot should succeed");
+    assert!(vault::add_key_slot_named(&root, name, &"alice-pass".into(), "bob", &"other".into()).is_err(), "labels must be unique");
+
+    let labels: Vec<String> = vault::key_slots_named(&root, name).unwrap().into_iter().map(|s| s.label).collect();
+    assert_eq!(labels, vec![vault::MASTER_SLOT_LABEL.to_string(), "bob".to_string()]);
+    vault::load_named(&root, name, &"bob-pass".into()).expect("second slot should unlock");
+
+    vault::remove_key_slot_named(&root, name, &"bob-pass".into(), vault::MASTER_SLOT_LABEL).expect("removing a slot should succeed");
+    assert!(vault::load_named(&root, name, &"alice-pass".into()).is_err());
+    assert!(vault::remove_key_slot_named(&root, name, &"bob-pass".into(), "bob").is_err(), "the last slot must not be removed");
+    vault::load_named(&root, name, &"bob-pass".into()).expect("remaining slot should unlock");
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_keyfile_vault_needs_both_factors() {
+    let name = "keyfile-test";
+    let root = test_root(name);
+    let keyfile = crypto::generate_keyfile();
+    let master = crypto::MasterKey::new("unit-test-master").with_keyfile(keyfile.clone());
+    vault::create_named(&root, name, &master).expect("Vault creation should succeed");
+
+    vault::load_named(&root, name, &master).expect("password and keyfile should unlock");
+    assert!(matches!(vault::load_named(&root, name, &"unit-test-master".into()), Err(Error::KeyfileRequired)), "password alone must not unlock");
+    let wrong_password = crypto::MasterKey::new("wrong").with_keyfile(keyfile);
+    assert!(vault::load_named(&root, name, &wrong_password).is_err(), "keyfile alone must not unlock");
+    let wrong_keyfile = crypto::MasterKey::new("unit-test-master").with_keyfile(crypto::generate_keyfile());
+    assert!(vault::load_named(&root, name, &wrong_keyfile).is_err(), "a different keyfile must not unlock");
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_recovery_phrase_resets_master_password() {
+    let name = "recovery-test";
+    let root = test_root(name);
+    vault::create_named(&root, name, &"forgotten".into()).expect("Vault creation should succeed");
+    let phrase = vault::set_recovery_named(&root, name, &"forgotten".into()).expect("recovery phrase should be created");
+
+    assert!(vault::load_named(&root, name, &phrase.expose().into()).is_err(), "the phrase is not a master password");
+    vault::recover_named(&root, name, phrase.expose(), &"brand-new".into()).expect("recovery should succeed");
+    assert!(vault::load_named(&root, name, &"forgotten".into()).is_err());
+    vault::load_named(&root, name, &"brand-new".into()).expect("new master password should unlock");
+
+    vault::revoke_recovery_named(&root, name, &"brand-new".into()).expect("revoking should succeed");
+    assert!(vault::recover_named(&root, name, phrase.expose(), &"again".into()).is_err(), "revoked phrase must not work");
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_truncated_and_missing_vaults_return_typed_errors() {
+    let name = "truncated-test";
+    let root = test_root(name);
+    let vault_file = vault::vault_file_path(&root, name).unwrap();
+    assert!(matches!(vault::load_named(&root, name, &"pass".into()), Err(Error::NotFound(_))));
+
+    fs::write(&vault_file, &format::MAGIC[..]).unwrap();
+    assert!(matches!(vault::load_named(&root, name, &"pass".into()), Err(Error::Truncated)));
+    fs::write(&vault_file, [0u8; 10]).unwrap();
+    assert!(matches!(vault::load_named(&root, name, &"pass".into()), Err(Error::Truncated)));
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_compactions_are_atomic_and_keep_rotated_backups() {
+    let name = "backup-rotation-test";
+    let root = test_root(name);
+    let master = "unit-test-master";
+    vault::create_named(&root, name, &master.into()).expect("Vault creation should succeed");
+    let vault_file = vault::vault_file_path(&root, name).unwrap();
+    assert!(vault::backup_files_named(&root, name).unwrap().is_empty(), "a new vault has no previous generation");
+
+    let mut generations = vec![fs::read(&vault_file).unwrap()];
+    let mut v = vault::load_named(&root, name, &master.into()).unwrap();
+    for i in 0..config::DEFAULT_BACKUPS + 1 {
+        let mut entry = Entry::new(
+            format!("entry-{}", i),
+            EntryKind::Login(Login {
+                username: "user".to_string(),
+                password: "pass".into(),
+            }),
+        );
+        entry.id = i as u32;
+        v.entries.push(entry);
+        vault::save_named(&root, name, &master.into(), &v).expect("Should save vault");
+        assert_eq!(fs::read(&vault_file).unwrap(), generations[generations.len() - 1], "saving only appends to the journal");
+        assert_eq!(vault::compact_named(&root, name, &master.into()).unwrap(), 1);
+        generations.push(fs::read(&vault_file).unwrap());
+    }
+
+    let backups = vault::backup_files_named(&root, name).unwrap();
+    assert_eq!(backups.len(), config::DEFAULT_BACKUPS);
+    for (i, backup) in backups.iter().enumerate() {
+        assert_eq!(fs::read(backup).unwrap(), generations[generations.len() - 2 - i], "bak.{} holds the wrong generation", i + 1);
+    }
+    let mut tmp = vault_file.clone().into_os_string();
+    tmp.push(".tmp");
+    assert!(!std::path::Path::new(&tmp).exists(), "the temporary file must be renamed into place");
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_busy_vault_is_reported_as_locked() {
+    let name = "lock-test";
+    let root = test_root(name);
+    let master = "unit-test-master";
+    vault::create_named(&root, name, &master.into()).expect("Vault creation should succeed");
+    let v = vault::load_named(&root, name, &master.into()).unwrap();
+
+    // Stands in for another rustpass process holding the vault.
+    let other = fs::OpenOptions::new().create(true).truncate(false).write(true).open(vault::lock_file_path(&root, name).unwrap()).unwrap();
+    other.lock().unwrap();
+    assert!(matches!(vault::save_named(&root, name, &master.into(), &v), Err(Error::Locked(_))));
+    other.unlock().unwrap();
+    vault::save_named(&root, name, &master.into(), &v).expect("save should succeed once the lock is released");
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_save_refuses_to_overwrite_concurrent_changes() {
+    let name = "conflict-test";
+    let root = test_root(name);
+    let master = "unit-test-master";
+    vault::create_named(&root, name, &master.into()).expect("Vault creation should succeed");
+
+    let mut mine = vault::load_named(&root, name, &master.into()).unwrap();
+    let loaded_at = mine.last_modified;
+    let mut theirs = vault::load_named(&root, name, &master.into()).unwrap();
+    theirs.last_modified = loaded_at + chrono::Duration::seconds(5);
+    vault::save_named_checked(&root, name, &master.into(), &theirs, loaded_at).expect("first save should succeed");
+
+    mine.last_modified = Utc::now();
+    assert!(matches!(vault::save_named_checked(&root, name, &master.into(), &mine, loaded_at), Err(Error::Conflict)));
+    assert_eq!(vault::load_named(&root, name, &master.into()).unwrap().last_modified, theirs.last_modified);
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_config_is_read_from_the_data_dir() {
+    let name = "config-root-test";
+    let master = "unit-test-master";
+    let root = test_root(name);
+    fs::write(config::config_path(&root), "backups = 1\n").unwrap();
+    assert_eq!(config::Config::load(&root).unwrap().backups, 1);
+
+    vault::create_named(&root, name, &master.into()).expect("Vault creation should succeed");
+    let mut v = vault::load_named(&root, name, &master.into()).unwrap();
+    for _ in 0..3 {
+        v.last_modified += chrono::Duration::seconds(1);
+        vault::save_named(&root, name, &master.into(), &v).unwrap();
+        vault::compact_named(&root, name, &master.into()).unwrap();
+    }
+    assert_eq!(vault::backup_files_named(&root, name).unwrap().len(), 1);
+    let names: Vec<String> = fs::read_dir(&root)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    assert!(names.iter().all(|n| n == "config.toml" || n.starts_with("vault_config-root-test.cbor")), "unexpected files: {:?}", names);
+
+    let _ = fs::remove_dir_all(&root);
 }
@@ -0,0 +1,113 @@
+use rustpass::model::{Entry, EntryKind, Login, VaultV1};
+use rustpass::storage::{MemoryBackend, StorageBackend};
+use rustpass::store::VaultStore;
+use rustpass::{crypto, journal, vault, Error};
+use chrono::Utc;
+
+fn entry(name: &str) -> Entry {
+    Entry::new(
+        name,
+        EntryKind::Login(Login {
+            username: "user".to_string(),
+            password: "pass".into(),
+        }),
+    )
+}
+
+fn messages(report: &rustpass::verify::Report) -> Vec<String> {
+    report.problems.iter().map(|p| p.to_string()).collect()
+}
+
+#[test]
+fn test_verify_finds_damage_and_a_good_backup() {
+    let storage = MemoryBackend::new();
+    let store = VaultStore::with_backend(storage.clone());
+    let master = "unit-test-master";
+    store.create("v", &master.into()).unwrap();
+    let mut session = store.unlock("v", &master.into()).unwrap();
+    session.add_entry(entry("Github"));
+    session.commit().unwrap();
+    session.compact().unwrap();
+    assert!(store.verify("v", Some(&master.into())).unwrap().is_ok());
+    assert!(store.verify("v", None).unwrap().is_ok(), "the structure checks need no password");
+
+    // Each damaged file is written the way a save would, keeping the one it
+    // replaces as a backup.
+    let key = vault::vault_key("v");
+    let good = storage.read(&key).unwrap();
+    storage.write(&key, &good[..20], 3).unwrap();
+    let report = store.verify("v", None).unwrap();
+    assert!(messages(&report)[0].contains("truncated"), "{:?}", messages(&report));
+    assert_eq!(report.good_backup.as_deref(), Some("vault_v.cbor.bak.1"));
+
+    let mut flipped = good.clone();
+    *flipped.last_mut().unwrap() ^= 1;
+    storage.write(&key, &flipped, 3).unwrap();
+    let report = store.verify("v", Some(&master.into())).unwrap();
+    assert!(messages(&report)[0].contains("failed authentication"), "{:?}", messages(&report));
+    assert_eq!(report.good_backup.as_deref(), Some("vault_v.cbor.bak.2"), "bak.1 is the truncated file");
+    assert!(matches!(store.verify("v", Some(&"wrong".into())), Err(Error::WrongPassword)));
+
+    assert_eq!(store.restore_backup("v", "vault_v.cbor.bak.2", false).unwrap(), 0);
+    assert!(store.verify("v", Some(&master.into())).unwrap().is_ok());
+    assert_eq!(store.unlock("v", &master.into()).unwrap().entries()[0].name, "Github");
+}
+
+#[test]
+fn test_verify_with_the_wrong_password_is_not_damage() {
+    let store = VaultStore::with_backend(MemoryBackend::new());
+    let master = "unit-test-master";
+    store.create("v", &master.into()).unwrap();
+    let mut session = store.unlock("v", &master.into()).unwrap();
+    session.add_entry(entry("Github"));
+    session.commit().unwrap();
+    session.change_password(&"new-master".into()).unwrap();
+    assert!(!store.backups("v").unwrap().is_empty());
+
+    // The old password still opens the backups, which must not be offered
+    // in place of the intact vault.
+    assert!(matches!(store.verify("v", Some(&master.into())), Err(Error::WrongPassword)));
+    assert!(matches!(store.verify("v", Some(&"typo".into())), Err(Error::WrongPassword)));
+    assert!(store.verify("v", Some(&"new-master".into())).unwrap().is_ok());
+}
+
+#[test]
+fn test_verify_reports_journal_and_salt_problems() {
+    let storage = MemoryBackend::new();
+    let store = VaultStore::with_backend(storage.clone());
+    let master = "unit-test-master";
+    store.create("v", &master.into()).unwrap();
+    let mut session = store.unlock("v", &master.into()).unwrap();
+    session.add_entry(entry("Github"));
+    session.commit().unwrap();
+    let journal_key = journal::journal_key(&vault::vault_key("v"));
+    let mut records = storage.read(&journal_key).unwrap();
+    records[40] ^= 1;
+    storage.write(&journal_key, &records, 0).unwrap();
+    let report = store.verify("v", Some(&master.into())).unwrap();
+    assert_eq!(report.problems.len(), 1);
+    assert_eq!(report.problems[0].key, journal_key);
+
+    // A vault from before the file header, with its salt file.
+    let legacy = VaultV1 {
+        created_at: Utc::now(),
+        last_modified: Utc::now(),
+        entries: Vec::new(),
+    };
+    let salt = crypto::random_salt();
+    let key = crypto::derive_key(master, &salt).unwrap();
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(&legacy, &mut buf).unwrap();
+    let (ciphertext, nonce) = crypto::encrypt(&key, &buf).unwrap();
+    storage.write(&vault::vault_key("old"), &[nonce.as_slice(), &ciphertext].concat(), 0).unwrap();
+    storage.write(&vault::legacy_salt_key("old"), &salt, 0).unwrap();
+    assert!(store.verify("old", Some(&master.into())).unwrap().is_ok());
+
+    storage.write(&vault::legacy_salt_key("old"), &salt[..8], 0).unwrap();
+    let report = store.verify("old", Some(&master.into())).unwrap();
+    assert_eq!(messages(&report), vec!["salt_old.bin: the salt file is 8 bytes instead of 16"]);
+    storage.delete(&vault::legacy_salt_key("old")).unwrap();
+    let report = store.verify("old", None).unwrap();
+    assert!(messages(&report)[0].starts_with("salt_old.bin: the salt file"), "{:?}", messages(&report));
+    assert!(store.unlock("old", &master.into()).is_err(), "unlocking reports the missing salt instead of panicking");
+}